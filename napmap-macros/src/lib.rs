@@ -0,0 +1,109 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::Expr;
+use syn::FnArg;
+use syn::ItemFn;
+use syn::MetaNameValue;
+use syn::Pat;
+use syn::ReturnType;
+use syn::Token;
+
+/// Memoizes an async function in a `napmap::Memo` keyed by its arguments,
+/// coalescing concurrent calls with the same arguments.
+///
+/// ```ignore
+/// #[napmemo(capacity = 512, ttl = Duration::from_secs(60))]
+/// async fn user(id: u64) -> User {
+///     fetch_user(id).await
+/// }
+/// ```
+///
+/// `capacity` defaults to 1024 and results never expire without a `ttl`.
+/// Arguments must be plain identifiers whose types implement
+/// `Eq + Hash + Clone + Debug + Send + Sync + 'static`, and the return type
+/// `Clone + Debug + Send + Sync + 'static`. Generic functions and methods are
+/// not supported, every function gets a single memo shared by all callers.
+#[proc_macro_attribute]
+pub fn napmemo(args: TokenStream, item: TokenStream) -> TokenStream {
+    match expand(args.into(), item.into()) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(
+    args: proc_macro2::TokenStream,
+    item: proc_macro2::TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let mut capacity: Expr = syn::parse_quote!(1024);
+    let mut ttl: Option<Expr> = None;
+    let args = Punctuated::<MetaNameValue, Token![,]>::parse_terminated.parse2(args)?;
+    for arg in args {
+        if arg.path.is_ident("capacity") {
+            capacity = arg.value;
+        } else if arg.path.is_ident("ttl") {
+            ttl = Some(arg.value);
+        } else {
+            return Err(syn::Error::new(
+                arg.path.span(),
+                "expected `capacity` or `ttl`",
+            ));
+        }
+    }
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = syn::parse2::<ItemFn>(item)?;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new(
+            sig.fn_token.span,
+            "napmemo requires an async fn",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            sig.generics.span(),
+            "napmemo doesn't support generic functions",
+        ));
+    }
+    let ReturnType::Type(_, output) = &sig.output else {
+        return Err(syn::Error::new(
+            sig.span(),
+            "napmemo requires a return type",
+        ));
+    };
+
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    for input in &sig.inputs {
+        let FnArg::Typed(arg) = input else {
+            return Err(syn::Error::new(
+                input.span(),
+                "napmemo doesn't support methods",
+            ));
+        };
+        let Pat::Ident(pat) = arg.pat.as_ref() else {
+            return Err(syn::Error::new(arg.pat.span(), "expected an identifier"));
+        };
+        names.push(pat.ident.clone());
+        types.push(arg.ty.as_ref().clone());
+    }
+
+    let with_ttl = ttl.map(|ttl| quote!(.with_ttl(#ttl)));
+    Ok(quote! {
+        #(#attrs)*
+        #vis #sig {
+            static MEMO: ::std::sync::OnceLock<::napmap::Memo<(#(#types,)*), #output>> =
+                ::std::sync::OnceLock::new();
+            let memo = MEMO.get_or_init(|| ::napmap::Memo::new(#capacity) #with_ttl);
+            let key = (#(::std::clone::Clone::clone(&#names),)*);
+            memo.get_or_compute(key, async move #block).await
+        }
+    })
+}
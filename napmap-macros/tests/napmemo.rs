@@ -0,0 +1,27 @@
+use napmap_macros::napmemo;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+#[napmemo(capacity = 16, ttl = Duration::from_secs(60))]
+async fn square(n: u64, label: String) -> String {
+    CALLS.fetch_add(1, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    format!("{label}: {}", n * n)
+}
+
+#[tokio::test(start_paused = true)]
+async fn it_should_memoize_and_coalesce_calls() {
+    let (a, b) = tokio::join!(square(3, "a".into()), square(3, "a".into()));
+    assert_eq!((a.as_str(), b.as_str()), ("a: 9", "a: 9"));
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+    assert_eq!(square(4, "a".into()).await, "a: 16");
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+    tokio::time::advance(Duration::from_secs(60)).await;
+    assert_eq!(square(3, "a".into()).await, "a: 9");
+    assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+}
@@ -0,0 +1,79 @@
+use crate::error::DowncastError;
+use crate::NapMap;
+use crate::UnboundedNapMap;
+use std::any::Any;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// Value of a map holding values of different types, accessed through the
+/// typed `insert_as` and `get_as`.
+pub type AnyValue = Arc<dyn Any + Send + Sync>;
+
+fn downcast<T: Any + Send + Sync>(v: Option<AnyValue>) -> Result<Option<Arc<T>>, DowncastError> {
+    match v {
+        None => Ok(None),
+        Some(v) => v.downcast::<T>().map(Some).map_err(|_| DowncastError {
+            expected: std::any::type_name::<T>(),
+        }),
+    }
+}
+
+impl<K> NapMap<K, AnyValue>
+where
+    K: Eq + Hash + Clone + Debug,
+{
+    pub async fn insert_as<T: Any + Send + Sync>(&self, k: K, v: T) {
+        self.insert(k, Arc::new(v)).await;
+    }
+
+    /// Like [`get`](Self::get), failing if the value is not a `T`.
+    pub async fn get_as<T: Any + Send + Sync>(
+        &self,
+        k: K,
+    ) -> Result<Option<Arc<T>>, DowncastError> {
+        downcast(self.get(&k).await)
+    }
+}
+
+impl<K> UnboundedNapMap<K, AnyValue>
+where
+    K: Eq + Hash + Clone + Debug,
+{
+    pub async fn insert_as<T: Any + Send + Sync>(&self, k: K, v: T) {
+        self.insert(k, Arc::new(v)).await;
+    }
+
+    /// Like [`get`](Self::get), failing if the value is not a `T`.
+    pub async fn get_as<T: Any + Send + Sync>(
+        &self,
+        k: K,
+    ) -> Result<Option<Arc<T>>, DowncastError> {
+        downcast(self.get(&k).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DowncastError;
+    use crate::UnboundedNapMap;
+
+    #[tokio::test]
+    async fn it_should_downcast_typed_values() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert_as("count", 7_u32).await;
+        napmap.insert_as("name", String::from("plugin")).await;
+
+        assert_eq!(*napmap.get_as::<u32>("count").await.unwrap().unwrap(), 7);
+        assert_eq!(
+            *napmap.get_as::<String>("name").await.unwrap().unwrap(),
+            "plugin"
+        );
+        assert_eq!(
+            napmap.get_as::<String>("count").await.unwrap_err(),
+            DowncastError {
+                expected: "alloc::string::String"
+            }
+        );
+    }
+}
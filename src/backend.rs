@@ -0,0 +1,316 @@
+use crate::error::lock;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// An external store kept in sync with a map's inserts and removals.
+pub trait Backend<K, V>: Send + Sync {
+    fn store<'a>(&'a self, k: &'a K, v: &'a V) -> BoxFuture<'a, Result<(), BoxError>>;
+    fn delete<'a>(&'a self, k: &'a K) -> BoxFuture<'a, Result<(), BoxError>>;
+
+    /// Reads `k` back, for a `get` missing the map before it naps. The
+    /// default finds nothing, for write-only backends like an audit log.
+    fn load<'a>(&'a self, _k: &'a K) -> BoxFuture<'a, Result<Option<V>, BoxError>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Persists a batch of queued mutations in write-behind mode. The default
+    /// applies them one by one, stopping at the first failure. A failed batch
+    /// is retried whole, see [`WriteBehind::max_attempts`].
+    fn write_batch<'a>(&'a self, batch: &'a [Mutation<K, V>]) -> BoxFuture<'a, Result<(), BoxError>>
+    where
+        K: Sync,
+        V: Sync,
+    {
+        Box::pin(async move {
+            for mutation in batch {
+                match mutation {
+                    Mutation::Store(k, v) => self.store(k, v).await?,
+                    Mutation::Delete(k) => self.delete(k).await?,
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A backend keeping the entries in memory, for tests and for sharing
+/// entries between maps. Clones share the entries.
+#[derive(Debug)]
+pub struct InMemoryBackend<K, V> {
+    entries: Arc<Mutex<HashMap<K, V>>>,
+}
+
+impl<K, V> InMemoryBackend<K, V> {
+    pub fn new() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Number of entries stored.
+    pub fn len(&self) -> usize {
+        lock(&self.entries).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V> Clone for InMemoryBackend<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<K, V> Default for InMemoryBackend<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, V> FromIterator<(K, V)> for InMemoryBackend<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(entries.into_iter().collect())),
+        }
+    }
+}
+
+impl<K, V> Backend<K, V> for InMemoryBackend<K, V>
+where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    fn store<'a>(&'a self, k: &'a K, v: &'a V) -> BoxFuture<'a, Result<(), BoxError>> {
+        lock(&self.entries).insert(k.clone(), v.clone());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete<'a>(&'a self, k: &'a K) -> BoxFuture<'a, Result<(), BoxError>> {
+        lock(&self.entries).remove(k);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn load<'a>(&'a self, k: &'a K) -> BoxFuture<'a, Result<Option<V>, BoxError>> {
+        let v = lock(&self.entries).get(k).cloned();
+        Box::pin(async { Ok(v) })
+    }
+}
+
+/// A change queued for the backend in write-behind mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation<K, V> {
+    Store(K, V),
+    Delete(K),
+}
+
+/// When the backend is written relative to the change becoming visible to `get`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteOrder {
+    /// The backend is written first, waiters only wake once it has succeeded.
+    #[default]
+    BeforeVisible,
+    /// The change is visible immediately, then the backend is written.
+    AfterVisible,
+}
+
+/// What happens when the backend fails to persist a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnBackendError {
+    /// The error is returned to the caller. With [`WriteOrder::BeforeVisible`]
+    /// the change is not applied to the map.
+    #[default]
+    Propagate,
+    /// The error is logged and the change is applied regardless.
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteThrough {
+    pub order: WriteOrder,
+    pub on_error: OnBackendError,
+}
+
+/// Configuration of the write-behind queue.
+///
+/// Changes are visible immediately and queued, a background task flushes them
+/// once `batch_size` changes are pending or `flush_interval` has passed since
+/// the oldest one was queued. Inserts wait for room when `capacity` changes are
+/// already queued.
+///
+/// A failed flush is retried after `retry_backoff`, doubled for each further
+/// retry, and changes keep queueing meanwhile. The batch is logged and dropped
+/// once `max_attempts` flushes of it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteBehind {
+    pub capacity: usize,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    /// Flushes of a batch before it is dropped, the first one included.
+    pub max_attempts: u32,
+    pub retry_backoff: Duration,
+}
+
+impl Default for WriteBehind {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            batch_size: 64,
+            flush_interval: Duration::from_millis(100),
+            max_attempts: 5,
+            retry_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BackendError(pub BoxError);
+
+impl Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "backend failed to persist the change: {}", self.0)
+    }
+}
+
+impl Error for BackendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Attached<K, V> {
+    pub(crate) backend: Arc<dyn Backend<K, V>>,
+    pub(crate) policy: WriteThrough,
+}
+
+impl<K, V> Attached<K, V> {
+    /// Runs a backend write, applying the error policy.
+    pub(crate) async fn persist(
+        &self,
+        write: BoxFuture<'_, Result<(), BoxError>>,
+    ) -> Result<(), BackendError> {
+        match (write.await, self.policy.on_error) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), OnBackendError::Propagate) => Err(BackendError(e)),
+            (Err(e), OnBackendError::Log) => {
+                tracing::error!("Backend write failed: {e}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Stores every pair, applying the error policy. A propagated failure
+    /// undoes the stores made so far: keys that held a value get their
+    /// `previous` one back, the others are deleted.
+    pub(crate) async fn store_batch(
+        &self,
+        pairs: &[(K, V)],
+        previous: &[Option<V>],
+    ) -> Result<(), BackendError> {
+        for (stored, (k, v)) in pairs.iter().enumerate() {
+            if let Err(e) = self.persist(self.backend.store(k, v)).await {
+                for ((k, _), old) in pairs[..stored].iter().zip(previous) {
+                    let undo = match old {
+                        Some(old) => self.backend.store(k, old),
+                        None => self.backend.delete(k),
+                    };
+                    if let Err(e) = undo.await {
+                        tracing::error!("Rolling back a batch failed: {e}");
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn spawn_write_behind<K, V>(
+    backend: impl Backend<K, V> + 'static,
+    config: WriteBehind,
+) -> mpsc::Sender<Mutation<K, V>>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    assert!(config.capacity > 0, "write-behind requires capacity > 0");
+    assert!(
+        config.batch_size > 0,
+        "write-behind requires batch_size > 0"
+    );
+    let (tx, mut rx) = mpsc::channel(config.capacity);
+
+    tokio::spawn(async move {
+        let mut batch = Vec::with_capacity(config.batch_size);
+        let mut open = true;
+        while open {
+            let Some(first) = rx.recv().await else {
+                break;
+            };
+            batch.push(first);
+
+            let deadline = Instant::now() + config.flush_interval;
+            while batch.len() < config.batch_size {
+                match tokio::time::timeout_at(deadline, rx.recv()).await {
+                    Ok(Some(mutation)) => batch.push(mutation),
+                    Ok(None) => {
+                        open = false;
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            tracing::trace!("Flushing {} queued mutations", batch.len());
+            flush(&backend, &batch, config).await;
+            batch.clear();
+        }
+        tracing::trace!("Write-behind queue closed");
+    });
+
+    tx
+}
+
+/// Writes `batch`, retrying it with a doubling backoff while attempts are left.
+async fn flush<K, V>(backend: &impl Backend<K, V>, batch: &[Mutation<K, V>], config: WriteBehind)
+where
+    K: Sync,
+    V: Sync,
+{
+    let mut backoff = config.retry_backoff;
+    let mut attempt = 1;
+    loop {
+        match backend.write_batch(batch).await {
+            Ok(()) => return,
+            Err(e) if attempt < config.max_attempts => {
+                tracing::warn!("Write-behind flush failed, retrying in {backoff:?}: {e}");
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2);
+                attempt += 1;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Write-behind flush failed {attempt} times, dropping {} mutations: {e}",
+                    batch.len()
+                );
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::backend::BoxFuture;
+use crate::NapMap;
+use crate::ShardedNapMap;
+use crate::UnboundedNapMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// A map a [`Workload`] can run against, keyed and valued by `u64`.
+pub trait Target: Send + Sync + 'static {
+    fn get(&self, k: u64) -> BoxFuture<'_, Option<u64>>;
+    fn insert(&self, k: u64, v: u64) -> BoxFuture<'_, ()>;
+}
+
+impl Target for NapMap<u64, u64> {
+    fn get(&self, k: u64) -> BoxFuture<'_, Option<u64>> {
+        Box::pin(async move { NapMap::get(self, &k).await })
+    }
+
+    fn insert(&self, k: u64, v: u64) -> BoxFuture<'_, ()> {
+        Box::pin(NapMap::insert(self, k, v))
+    }
+}
+
+impl Target for UnboundedNapMap<u64, u64> {
+    fn get(&self, k: u64) -> BoxFuture<'_, Option<u64>> {
+        Box::pin(async move { UnboundedNapMap::get(self, &k).await })
+    }
+
+    fn insert(&self, k: u64, v: u64) -> BoxFuture<'_, ()> {
+        Box::pin(UnboundedNapMap::insert(self, k, v))
+    }
+}
+
+impl Target for ShardedNapMap<u64, u64> {
+    fn get(&self, k: u64) -> BoxFuture<'_, Option<u64>> {
+        Box::pin(async move { ShardedNapMap::get(self, &k).await })
+    }
+
+    fn insert(&self, k: u64, v: u64) -> BoxFuture<'_, ()> {
+        Box::pin(ShardedNapMap::insert(self, k, v))
+    }
+}
+
+/// A synthetic lookup load, modeled on a cache stampede: `waiters` tasks
+/// each run `lookups` gets. Most hit one of `keys` prefilled keys, picked
+/// with a power-law `skew` (0 is uniform). A `miss_rate` share asks for the
+/// key a producer inserts next, so the waiters missing at the same step all
+/// nap on the same key until it lands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Workload {
+    pub keys: u64,
+    pub skew: f64,
+    pub miss_rate: f64,
+    pub waiters: usize,
+    pub lookups: usize,
+    /// Time the producer takes per missing key.
+    pub produce_every: Duration,
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            keys: 1024,
+            skew: 1.0,
+            miss_rate: 0.01,
+            waiters: 64,
+            lookups: 1000,
+            produce_every: Duration::from_micros(50),
+            seed: 0x5eed,
+        }
+    }
+}
+
+/// What a [`Workload`] measured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub lookups: usize,
+    pub misses: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Report {
+    /// Lookups completed per second.
+    pub fn throughput(&self) -> f64 {
+        self.lookups as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl Workload {
+    /// Prefills `target`, then runs the lookups and the producer of missing
+    /// keys concurrently.
+    pub async fn run<T: Target>(&self, target: Arc<T>) -> Report {
+        for k in 0..self.keys {
+            target.insert(k, k).await;
+        }
+
+        let producer = tokio::spawn({
+            let target = target.clone();
+            let (first, count, every) = (self.keys, self.lookups as u64, self.produce_every);
+            async move {
+                for k in first..first + count {
+                    tokio::time::sleep(every).await;
+                    target.insert(k, k).await;
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let mut waiters = JoinSet::new();
+        for waiter in 0..self.waiters {
+            let target = target.clone();
+            let workload = *self;
+            waiters.spawn(async move { workload.lookups(&*target, waiter as u64).await });
+        }
+        let mut latencies = Vec::with_capacity(self.waiters * self.lookups);
+        let mut misses = 0;
+        while let Some(joined) = waiters.join_next().await {
+            let (waited, missed) = joined.expect("bench waiter panicked");
+            latencies.extend(waited);
+            misses += missed;
+        }
+        let elapsed = start.elapsed();
+        producer.abort();
+
+        latencies.sort_unstable();
+        let at = |q: f64| match latencies.len() {
+            0 => Duration::ZERO,
+            n => latencies[((n - 1) as f64 * q) as usize],
+        };
+        Report {
+            lookups: latencies.len(),
+            misses,
+            elapsed,
+            p50: at(0.5),
+            p99: at(0.99),
+            max: at(1.0),
+        }
+    }
+
+    async fn lookups<T: Target>(&self, target: &T, waiter: u64) -> (Vec<Duration>, usize) {
+        let mut rng = XorShift::new(self.seed ^ (waiter + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        let mut latencies = Vec::with_capacity(self.lookups);
+        let mut misses = 0;
+        for step in 0..self.lookups as u64 {
+            let k = match rng.next_f64() < self.miss_rate {
+                true => {
+                    misses += 1;
+                    self.keys + step
+                }
+                false => (self.keys as f64 * rng.next_f64().powf(1.0 + self.skew)) as u64,
+            };
+            let start = Instant::now();
+            target.get(k).await;
+            latencies.push(start.elapsed());
+        }
+        (latencies, misses)
+    }
+}
+
+/// Small deterministic generator, so runs are comparable across configurations.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Workload;
+    use crate::ShardedNapMap;
+    use crate::UnboundedNapMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn it_should_run_a_stampede() {
+        let workload = Workload {
+            keys: 64,
+            miss_rate: 0.2,
+            waiters: 8,
+            lookups: 50,
+            produce_every: Duration::from_micros(10),
+            ..Workload::default()
+        };
+
+        let report = workload.run(Arc::new(UnboundedNapMap::new())).await;
+        assert_eq!(report.lookups, 400);
+        assert!(report.misses > 0);
+        assert!(report.p50 <= report.p99 && report.p99 <= report.max);
+
+        let sharded = workload.run(Arc::new(ShardedNapMap::new(4))).await;
+        assert_eq!(sharded.misses, report.misses);
+    }
+}
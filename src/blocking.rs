@@ -0,0 +1,45 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Wake;
+use std::thread::Thread;
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Drives `fut` to completion on the calling thread, parking it between
+/// polls.
+///
+/// # Panics
+///
+/// Within a tokio runtime, `spawn_blocking` included, like tokio's own
+/// blocking calls: parking a worker thread would stall its other tasks.
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        panic!(
+            "Cannot block the current thread from within a runtime. This \
+             happens because a function attempted to block the current \
+             thread while the thread is being used to drive asynchronous \
+             tasks."
+        );
+    }
+    let waker = Arc::new(Unpark(std::thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = std::pin::pin!(fut);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
@@ -1,20 +1,172 @@
+use crate::backend::Attached;
+use crate::backend::Backend;
+use crate::backend::BackendError;
+use crate::backend::WriteOrder;
+use crate::backend::WriteThrough;
+use crate::dedup::Requests;
+use crate::error::lock;
+use crate::error::EntryTooLarge;
+use crate::error::GetError;
+use crate::error::InsertError;
+use crate::error::NapMapInternalError;
+use crate::eviction::Evictions;
+use crate::eviction::Usage;
+use crate::expiry::Expiry;
+use crate::gauge::Gauge;
+use crate::hooks::Authorizer;
+use crate::hooks::BlockingClone;
+use crate::hooks::Finalizer;
+use crate::hooks::HookPanic;
+use crate::hooks::Hooks;
+use crate::hooks::KeyFilter;
+use crate::hooks::OnEvict;
+use crate::hooks::OnRemove;
+use crate::hooks::Operation;
+use crate::hooks::RemovalCause;
+use crate::hooks::SlowWait;
+use crate::hooks::Weigher;
+use crate::loads::in_flight;
+use crate::loads::LoadGuard;
+use crate::loads::Loads;
+use crate::notifiers::NotifierStats;
+use crate::notifiers::Notifiers;
+use crate::notifiers::Registration;
+use crate::producers::Producers;
+use crate::room::Room;
+use crate::snapshot::MapSnapshot;
+use crate::stats::Counters;
+use crate::stats::NapMapStats;
+use crate::sync::AsyncRwLock;
+use crate::tombstones::AfterRemove;
+use crate::tombstones::Tombstones;
+use crate::version::EntryOrder;
+use crate::version::SwapError;
+use crate::version::Version;
+use crate::version::VersionError;
+use crate::watermark::HighWater;
+use crate::watermark::Watermarks;
 use indexmap::IndexMap;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::future::Future;
+use std::hash::BuildHasher;
 use std::hash::Hash;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::Mutex as AsyncMutex;
-use tokio::sync::Notify;
-use tokio::sync::RwLock as AsyncRwLock;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-pub struct NapMap<K, V>
+/// Which entry a full [`NapMap`] evicts to make room for a new key. Pinned
+/// entries are never evicted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The most recently inserted key.
+    #[default]
+    Lifo,
+    /// The least recently inserted key.
+    Fifo,
+    /// The key least recently inserted or read.
+    Lru,
+    /// The key read the fewest times, the least recently inserted on ties.
+    Lfu,
+}
+
+/// The lane an entry was inserted through, see
+/// [`NapMap::with_priority_reserve`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Lane {
+    /// Regular inserts, capped below the reserved share of the capacity.
+    #[default]
+    Normal,
+    /// Critical inserts, evicting normal entries first when the map is full.
+    High,
+}
+
+/// A handle to the map. Clones share the entries, the waiters and the
+/// configuration set so far, like the endpoints of a channel.
+#[derive(Clone)]
+pub struct NapMap<K, V, S = RandomState>
 where
     K: Eq + Hash + Clone + Debug,
     V: Clone + Debug,
 {
-    map: Arc<AsyncRwLock<IndexMap<K, V>>>,
-    notifiers: Arc<AsyncMutex<HashMap<K, Arc<Notify>>>>,
-    bound: usize,
+    map: Arc<AsyncRwLock<IndexMap<K, Slot<V>, S>>>,
+    notifiers: Arc<Notifiers<K, S>>,
+    versions: Arc<AtomicU64>,
+    requests: Arc<Requests<K, V>>,
+    napping: Arc<Gauge>,
+    loads: Loads<K>,
+    loading: Arc<Gauge>,
+    entries_peak: Arc<HighWater>,
+    counters: Arc<Counters>,
+    closed: Arc<AtomicBool>,
+    closed_keys: Arc<Mutex<Vec<KeyFilter<K>>>>,
+    room: Arc<Room>,
+    permits: Arc<AtomicUsize>,
+    producers: Arc<Producers<K>>,
+    bound: Arc<AtomicUsize>,
+    backend: Option<Attached<K, V>>,
+    finalizer: Option<Finalizer<K, V>>,
+    on_remove: Option<OnRemove<K, V>>,
+    on_evict: Option<OnEvict<K, V>>,
+    authorizer: Option<Authorizer<K>>,
+    hooks: Arc<Hooks>,
+    weigher: Option<Weigher<K, V>>,
+    slow_wait: Option<(Duration, SlowWait<K>)>,
+    max_entry_weight: Option<usize>,
+    blocking_clone: Option<(usize, BlockingClone<K, V>)>,
+    tombstones: Option<Arc<Tombstones<K>>>,
+    ttl: Option<Duration>,
+    grace: Duration,
+    expiry: Arc<Expiry<K>>,
+    span: Option<tracing::Span>,
+    evictions: Arc<Evictions<K>>,
+    clock: Arc<AtomicU64>,
+    // Share of the capacity kept for `Lane::High`, see `with_priority_reserve`
+    reserve: f64,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    value: V,
+    version: Version,
+    inserted_at: Instant,
+    expires_at: Option<Instant>,
+    pinned: bool,
+    usage: Usage,
+    lane: Lane,
+}
+
+impl<V> Slot<V> {
+    /// Expired entries are misses until they are overwritten or reaped.
+    fn is_live(&self) -> bool {
+        self.expires_at.is_none_or(|at| at > Instant::now())
+    }
+
+    /// Why the entry leaves the map when taken out for `cause`, expired ones
+    /// always leave as [`RemovalCause::Expired`].
+    fn leaving(&self, cause: RemovalCause) -> RemovalCause {
+        match self.is_live() {
+            true => cause,
+            false => RemovalCause::Expired,
+        }
+    }
+
+    /// Live, or expired less than `grace` ago.
+    fn is_within(&self, grace: Duration) -> bool {
+        self.expires_at.is_none_or(|at| at + grace > Instant::now())
+    }
 }
 
 pub fn napmap<K, V>(buffer: usize) -> NapMap<K, V>
@@ -25,149 +177,3796 @@ where
     NapMap::new(buffer)
 }
 
+pub fn napmap_with_policy<K, V>(buffer: usize, policy: EvictionPolicy) -> NapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    NapMap::new(buffer).with_eviction_policy(policy)
+}
+
 impl<K, V> NapMap<K, V>
 where
     K: Eq + Hash + Clone + Debug,
     V: Clone + Debug,
 {
+    /// Panics if `buffer` is 0, see [`try_new`](Self::try_new).
     pub fn new(buffer: usize) -> Self {
-        assert!(buffer > 0, "bounded napmap requires buffer > 0");
-        Self {
-            map: Arc::new(AsyncRwLock::new(IndexMap::with_capacity(buffer))),
-            notifiers: Arc::new(AsyncMutex::new(HashMap::new())),
-            bound: buffer,
+        Self::with_capacity_and_hasher(buffer, RandomState::new())
+    }
+
+    pub fn try_new(buffer: usize) -> Result<Self, NapMapInternalError> {
+        Self::try_with_capacity_and_hasher(buffer, RandomState::new())
+    }
+}
+
+impl<K, V, S> NapMap<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: BuildHasher + Clone,
+{
+    /// Like [`new`](NapMap::new), hashing both the entries and the waiters'
+    /// keys with `hasher`, e.g. a [`BuildIdHasher`](crate::BuildIdHasher)
+    /// for integer keys or a fixed-seed one for reproducible tests.
+    pub fn with_capacity_and_hasher(buffer: usize, hasher: S) -> Self {
+        match Self::try_with_capacity_and_hasher(buffer, hasher) {
+            Ok(napmap) => napmap,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    pub fn try_with_capacity_and_hasher(
+        buffer: usize,
+        hasher: S,
+    ) -> Result<Self, NapMapInternalError> {
+        if buffer == 0 {
+            return Err(NapMapInternalError::InvalidConfig(
+                "bounded napmap requires buffer > 0",
+            ));
         }
+        let counters = Arc::new(Counters::default());
+        Ok(Self {
+            map: Arc::new(AsyncRwLock::new(IndexMap::with_capacity_and_hasher(
+                buffer,
+                hasher.clone(),
+            ))),
+            notifiers: Arc::new(Notifiers::with_hasher(hasher)),
+            versions: Arc::new(AtomicU64::new(0)),
+            requests: Arc::new(Requests::new()),
+            napping: Arc::new(counters.napping()),
+            loads: Arc::new(Mutex::new(HashMap::new())),
+            loading: Arc::new(Gauge::new()),
+            entries_peak: Arc::new(HighWater::new()),
+            counters,
+            closed: Arc::new(AtomicBool::new(false)),
+            closed_keys: Arc::new(Mutex::new(Vec::new())),
+            room: Arc::new(Room::new()),
+            permits: Arc::new(AtomicUsize::new(0)),
+            producers: Arc::new(Producers::new()),
+            bound: Arc::new(AtomicUsize::new(buffer)),
+            backend: None,
+            finalizer: None,
+            on_remove: None,
+            on_evict: None,
+            authorizer: None,
+            hooks: Arc::new(Hooks::default()),
+            weigher: None,
+            slow_wait: None,
+            max_entry_weight: None,
+            blocking_clone: None,
+            tombstones: None,
+            ttl: None,
+            grace: Duration::ZERO,
+            expiry: Arc::new(Expiry::new()),
+            span: None,
+            evictions: Arc::new(Evictions::new(EvictionPolicy::default())),
+            clock: Arc::new(AtomicU64::new(0)),
+            reserve: 0.0,
+        })
+    }
+
+    /// Picks the entry evicted once the map is full, see [`EvictionPolicy`].
+    /// Reads are only tracked by [`EvictionPolicy::Lru`] and
+    /// [`EvictionPolicy::Lfu`], which re-rank the entry on every read. The
+    /// policy applies to every clone of the map.
+    pub fn with_eviction_policy(self, policy: EvictionPolicy) -> Self {
+        self.evictions.set_policy(policy);
+        self
+    }
+
+    /// Names the map and records its inserts, wakes, evictions and sweeps as
+    /// events of one long-lived span, so a single span query tells the map's
+    /// whole story. Under the `metrics` feature the name also labels the
+    /// map's metrics as `map`, for every clone of the map.
+    pub fn with_span_name(mut self, name: &str) -> Self {
+        self.span = Some(tracing::info_span!("napmap", name));
+        #[cfg(feature = "metrics")]
+        self.counters.name(name);
+        self
+    }
+
+    /// Reserves `fraction` (between 0 and 1) of the capacity for
+    /// [`Lane::High`] inserts, so bulk traffic through the normal lane can
+    /// never push critical entries out. Normal inserts evict normal entries
+    /// once they fill the rest. At least one slot is left to the normal lane.
+    /// The reserve follows the capacity through [`resize`](Self::resize).
+    pub fn with_priority_reserve(mut self, fraction: f64) -> Self {
+        self.reserve = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Slots of `capacity` kept for [`Lane::High`].
+    fn reserved(&self, capacity: usize) -> usize {
+        let reserved = (capacity as f64 * self.reserve).ceil() as usize;
+        reserved.min(capacity - 1)
+    }
+
+    /// Writes every insert and removal through to `backend`, see
+    /// [`WriteThrough`] for ordering and error handling. A `get` missing the
+    /// map loads the key from the backend before napping, the loaded value is
+    /// kept in the map. Evictions and expirations leave the backend alone, so
+    /// a key pushed out by capacity pressure is loaded back on its next miss.
+    pub fn with_backend(
+        mut self,
+        backend: impl Backend<K, V> + 'static,
+        policy: WriteThrough,
+    ) -> Self {
+        self.backend = Some(Attached {
+            backend: Arc::new(backend),
+            policy,
+        });
+        self
+    }
+
+    /// Runs `finalizer` on a background task for every value leaving the map,
+    /// whether it was evicted or overwritten. Meant for values owning
+    /// resources that need an async cleanup, like connections or child tasks.
+    ///
+    /// Must be used from within a tokio runtime.
+    pub fn with_finalizer<F, Fut>(mut self, finalizer: F) -> Self
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+        F: Fn(K, V) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.finalizer = Some(crate::hooks::finalizer(finalizer));
+        self
+    }
+
+    /// Calls `callback` inline for every entry removed through `remove` or
+    /// `clear`, while the write lock is still held, before any other task can
+    /// observe the removal. Keep it cheap, it is meant for logging and metrics.
+    pub fn with_on_remove(mut self, callback: impl Fn(&K, &V) + Send + Sync + 'static) -> Self {
+        self.on_remove = Some(Arc::new(callback));
+        self
+    }
+
+    /// Calls `listener` inline for every entry leaving the map, whether it
+    /// was removed, replaced, expired or evicted, see [`RemovalCause`].
+    /// Expired entries are reported once purged or overwritten. Keep it cheap,
+    /// it runs on the task whose operation made the entry leave.
+    pub fn with_on_evict(
+        mut self,
+        listener: impl Fn(&K, &V, RemovalCause) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_evict = Some(Arc::new(listener));
+        self
+    }
+
+    /// Consults `authorize` before every lookup, insert and removal of a key,
+    /// e.g. to keep each tenant of a shared map to its own namespace. Denied
+    /// lookups find nothing and `get_checked` fails with
+    /// [`GetError::Denied`], denied inserts fail with [`InsertError::Denied`]
+    /// and denied removals remove nothing. It runs inline, often under a
+    /// lock, so keep it cheap.
+    pub fn with_authorizer(
+        mut self,
+        authorize: impl Fn(&K, Operation) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.authorizer = Some(Arc::new(authorize));
+        self
+    }
+
+    /// Picks what a panicking hook does to the map operation running it, see
+    /// [`HookPanic`]. Panics unwind into the caller by default. Only the
+    /// synchronous part of a finalizer is covered, its future runs on a task
+    /// of its own.
+    pub fn with_hook_panic(mut self, on_panic: HookPanic) -> Self {
+        self.hooks = Arc::new(Hooks::new(on_panic));
+        self
+    }
+
+    /// Weighs entries for [`shrink_to_weight`](Self::shrink_to_weight), e.g. by
+    /// their size in bytes. Without a weigher every entry weighs 1.
+    pub fn with_weigher(
+        mut self,
+        weigher: impl Fn(&K, &V) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.weigher = Some(Arc::new(weigher));
+        self
+    }
+
+    /// Rejects entries weighing more than `max`, see
+    /// [`with_weigher`](Self::with_weigher), so one oversized value can't be
+    /// silently accepted into a shared map.
+    pub fn with_max_entry_weight(mut self, max: usize) -> Self {
+        self.max_entry_weight = Some(max);
+        self
+    }
+
+    /// Calls `callback` inline with the key and the elapsed time as soon as a
+    /// single `get` has napped for `threshold`, while it keeps napping. Meant
+    /// for alerting on coordination points that stall.
+    pub fn with_slow_wait(
+        mut self,
+        threshold: Duration,
+        callback: impl Fn(&K, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.slow_wait = Some((threshold, Arc::new(callback)));
+        self
+    }
+
+    /// Clones values weighing at least `min_weight` on the blocking pool when
+    /// [`get`](Self::get) returns them, so cloning a huge value doesn't stall
+    /// the async worker thread. Values are told apart by the
+    /// [`with_weigher`](Self::with_weigher) weight, e.g. a size in bytes.
+    ///
+    /// The clones go through `tokio::task::spawn_blocking`, so getting a heavy
+    /// value panics outside a tokio runtime.
+    pub fn with_blocking_clone(mut self, min_weight: usize) -> Self
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let clone = crate::hooks::blocking_clone(self.map.clone(), |map, k| {
+            map.get(k).filter(|s| s.is_live()).map(|s| s.value.clone())
+        });
+        self.blocking_clone = Some((min_weight, clone));
+        self
+    }
+
+    /// Caps how many distinct keys may have napping tasks at once. Past the
+    /// cap, [`get_checked`](Self::get_checked) on a new key fails right away
+    /// and [`get`](Self::get) returns `None`, so attacker-chosen keys can't
+    /// grow the notifier table without bound. Other napping methods are not
+    /// refused. The cap applies to every clone of the map.
+    pub fn with_max_pending_keys(self, limit: usize) -> Self {
+        self.notifiers.set_limit(limit);
+        self
     }
 
+    /// Remembers keys removed through `remove` or `clear` for `ttl`. Until
+    /// the key is inserted again, [`get_checked`](Self::get_checked) fails
+    /// with [`GetError::Removed`] and [`get`](Self::get) returns `None` right
+    /// away, instead of napping for a value that may never come back.
+    pub fn with_tombstones(mut self, ttl: Duration) -> Self {
+        self.tombstones = Some(Arc::new(Tombstones::new(Some(ttl))));
+        self
+    }
+
+    /// Picks whether `get` naps on a removed key or gives up right away, see
+    /// [`AfterRemove`]. Napping is the default.
+    pub fn with_after_remove(mut self, after_remove: AfterRemove) -> Self {
+        self.tombstones = match after_remove {
+            AfterRemove::Nap => None,
+            AfterRemove::Removed => Some(Arc::new(Tombstones::new(None))),
+        };
+        self
+    }
+
+    /// Expires every entry `ttl` after it was inserted, unless inserted
+    /// through [`insert_with_ttl`](Self::insert_with_ttl). Expired entries are
+    /// misses, `get` naps on them, see [`purge_expired`](Self::purge_expired)
+    /// to reclaim them.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Keeps expired entries readable through [`get_stale`](Self::get_stale)
+    /// for `grace` past their TTL, [`purge_expired`](Self::purge_expired)
+    /// leaves them in until then. Every other lookup still misses them.
+    pub fn with_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Same as [`insert_checked`](Self::insert_checked), rejections are logged.
     #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
     pub async fn insert(&self, k: K, v: V) {
+        if let Err(e) = self.insert_checked(k, v).await {
+            tracing::warn!("{e}");
+        }
+    }
+
+    /// Inserts like [`insert`](Self::insert), then waits, at most `timeout`,
+    /// for the tasks napping on `k` to observe the value. Returns how many
+    /// did, e.g. to release upstream resources once consumers got the value.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub async fn insert_acked(&self, k: K, v: V, timeout: Duration) -> usize {
+        let nap = self.notifiers.lock().await.get(&k);
+        let napping = nap.as_ref().map_or(0, |nap| nap.napping());
+        self.insert(k, v).await;
+        match nap {
+            Some(nap) => nap.acked(napping, timeout).await,
+            None => 0,
+        }
+    }
+
+    /// Inserts the output of `fut` under `k` once it completes, driving it on
+    /// a task holding a handle to the map. The future is dropped unfinished
+    /// if `k` is removed or if another one is registered for `k`. Meanwhile
+    /// `k` counts as [pending](Self::is_pending).
+    pub fn insert_from_future<F>(&self, k: K, fut: F)
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+        F: Future<Output = V> + Send + 'static,
+    {
+        if !self.allows(&k, Operation::Insert) {
+            tracing::error!("{}", InsertError::<V>::Denied);
+            return;
+        }
+        let map = self.clone();
+        self.producers.spawn(k.clone(), move |id| async move {
+            let running = map.producers.running(&k, id);
+            let v = crate::reentrant::producing(map.id(), &k, fut).await;
+            drop(running);
+            map.insert(k, v).await;
+        });
+    }
+
+    pub async fn insert_checked(&self, k: K, v: V) -> Result<(), InsertError<V>> {
+        self.insert_expiring(k, v, self.ttl, Lane::Normal).await
+    }
+
+    /// Like [`insert`](Self::insert), through the given lane. Normal entries
+    /// are evicted before high priority ones.
+    pub async fn insert_priority(&self, k: K, v: V, lane: Lane) {
+        if let Err(e) = self.insert_expiring(k, v, self.ttl, lane).await {
+            tracing::warn!("{e}");
+        }
+    }
+
+    /// Like [`insert`](Self::insert), but expires the entry after `ttl`
+    /// instead of the map's [`with_ttl`](Self::with_ttl).
+    pub async fn insert_with_ttl(&self, k: K, v: V, ttl: Duration) {
+        if let Err(e) = self.insert_expiring(k, v, Some(ttl), Lane::Normal).await {
+            tracing::warn!("{e}");
+        }
+    }
+
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    async fn insert_expiring(
+        &self,
+        k: K,
+        v: V,
+        ttl: Option<Duration>,
+        lane: Lane,
+    ) -> Result<(), InsertError<V>> {
         tracing::trace!("Insert");
+        if !self.allows(&k, Operation::Insert) {
+            return Err(InsertError::Denied);
+        }
+        self.check_weight(&k, &v)?;
+        self.persist_store(WriteOrder::BeforeVisible, &k, &v)
+            .await?;
+        let written = self.writes_after().then(|| v.clone());
 
         let mut map = self.map.write().await;
-        if map.len() >= self.bound {
-            map.pop();
-        }
-        map.insert(k.clone(), v);
+        self.admit(&mut map, k.clone(), v, ttl, lane);
         drop(map);
 
-        if let Some(notify) = self.notifiers.lock().await.remove(&k) {
+        self.wake(&k).await;
+        if let Some(v) = written {
+            self.persist_store(WriteOrder::AfterVisible, &k, &v).await?;
+        }
+        Ok(())
+    }
+
+    async fn wake(&self, k: &K) {
+        if let Some(notify) = self.notifiers.lock().await.remove(k) {
             notify.notify_waiters();
+            self.lifecycle("wake", k);
             tracing::trace!("Notified all waiting tasks");
         }
     }
 
-    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
-    pub async fn get(&self, k: K) -> Option<V> {
-        tracing::trace!("Get");
-        if self.map.read().await.contains_key(&k) {
-            tracing::debug!("Contains key");
-            return self.map.read().await.get(&k).cloned();
+    /// Inserts without evicting: at capacity, a new key's value is handed
+    /// back with [`InsertError::Full`]. Slots held through
+    /// [`reserve`](Self::reserve) count as taken, overwriting a key always
+    /// fits.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub async fn try_insert(&self, k: K, v: V) -> Result<(), InsertError<V>> {
+        tracing::trace!("Try insert");
+        if !self.allows(&k, Operation::Insert) {
+            return Err(InsertError::Denied);
+        }
+        self.check_weight(&k, &v)?;
+        match self.admit_if_room(k, v).await? {
+            None => Ok(()),
+            Some((_, v)) => Err(InsertError::Full(v)),
+        }
+    }
+
+    /// Like [`try_insert`](Self::try_insert), but waits up to `timeout` for
+    /// entries to leave the map when it is full. Fails with
+    /// [`InsertError::Closed`] once `k` is closed meanwhile.
+    ///
+    /// Producers waiting here or in [`reserve`](Self::reserve) are served in
+    /// the order they came, in a queue of their own: gets napping on a key
+    /// are never woken by room being made, nor held up by its waiters.
+    pub async fn insert_timeout(
+        &self,
+        k: K,
+        v: V,
+        timeout: Duration,
+    ) -> Result<(), InsertError<V>> {
+        if !self.allows(&k, Operation::Insert) {
+            return Err(InsertError::Denied);
+        }
+        self.check_weight(&k, &v)?;
+        let deadline = Instant::now() + timeout;
+        let (mut k, mut v) = (k, v);
+        let turn = self.room.join();
+        loop {
+            if self.is_closed_for(&k) {
+                return Err(InsertError::Closed);
+            }
+            if turn.is_first() {
+                match self.admit_if_room(k, v).await? {
+                    None => return Ok(()),
+                    Some(full) => (k, v) = full,
+                }
+            }
+            if tokio::time::timeout_at(deadline, turn.wait())
+                .await
+                .is_err()
+            {
+                tracing::debug!("Gave up waiting for room");
+                return Err(InsertError::Full(v));
+            }
+        }
+    }
+
+    /// Inserts unless that takes evicting, handing the entry back then.
+    async fn admit_if_room(&self, k: K, v: V) -> Result<Option<(K, V)>, BackendError> {
+        let mut map = self.map.write().await;
+        if !map.contains_key(&k) && !self.has_room(&map, self.permits.load(Ordering::Relaxed)) {
+            tracing::debug!("Full");
+            return Ok(Some((k, v)));
+        }
+        self.persist_store(WriteOrder::BeforeVisible, &k, &v)
+            .await?;
+        let written = self.writes_after().then(|| v.clone());
+        self.admit(&mut map, k.clone(), v, self.ttl, Lane::Normal);
+        drop(map);
+
+        self.wake(&k).await;
+        if let Some(v) = written {
+            self.persist_store(WriteOrder::AfterVisible, &k, &v).await?;
+        }
+        Ok(None)
+    }
+
+    /// Waits until the map has room for one more key without evicting, and
+    /// holds that slot until the returned [`Permit`] inserts or is dropped.
+    /// Producers can reserve before doing the work of building a value.
+    /// Only the inserts that refuse to evict honor reservations, a plain
+    /// [`insert`](Self::insert) still evicts to make room. Waits in turn with
+    /// [`insert_timeout`](Self::insert_timeout).
+    pub async fn reserve(&self) -> Permit<'_, K, V, S> {
+        let turn = self.room.join();
+        loop {
+            if turn.is_first() {
+                // Writers are held off, concurrent reservations race on the count
+                let map = self.map.read().await;
+                let mut held = self.permits.load(Ordering::Relaxed);
+                while self.has_room(&map, held) {
+                    match self.permits.compare_exchange_weak(
+                        held,
+                        held + 1,
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => return Permit { napmap: self },
+                        Err(current) => held = current,
+                    }
+                }
+            }
+            tracing::trace!("Waiting for room...");
+            turn.wait().await;
+        }
+    }
+
+    /// The most entries the map holds before it evicts.
+    pub fn capacity(&self) -> usize {
+        self.bound.load(Ordering::Relaxed)
+    }
+
+    /// Keys that still fit without evicting, minus the slots held through
+    /// [`reserve`](Self::reserve).
+    pub async fn remaining(&self) -> usize {
+        let len = self.map.read().await.len();
+        self.capacity()
+            .saturating_sub(len + self.permits.load(Ordering::Relaxed))
+    }
+
+    /// Changes the capacity of the map, of every clone of it too. Growing
+    /// wakes the producers waiting in [`reserve`](Self::reserve) or
+    /// [`insert_timeout`](Self::insert_timeout) right away. Shrinking evicts
+    /// nothing: the map stops growing and gets under the new capacity as
+    /// entries are removed, each insert of a new key evicting one meanwhile.
+    pub fn resize(&self, capacity: usize) -> Result<(), NapMapInternalError> {
+        if capacity == 0 {
+            return Err(NapMapInternalError::InvalidConfig(
+                "bounded napmap requires buffer > 0",
+            ));
+        }
+        let old = self.bound.swap(capacity, Ordering::Relaxed);
+        tracing::debug!("Resized from {old} to {capacity}");
+        if capacity > old {
+            self.room.made();
         }
+        Ok(())
+    }
+
+    fn has_room(&self, map: &IndexMap<K, Slot<V>, S>, permits: usize) -> bool {
+        map.len() + permits < self.capacity()
+    }
+
+    /// Inserts all `pairs` under a single write lock, so readers observe either
+    /// none or all of them, and wakes their waiters only once every pair is in.
+    ///
+    /// With more pairs than the map's capacity, the earliest ones of the batch
+    /// are evicted by the later ones. With a [`WriteOrder::BeforeVisible`]
+    /// backend, every pair is persisted first and a propagated failure leaves
+    /// the map untouched, the pairs persisted so far are restored to their
+    /// previous value or deleted from the backend.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn insert_batch_atomic(
+        &self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<(), InsertError<V>> {
+        tracing::trace!("Insert batch");
+        let pairs: Vec<(K, V)> = pairs.into_iter().collect();
+        for (k, v) in &pairs {
+            if !self.allows(k, Operation::Insert) {
+                return Err(InsertError::Denied);
+            }
+            self.check_weight(k, v)?;
+        }
+        self.persist_batch(&pairs).await?;
+        let written = self.writes_after().then(|| pairs.clone());
+
+        let mut map = self.map.write().await;
+        let keys: Vec<K> = pairs
+            .into_iter()
+            .map(|(k, v)| {
+                self.admit(&mut map, k.clone(), v, self.ttl, Lane::Normal);
+                k
+            })
+            .collect();
+        drop(map);
 
         let mut notifiers = self.notifiers.lock().await;
-        let notify = notifiers
-            .entry(k.clone())
-            .or_insert(Arc::new(Notify::new()))
-            .clone();
+        for k in &keys {
+            if let Some(notify) = notifiers.remove(k) {
+                notify.notify_waiters();
+                self.lifecycle("wake", k);
+            }
+        }
         drop(notifiers);
-
-        tracing::trace!("Waiting...");
-        notify.notified().await;
-        tracing::trace!("Notified, data is available");
-        self.map.read().await.get(&k).cloned()
+        tracing::trace!("Notified all waiting tasks");
+        for (k, v) in written.iter().flatten() {
+            self.persist_store(WriteOrder::AfterVisible, k, v).await?;
+        }
+        Ok(())
     }
 
-    pub async fn len(&self) -> usize {
-        self.map.read().await.len()
+    /// Same as [`insert_batch_atomic`](Self::insert_batch_atomic), failures
+    /// are logged.
+    pub async fn insert_many(&self, pairs: impl IntoIterator<Item = (K, V)>) {
+        if let Err(e) = self.insert_batch_atomic(pairs).await {
+            tracing::error!("{e}");
+        }
     }
 
-    pub async fn is_empty(&self) -> bool {
-        self.map.read().await.is_empty()
+    /// Inserts only if the entry is still at `expected`, `None` meaning that
+    /// the key must be absent. Returns the version of the new entry.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub async fn insert_if_version(
+        &self,
+        k: K,
+        v: V,
+        expected: Option<Version>,
+    ) -> Result<Version, VersionError> {
+        tracing::trace!("Insert if version");
+        if !self.allows(&k, Operation::Insert) {
+            return Err(VersionError::Denied);
+        }
+        let mut map = self.map.write().await;
+        let current = map.get(&k).filter(|s| s.is_live()).map(|s| s.version);
+        if current != expected {
+            return Err(VersionError::Mismatch { current });
+        }
+        self.persist_store(WriteOrder::BeforeVisible, &k, &v)
+            .await?;
+        let written = self.writes_after().then(|| v.clone());
+        let version = self.admit(&mut map, k.clone(), v, self.ttl, Lane::Normal);
+        drop(map);
+
+        self.wake(&k).await;
+        if let Some(v) = written {
+            self.persist_store(WriteOrder::AfterVisible, &k, &v).await?;
+        }
+        Ok(version)
     }
-}
 
-impl<K, V> Debug for NapMap<K, V>
-where
-    K: Eq + Hash + Clone + Debug,
-    V: Clone + Debug,
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("NapMap")
-            .field("map", &self.map)
-            .field("notifiers", &self.notifiers)
-            .finish()
+    /// Applies `f` to the value of `k` under the write lock, waking the tasks
+    /// napping on it like an insert. Returns the updated value and its
+    /// version, `None` if `k` is absent.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, f))]
+    pub async fn update(
+        &self,
+        k: K,
+        f: impl FnOnce(&mut V),
+    ) -> Result<Option<(V, Version)>, InsertError<V>> {
+        tracing::trace!("Update");
+        if !self.allows(&k, Operation::Insert) {
+            return Err(InsertError::Denied);
+        }
+        let mut map = self.map.write().await;
+        let Some(slot) = map.get(&k).filter(|s| s.is_live()) else {
+            return Ok(None);
+        };
+        let (mut v, lane) = (slot.value.clone(), slot.lane);
+        f(&mut v);
+        self.check_weight(&k, &v)?;
+        self.persist_store(WriteOrder::BeforeVisible, &k, &v)
+            .await?;
+        let version = self.admit(&mut map, k.clone(), v.clone(), self.ttl, lane);
+        drop(map);
+
+        self.wake(&k).await;
+        self.persist_store(WriteOrder::AfterVisible, &k, &v).await?;
+        Ok(Some((v, version)))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::NapMap;
-    use std::sync::Arc;
-    use std::time::Duration;
-    use tracing_subscriber::EnvFilter;
+    /// Swaps in `new` only if the entry still holds `expected`, `None`
+    /// meaning that the key must be absent. Returns the version of the new
+    /// entry, or the value found instead.
+    ///
+    /// The comparison is against the value as of one write: another writer
+    /// storing an equal value meanwhile still fails the swap.
+    pub async fn compare_and_swap(
+        &self,
+        k: K,
+        expected: Option<&V>,
+        new: V,
+    ) -> Result<Version, SwapError<V>>
+    where
+        V: PartialEq,
+    {
+        if !self.allows(&k, Operation::Insert) {
+            return Err(SwapError::Denied);
+        }
+        let current = self.peek_versioned(&k).await;
+        if current.as_ref().map(|(v, _)| v) != expected {
+            return Err(SwapError::Mismatch {
+                current: current.map(|(v, _)| v),
+            });
+        }
+        let version = current.map(|(_, version)| version);
+        match self.insert_if_version(k.clone(), new, version).await {
+            Ok(version) => Ok(version),
+            Err(VersionError::Backend(e)) => Err(SwapError::Backend(e)),
+            Err(VersionError::Denied) => Err(SwapError::Denied),
+            Err(VersionError::Mismatch { .. }) => Err(SwapError::Mismatch {
+                current: self.peek_versioned(&k).await.map(|(v, _)| v),
+            }),
+        }
+    }
 
-    // Add this to a test to see the logs
-    fn _tracing_sub() {
-        let env_filter =
-            EnvFilter::from_default_env().add_directive("napmap=trace".parse().unwrap());
-        tracing_subscriber::fmt().with_env_filter(env_filter).init();
+    /// Read-copy-update: computes a new value from the current one (`None` when
+    /// the key is absent) and swaps it in only if no other writer touched the
+    /// entry meanwhile, retrying against the fresher value otherwise.
+    ///
+    /// `f` may run several times and should be free of side effects. Never
+    /// fails with [`VersionError::Mismatch`], which is retried.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, f))]
+    pub async fn rcu<F>(&self, k: K, mut f: F) -> Result<V, VersionError>
+    where
+        F: FnMut(Option<&V>) -> V,
+    {
+        if !self.allows(&k, Operation::Insert) {
+            return Err(VersionError::Denied);
+        }
+        loop {
+            let current = self.peek_versioned(&k).await;
+            let new = f(current.as_ref().map(|(v, _)| v));
+            let expected = current.map(|(_, version)| version);
+            match self
+                .insert_if_version(k.clone(), new.clone(), expected)
+                .await
+            {
+                Ok(_) => return Ok(new),
+                Err(VersionError::Mismatch { .. }) => {
+                    tracing::trace!("Entry changed concurrently, retrying");
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    #[tokio::test]
-    async fn it_should_wait_until_data_is_inserted() {
-        let napmap = Arc::new(NapMap::new(10));
+    fn admit(
+        &self,
+        map: &mut IndexMap<K, Slot<V>, S>,
+        k: K,
+        v: V,
+        ttl: Option<Duration>,
+        lane: Lane,
+    ) -> Version {
+        let version = Version(self.versions.fetch_add(1, Ordering::Relaxed) + 1);
+        self.counters.insert();
+        self.lifecycle("insert", &k);
+        if let Some(tombstones) = &self.tombstones {
+            tombstones.revive(&k);
+        }
+        if let Some(slot) = map.get_mut(&k) {
+            let cause = match slot.is_live() {
+                true => RemovalCause::Replaced,
+                false => RemovalCause::Expired,
+            };
+            let old = std::mem::replace(&mut slot.value, v);
+            if let Some(at) = slot.expires_at {
+                self.expiry.unschedule(&k, at);
+            }
+            slot.version = version;
+            slot.inserted_at = Instant::now();
+            slot.expires_at = ttl.map(|ttl| slot.inserted_at + ttl);
+            if let Some(at) = slot.expires_at {
+                self.expiry.schedule(k.clone(), at);
+            }
+            if slot.lane != lane {
+                self.evictions
+                    .relane(slot.lane, lane, &slot.usage, slot.pinned);
+                slot.lane = lane;
+            }
+            self.touch(slot);
+            self.retire(k, old, cause);
+            return version;
+        }
 
-        tokio::spawn({
-            let map = napmap.clone();
-            async move {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                map.insert("key", 7).await;
+        let capacity = self.capacity();
+        let reserved = self.reserved(capacity);
+        if lane == Lane::Normal && reserved > 0 {
+            let normal = capacity - reserved;
+            while self.evictions.occupancy(Lane::Normal) >= normal {
+                let Some((k, evicted)) = self.evict_one_in(map, Some(Lane::Normal)) else {
+                    break;
+                };
+                let cause = evicted.leaving(RemovalCause::Evicted);
+                self.retire(k, evicted.value, cause);
             }
-        });
+        }
+        // One out for one in, a map left above a shrunk capacity only gets
+        // back under it as entries are removed
+        if map.len() >= capacity {
+            let evicted = self
+                .evict_one_in(map, Some(Lane::Normal))
+                .or_else(|| self.evict_one(map));
+            match evicted {
+                Some((k, evicted)) => {
+                    let cause = evicted.leaving(RemovalCause::Evicted);
+                    self.retire(k, evicted.value, cause);
+                }
+                None => tracing::warn!("Every entry is pinned, exceeding capacity"),
+            }
+        }
+        let inserted_at = Instant::now();
+        let expires_at = ttl.map(|ttl| inserted_at + ttl);
+        if let Some(at) = expires_at {
+            self.expiry.schedule(k.clone(), at);
+        }
+        let slot = Slot {
+            value: v,
+            version,
+            inserted_at,
+            expires_at,
+            pinned: false,
+            usage: Usage::new(self.tick()),
+            lane,
+        };
+        self.evictions.link(k.clone(), lane, &slot.usage);
+        map.insert(k, slot);
+        self.entries_peak.observe(map.len());
+        version
+    }
 
-        let res = napmap.get("key").await.unwrap();
-        assert_eq!(res, 7);
+    fn evict_one(&self, map: &mut IndexMap<K, Slot<V>, S>) -> Option<(K, Slot<V>)> {
+        self.evict_one_in(map, None)
     }
 
-    #[tokio::test]
-    async fn it_should_notify_all_waiters() {
-        let napmap = Arc::new(NapMap::new(10));
+    /// Evicts by the map's policy, among the entries of `lane` if given.
+    fn evict_one_in(
+        &self,
+        map: &mut IndexMap<K, Slot<V>, S>,
+        lane: Option<Lane>,
+    ) -> Option<(K, Slot<V>)> {
+        let victim = self.evictions.victim(lane, || {
+            map.iter().map(|(k, s)| (k, s.lane, &s.usage, s.pinned))
+        })?;
+        let evicted = self.take_entry(map, &victim)?;
+        self.lifecycle("evict", &evicted.0);
+        Some(evicted)
+    }
 
-        tokio::spawn({
-            let map = napmap.clone();
-            async move {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                map.insert("key", 7).await;
+    /// Removes the entry in constant time, moving the last one in its place.
+    fn take_entry(&self, map: &mut IndexMap<K, Slot<V>, S>, k: &K) -> Option<(K, Slot<V>)> {
+        let (k, slot) = map.swap_remove_entry(k)?;
+        self.evictions.unlink(slot.lane, &slot.usage, slot.pinned);
+        if let Some(at) = slot.expires_at {
+            self.expiry.unschedule(&k, at);
+        }
+        Some((k, slot))
+    }
+
+    /// Number of entries inserted through `lane`.
+    pub async fn lane_occupancy(&self, lane: Lane) -> usize {
+        let _map = self.map.read().await;
+        self.evictions.occupancy(lane)
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Records a read or an overwrite of the entry for the eviction policy.
+    fn touch(&self, slot: &Slot<V>) {
+        if self.evictions.tracks_reads() {
+            self.evictions
+                .touch(slot.lane, &slot.usage, slot.pinned, self.tick());
+        }
+    }
+
+    /// Exempts the entry from capacity evictions and shedding until it is
+    /// unpinned or removed, overwriting it keeps it pinned. Returns `false` if
+    /// the key is absent.
+    ///
+    /// Once every entry is pinned, inserts of new keys grow the map past its
+    /// capacity. It shrinks back as entries get unpinned. Pinning counts as a
+    /// write for the authorizer, `false` when it is denied.
+    pub async fn pin(&self, k: K) -> bool {
+        if !self.allows(&k, Operation::Insert) {
+            return false;
+        }
+        match self.map.write().await.get_mut(&k) {
+            Some(slot) => {
+                if !slot.pinned {
+                    self.evictions.pin(slot.lane, &slot.usage);
+                    slot.pinned = true;
+                }
+                true
             }
-        });
+            None => false,
+        }
+    }
 
-        let first_handle = tokio::spawn({
-            let map = napmap.clone();
-            async move {
-                let res = map.get("key").await.unwrap();
-                assert_eq!(res, 7);
+    /// Makes the entry evictable again, returns `false` if the key is absent
+    /// or the authorizer denies writing it.
+    pub async fn unpin(&self, k: K) -> bool {
+        if !self.allows(&k, Operation::Insert) {
+            return false;
+        }
+        let mut map = self.map.write().await;
+        let Some(slot) = map.get_mut(&k) else {
+            return false;
+        };
+        if slot.pinned {
+            self.evictions.unpin(k, slot.lane, &slot.usage);
+            slot.pinned = false;
+        }
+
+        let mut evicted = Vec::new();
+        while map.len() > self.capacity() {
+            match self.evict_one(&mut map) {
+                Some(entry) => evicted.push(entry),
+                None => break,
             }
-        });
+        }
+        drop(map);
 
-        let second_handle = tokio::spawn({
-            let map = napmap.clone();
-            async move {
-                let res = map.get("key").await.unwrap();
-                assert_eq!(res, 7);
+        for (k, slot) in evicted {
+            let cause = slot.leaving(RemovalCause::Evicted);
+            self.retire(k, slot.value, cause);
+        }
+        true
+    }
+
+    async fn persist_store(&self, order: WriteOrder, k: &K, v: &V) -> Result<(), BackendError> {
+        match &self.backend {
+            Some(attached) if attached.policy.order == order => {
+                attached.persist(attached.backend.store(k, v)).await
             }
-        });
+            _ => Ok(()),
+        }
+    }
 
-        first_handle.await.unwrap();
-        second_handle.await.unwrap();
+    /// Persists a batch ahead of publishing it, see
+    /// [`insert_batch_atomic`](Self::insert_batch_atomic).
+    async fn persist_batch(&self, pairs: &[(K, V)]) -> Result<(), BackendError> {
+        let Some(attached) = self
+            .backend
+            .as_ref()
+            .filter(|a| a.policy.order == WriteOrder::BeforeVisible)
+        else {
+            return Ok(());
+        };
+        let previous: Vec<Option<V>> = {
+            let map = self.map.read().await;
+            pairs
+                .iter()
+                .map(|(k, _)| map.get(k).filter(|s| s.is_live()).map(|s| s.value.clone()))
+                .collect()
+        };
+        attached.store_batch(pairs, &previous).await
     }
 
-    #[tokio::test]
-    async fn it_should_not_exceed_the_provided_buffer_size() {
-        let napmap = Arc::new(NapMap::new(3));
+    /// Whether inserts are written to the backend once visible, which takes
+    /// a clone of the value.
+    fn writes_after(&self) -> bool {
+        self.backend
+            .as_ref()
+            .is_some_and(|a| a.policy.order == WriteOrder::AfterVisible)
+    }
+
+    /// Deletes `keys` from the backend if it is written in `order`. The
+    /// removals return no error, failures are logged and `false` tells the
+    /// caller to leave the map alone.
+    async fn delete_through<'a>(
+        &self,
+        order: WriteOrder,
+        keys: impl IntoIterator<Item = &'a K>,
+    ) -> bool
+    where
+        K: 'a,
+    {
+        let Some(attached) = self.backend.as_ref().filter(|a| a.policy.order == order) else {
+            return true;
+        };
+        for k in keys {
+            if let Err(e) = attached.persist(attached.backend.delete(k)).await {
+                tracing::error!("{e}");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Loads a missing `k` from the backend and publishes it, unless an
+    /// insert got there first. A failed load is logged and counts as a miss,
+    /// the caller naps on.
+    async fn load_from_backend(&self, k: &K) -> Option<V> {
+        let attached = self.backend.as_ref()?;
+        if !self.allows(k, Operation::Get) {
+            return None;
+        }
+        let v = match attached.backend.load(k).await {
+            Ok(v) => v?,
+            Err(e) => {
+                tracing::error!("Backend load failed: {e}");
+                return None;
+            }
+        };
+        let mut map = self.map.write().await;
+        if let Some(slot) = map.get(k).filter(|s| s.is_live()) {
+            return Some(slot.value.clone());
+        }
+        self.admit(&mut map, k.clone(), v.clone(), self.ttl, Lane::Normal);
+        drop(map);
+
+        self.wake(k).await;
+        Some(v)
+    }
+
+    /// Whether values leaving the map are needed past the removal.
+    fn retires(&self) -> bool {
+        self.finalizer.is_some() || self.on_evict.is_some()
+    }
+
+    fn retire(&self, k: K, v: V, cause: RemovalCause) {
+        self.room.made();
+        if let Some(on_evict) = &self.on_evict {
+            self.hooks.call("on_evict", || on_evict(&k, &v, cause));
+        }
+        if let Some(finalizer) = &self.finalizer {
+            tracing::trace!("Finalizing");
+            self.hooks.call("finalizer", || finalizer(k, v));
+        }
+    }
+
+    /// Records an insert, a wake or an eviction of `k` under the span of
+    /// [`with_span_name`](Self::with_span_name).
+    fn lifecycle(&self, event: &'static str, k: &K) {
+        if let Some(span) = &self.span {
+            tracing::debug!(parent: span, key = ?k, "{event}");
+        }
+    }
+
+    /// Records a sweep over the whole map, see [`lifecycle`](Self::lifecycle).
+    fn swept(&self, event: &'static str, count: usize) {
+        if let Some(span) = &self.span {
+            tracing::debug!(parent: span, count, "{event}");
+        }
+    }
+
+    /// Like [`get`](Self::get), but repeated gets of `k` tagged with the same
+    /// `request`, e.g. retries of one handler, share a single nap and wakeup
+    /// instead of napping side by side.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn get_for_request(&self, k: K, request: u64) -> Option<V> {
+        self.requests.dedup(k.clone(), request, self.get(&k)).await
+    }
+
+    /// Same as [`get_checked`](Self::get_checked), a refused key is `None`.
+    ///
+    /// Takes any borrowed form of the key, like `&str` for `String` keys, and
+    /// only allocates an owned key when the fast path below misses.
+    ///
+    /// A hit first tries the lock without awaiting, a best-effort fast path:
+    /// while a writer holds or waits for the lock, the hit awaits like a miss.
+    /// Awaiting lookups spend from tokio's cooperative budget, so a task
+    /// looping over contended or missing keys yields to its peers once it
+    /// runs out.
+    pub async fn get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(v) = self.clone_now(k) {
+            self.counters.lookup(true);
+            return Some(v);
+        }
+        self.get_or_nap(k.to_owned(), false).await.ok()
+    }
+
+    /// Like [`get`](Self::get), but opts out of tokio's cooperative budget,
+    /// for callers that yield on their own terms.
+    pub async fn get_uncooperative<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        tokio::task::unconstrained(self.get(k)).await
+    }
+
+    /// Whether a task naps on `k` or computes it through
+    /// [`get_or_compute`](Self::get_or_compute) or
+    /// [`insert_from_future`](Self::insert_from_future), without registering
+    /// interest in it. Producers can skip results nobody waits for anymore.
+    pub async fn is_pending(&self, k: &K) -> bool {
+        if !self.allows(k, Operation::Get) {
+            return false;
+        }
+        let loading = lock(&self.loads).contains_key(k) || self.producers.contains(k);
+        loading || self.notifiers.lock().await.is_held(k)
+    }
+
+    /// Returns the value of `k`, or runs `compute` and inserts its output when
+    /// there is none. Concurrent calls for a missing key run `compute` once:
+    /// the first one computes while the others nap until the value lands.
+    /// A key the authorizer keeps from reads is computed without touching the
+    /// map.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn get_or_compute<F, Fut>(&self, k: K, compute: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        if !self.allows(&k, Operation::Get) {
+            return compute().await;
+        }
+        let guard = loop {
+            if let Some(v) = self.try_get(&k).await {
+                return v;
+            }
+            if let Some(guard) = LoadGuard::acquire(&self.loads, &self.loading, &k) {
+                break guard;
+            }
+            if let Some(notify) = in_flight(&self.loads, &k) {
+                let notified = notify.notified();
+                // The computation might have finished between the lookup and the registration
+                if lock(&self.loads).contains_key(&k) {
+                    crate::reentrant::check_nap(self.id(), &k);
+                    tracing::trace!("Waiting for in-flight computation...");
+                    notified.await;
+                }
+            }
+        };
+
+        tracing::trace!("Computing");
+        let v = crate::reentrant::producing(self.id(), &k, compute()).await;
+        self.insert(guard.k.clone(), v.clone()).await;
+        v
+    }
+
+    /// Like [`get_checked`](Self::get_checked), but gives up after `timeout`.
+    pub async fn get_timeout(&self, k: K, timeout: Duration) -> Result<V, GetError> {
+        self.get_deadline(k, Instant::now() + timeout).await
+    }
+
+    /// Like [`get_checked`](Self::get_checked), but gives up at `deadline`
+    /// with [`GetError::TimedOut`]. The last waiter giving up on a key drops
+    /// its notifier, so abandoned keys don't pile up.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn get_deadline(&self, k: K, deadline: Instant) -> Result<V, GetError> {
+        match tokio::time::timeout_at(deadline, self.get_checked(k)).await {
+            Ok(got) => got,
+            Err(_) => {
+                tracing::debug!("Gave up waiting");
+                Err(GetError::TimedOut)
+            }
+        }
+    }
+
+    /// Returns the value right away, `None` if the key is absent rather than
+    /// napping on it.
+    pub async fn try_get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let v = self.clone_out(k).await;
+        self.counters.lookup(v.is_some());
+        v
+    }
+
+    /// Like [`try_get`](Self::try_get), also returning a value expired less
+    /// than the [`with_grace`](Self::with_grace) window ago, e.g. to serve a
+    /// slightly old value rather than an error.
+    pub async fn get_stale<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let map = self.map.read().await;
+        let v = map
+            .get_key_value(k)
+            .filter(|(k, s)| s.is_within(self.grace) && self.allows(k, Operation::Get))
+            .map(|(_, s)| s.value.clone());
+        self.counters.lookup(v.is_some());
+        v
+    }
+
+    pub async fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let map = self.map.read().await;
+        map.get_key_value(k)
+            .is_some_and(|(k, s)| self.visible(k, s))
+    }
+
+    /// Naps until `k` is available. Fails right away when `k` would exceed
+    /// [`with_max_pending_keys`](Self::with_max_pending_keys), or when it is
+    /// still held past its TTL, with the instant it expired at. See
+    /// [`GetError`] for the other reasons to give up.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn get_checked(&self, k: K) -> Result<V, GetError> {
+        tracing::trace!("Get");
+        self.get_or_nap(k, true).await
+    }
+
+    /// Where `get` naps on expired keys like on missing ones, `get_checked`
+    /// reports them.
+    async fn get_or_nap(&self, k: K, report_expired: bool) -> Result<V, GetError> {
+        if !self.allows(&k, Operation::Get) {
+            return Err(GetError::Denied);
+        }
+        if let Some(v) = self.clone_out(&k).await {
+            tracing::debug!("Contains key");
+            self.counters.lookup(true);
+            return Ok(v);
+        }
+        self.counters.lookup(false);
+        loop {
+            if self.is_closed_for(&k) {
+                return Err(GetError::Closed);
+            }
+            if self.buried(&k) {
+                tracing::debug!("Removed");
+                return Err(GetError::Removed);
+            }
+            if let Some(at) = self.expired_at(&k).await.filter(|_| report_expired) {
+                tracing::debug!("Expired");
+                return Err(GetError::Expired { at });
+            }
+            if let Some(v) = self.load_from_backend(&k).await {
+                tracing::debug!("Loaded from the backend");
+                return Ok(v);
+            }
+
+            let mut notifiers = self.notifiers.lock().await;
+            let notify = notifiers.try_register(&k)?;
+            drop(notifiers);
+
+            let napping = notify.enter();
+            let notified = notify.notified();
+            // Checked once registered, so a concurrent close or insert can't go unnoticed
+            if self.is_closed_for(&k) {
+                return Err(GetError::Closed);
+            }
+            if let Some(v) = self.clone_out(&k).await {
+                return Ok(v);
+            }
+            self.nap(&k, notified).await;
+            if notify.is_evicted() {
+                return Err(match self.is_closed_for(&k) {
+                    true => GetError::Closed,
+                    false => GetError::Evicted,
+                });
+            }
+            if let Some(v) = self.clone_out(&k).await {
+                tracing::trace!("Notified, data is available");
+                napping.delivered();
+                return Ok(v);
+            }
+            tracing::debug!("Gone before it was read, napping again");
+        }
+    }
+
+    /// When `k` expired, if it is still held past its deadline.
+    async fn expired_at(&self, k: &K) -> Option<Instant> {
+        let map = self.map.read().await;
+        map.get(k).filter(|s| !s.is_live())?.expires_at
+    }
+
+    /// Tries to serve a hit without awaiting, `None` when a writer holds or
+    /// waits for the lock, the key is missing or its value is cloned on the
+    /// blocking pool.
+    fn clone_now<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let map = self.map.try_read().ok()?;
+        let (k, slot) = map
+            .get_key_value(k)
+            .filter(|(k, s)| s.is_live() && self.allows(k, Operation::Get))?;
+        if let Some((min_weight, _)) = &self.blocking_clone {
+            if self.weigh(k, &slot.value) >= *min_weight {
+                return None;
+            }
+        }
+        self.touch(slot);
+        Some(slot.value.clone())
+    }
+
+    async fn clone_out<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let map = self.map.read().await;
+        let (k, slot) = map
+            .get_key_value(k)
+            .filter(|(k, s)| s.is_live() && self.allows(k, Operation::Get))?;
+        self.touch(slot);
+        match &self.blocking_clone {
+            Some((min_weight, clone)) if self.weigh(k, &slot.value) >= *min_weight => {
+                let k = k.clone();
+                drop(map);
+                tracing::trace!("Cloning on the blocking pool");
+                clone(k).await
+            }
+            _ => Some(slot.value.clone()),
+        }
+    }
+
+    /// Naps until every key is present, then reads all of them under a single
+    /// read lock, so the values come from one consistent point in time.
+    /// Fails with [`GetError::Denied`] if the authorizer keeps any of them
+    /// from reads, with [`GetError::Closed`] once one of the missing keys is
+    /// closed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn get_all_atomic(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<Vec<V>, GetError> {
+        tracing::trace!("Get all");
+        let keys: Vec<K> = keys.into_iter().collect();
+        if !keys.iter().all(|k| self.allows(k, Operation::Get)) {
+            return Err(GetError::Denied);
+        }
+        loop {
+            let map = self.map.read().await;
+            let missing: Vec<&K> = keys
+                .iter()
+                .filter(|k| !map.get(*k).is_some_and(Slot::is_live))
+                .collect();
+            if missing.is_empty() {
+                return Ok(keys
+                    .iter()
+                    .map(|k| {
+                        self.touch(&map[k]);
+                        map[k].value.clone()
+                    })
+                    .collect());
+            }
+
+            // Registering while holding the read lock guarantees that no insert
+            // of a missing key can slip in unnoticed
+            let mut notifiers = self.notifiers.lock().await;
+            let notifies: Vec<Registration<K, S>> =
+                missing.iter().map(|k| notifiers.register(k)).collect();
+            drop(notifiers);
+            let mut notified: Vec<_> = notifies.iter().map(|n| Box::pin(n.notified())).collect();
+            drop(map);
+            // Checked once registered, so a concurrent close can't go unnoticed
+            if missing.iter().any(|k| self.is_closed_for(k)) {
+                return Err(GetError::Closed);
+            }
+
+            tracing::trace!("Waiting...");
+            let _napping = self.napping.enter();
+            // Any write or close ends the nap, the loop checks them all again
+            std::future::poll_fn(|cx| {
+                match notified.iter_mut().any(|n| n.as_mut().poll(cx).is_ready()) {
+                    true => Poll::Ready(()),
+                    false => Poll::Pending,
+                }
+            })
+            .await;
+        }
+    }
+
+    /// Same as [`get_all_atomic`](Self::get_all_atomic).
+    pub async fn get_many(&self, keys: impl IntoIterator<Item = K>) -> Result<Vec<V>, GetError> {
+        self.get_all_atomic(keys).await
+    }
+
+    /// Naps until any of `keys` is present and returns it with its value,
+    /// the earliest in `keys` if several are. Never resolves without keys.
+    /// Fails with [`GetError::Denied`] if the authorizer keeps any of them
+    /// from reads, with [`GetError::Closed`] once all of them are closed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn wait_any(&self, keys: impl IntoIterator<Item = K>) -> Result<(K, V), GetError> {
+        tracing::trace!("Wait any");
+        let keys: Vec<K> = keys.into_iter().collect();
+        if !keys.iter().all(|k| self.allows(k, Operation::Get)) {
+            return Err(GetError::Denied);
+        }
+        loop {
+            let map = self.map.read().await;
+            let found = keys
+                .iter()
+                .find_map(|k| map.get_key_value(k).filter(|(_, s)| s.is_live()));
+            if let Some((k, slot)) = found {
+                self.touch(slot);
+                return Ok((k.clone(), slot.value.clone()));
+            }
+
+            // Registering while holding the read lock guarantees that no insert
+            // of any key can slip in unnoticed
+            let mut notifiers = self.notifiers.lock().await;
+            let notifies: Vec<Registration<K, S>> =
+                keys.iter().map(|k| notifiers.register(k)).collect();
+            drop(notifiers);
+            let mut notified: Vec<_> = notifies.iter().map(|n| Box::pin(n.notified())).collect();
+            drop(map);
+            // Checked once registered, so a concurrent close can't go unnoticed
+            if !keys.is_empty() && keys.iter().all(|k| self.is_closed_for(k)) {
+                return Err(GetError::Closed);
+            }
+
+            tracing::trace!("Waiting...");
+            let _napping = self.napping.enter();
+            std::future::poll_fn(|cx| {
+                match notified.iter_mut().any(|n| n.as_mut().poll(cx).is_ready()) {
+                    true => Poll::Ready(()),
+                    false => Poll::Pending,
+                }
+            })
+            .await;
+        }
+    }
+
+    /// Returns the entries of `keys` currently present, read in a single pass
+    /// under the read lock. Never naps, missing and denied keys are left out.
+    pub async fn try_get_many(&self, keys: impl IntoIterator<Item = K>) -> HashMap<K, V> {
+        let map = self.map.read().await;
+        keys.into_iter()
+            .filter_map(|k| {
+                let slot = map.get(&k).filter(|s| self.visible(&k, s))?;
+                self.touch(slot);
+                Some((k, slot.value.clone()))
+            })
+            .collect()
+    }
+
+    /// Like [`get`](Self::get), but also returns the version of the entry, to
+    /// be handed back to [`insert_if_version`](Self::insert_if_version).
+    pub async fn get_versioned(&self, k: K) -> Result<(V, Version), GetError> {
+        self.changed_since(k, Version(0)).await
+    }
+
+    /// Returns the entry right away if its version is past `version`, naps
+    /// until it is otherwise. Checking and napping in one call leaves no gap
+    /// for a write to slip through unnoticed. Fails with [`GetError::Denied`]
+    /// for a key the authorizer keeps from reads, with [`GetError::Closed`]
+    /// instead of napping once the key is closed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn changed_since(&self, k: K, version: Version) -> Result<(V, Version), GetError> {
+        tracing::trace!("Changed since");
+        if !self.allows(&k, Operation::Get) {
+            return Err(GetError::Denied);
+        }
+        loop {
+            let map = self.map.read().await;
+            if let Some(slot) = map.get(&k).filter(|s| s.is_live() && s.version > version) {
+                self.touch(slot);
+                return Ok((slot.value.clone(), slot.version));
+            }
+            let notify = self.notifiers.lock().await.register(&k);
+            let notified = notify.notified();
+            drop(map);
+            // Checked once registered, so a concurrent close can't go unnoticed
+            if self.is_closed_for(&k) {
+                return Err(GetError::Closed);
+            }
+
+            self.nap(&k, notified).await;
+        }
+    }
+
+    /// Naps until `k` holds a value satisfying `predicate`, e.g. a job whose
+    /// status reached `Done`, checking it again on every write to `k`. Fails
+    /// with [`GetError::Closed`] instead of napping once the key is closed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, predicate))]
+    pub async fn wait_for(&self, k: K, predicate: impl Fn(&V) -> bool) -> Result<V, GetError> {
+        tracing::trace!("Wait for");
+        if !self.allows(&k, Operation::Get) {
+            return Err(GetError::Denied);
+        }
+        loop {
+            let map = self.map.read().await;
+            if let Some(slot) = map.get(&k).filter(|s| s.is_live() && predicate(&s.value)) {
+                self.touch(slot);
+                return Ok(slot.value.clone());
+            }
+            let notify = self.notifiers.lock().await.register(&k);
+            let notified = notify.notified();
+            drop(map);
+            // Checked once registered, so a concurrent close can't go unnoticed
+            if self.is_closed_for(&k) {
+                return Err(GetError::Closed);
+            }
+
+            self.nap(&k, notified).await;
+        }
+    }
+
+    /// Identifies the map across its handles.
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.counters) as usize
+    }
+
+    async fn nap(&self, k: &K, notified: impl Future<Output = ()>) {
+        tracing::trace!("Waiting...");
+        crate::reentrant::check_nap(self.id(), k);
+        let _napping = self.napping.enter();
+        let started = Instant::now();
+        let mut notified = std::pin::pin!(notified);
+        match &self.slow_wait {
+            Some((threshold, callback)) => {
+                if tokio::time::timeout(*threshold, &mut notified)
+                    .await
+                    .is_err()
+                {
+                    tracing::debug!("Slow wait");
+                    // The timer may fire late on a busy runtime
+                    let elapsed = started.elapsed();
+                    self.hooks.call("slow_wait", || callback(k, elapsed));
+                    notified.await;
+                }
+            }
+            None => notified.await,
+        }
+        self.counters.napped(started.elapsed());
+    }
+
+    /// Naps until the next write of `k`, ignoring the current value, and
+    /// returns the written value.
+    pub async fn next_update(&self, k: K) -> Result<V, GetError> {
+        let since = self
+            .map
+            .read()
+            .await
+            .get(&k)
+            .map_or(Version(0), |s| s.version);
+        Ok(self.changed_since(k, since).await?.0)
+    }
+
+    async fn peek_versioned(&self, k: &K) -> Option<(V, Version)> {
+        let map = self.map.read().await;
+        map.get(k)
+            .filter(|s| s.is_live())
+            .map(|s| (s.value.clone(), s.version))
+    }
+
+    pub async fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut map = self.map.write().await;
+        let k = map
+            .get_key_value(k)
+            .map(|(k, _)| k.clone())
+            .filter(|k| self.allows(k, Operation::Remove))?;
+        if !self.delete_through(WriteOrder::BeforeVisible, [&k]).await {
+            return None;
+        }
+        let (k, slot) = self.take_entry(&mut map, &k)?;
+        if !slot.is_live() {
+            drop(map);
+            self.delete_through(WriteOrder::AfterVisible, [&k]).await;
+            self.retire(k, slot.value, RemovalCause::Expired);
+            return None;
+        }
+        self.removed(&k, &slot.value);
+        drop(map);
+
+        self.delete_through(WriteOrder::AfterVisible, [&k]).await;
+        self.retire(k, slot.value.clone(), RemovalCause::Removed);
+        Some(slot.value)
+    }
+
+    /// Like [`get`](Self::get), for synchronous callers: blocks the thread
+    /// until `k` is available. Meant for threads outside the runtime, so
+    /// configurations that need one, like
+    /// [`with_slow_wait`](Self::with_slow_wait) or
+    /// [`with_blocking_clone`](Self::with_blocking_clone), panic here.
+    ///
+    /// # Panics
+    ///
+    /// When called within a tokio runtime, like tokio's own blocking calls.
+    pub fn blocking_get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        crate::blocking::block_on(self.get(k))
+    }
+
+    /// Like [`insert`](Self::insert), for synchronous callers.
+    ///
+    /// # Panics
+    ///
+    /// When called within a tokio runtime.
+    pub fn blocking_insert(&self, k: K, v: V) {
+        crate::blocking::block_on(self.insert(k, v))
+    }
+
+    /// Like [`remove`](Self::remove), for synchronous callers.
+    ///
+    /// # Panics
+    ///
+    /// When called within a tokio runtime.
+    pub fn blocking_remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        crate::blocking::block_on(self.remove(k))
+    }
+
+    /// Removes the entry only if its current value satisfies `predicate`, as
+    /// a single atomic step. Returns whether the entry was removed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, predicate))]
+    pub async fn remove_if(&self, k: K, predicate: impl FnOnce(&V) -> bool) -> bool {
+        tracing::trace!("Remove if");
+        if !self.allows(&k, Operation::Remove) {
+            return false;
+        }
+        let mut map = self.map.write().await;
+        if !map
+            .get(&k)
+            .is_some_and(|slot| slot.is_live() && predicate(&slot.value))
+        {
+            return false;
+        }
+        if !self.delete_through(WriteOrder::BeforeVisible, [&k]).await {
+            return false;
+        }
+        let Some((k, slot)) = self.take_entry(&mut map, &k) else {
+            return false;
+        };
+        self.removed(&k, &slot.value);
+        drop(map);
+
+        self.delete_through(WriteOrder::AfterVisible, [&k]).await;
+        self.retire(k, slot.value, RemovalCause::Removed);
+        true
+    }
+
+    /// Naps until `k` is available, then removes it in the same step. Each
+    /// value goes to exactly one of the concurrent takers of its key, the
+    /// others keep napping, e.g. for one-shot replies correlated by request
+    /// id. `None` once `k` is closed or the taker evicted, or right away if
+    /// the authorizer denies removing it.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn take(&self, k: K) -> Option<V> {
+        if !self.allows(&k, Operation::Remove) {
+            return None;
+        }
+        loop {
+            if let Some(v) = self.remove(&k).await {
+                return Some(v);
+            }
+            if self.is_closed_for(&k) {
+                return None;
+            }
+
+            let notify = self.notifiers.lock().await.register(&k);
+            let napping = notify.enter();
+            let notified = notify.notified();
+            // Checked once registered, so a concurrent close or insert can't go unnoticed
+            if self.is_closed_for(&k) {
+                return None;
+            }
+            if let Some(v) = self.remove(&k).await {
+                return Some(v);
+            }
+            self.nap(&k, notified).await;
+            if notify.is_evicted() {
+                return None;
+            }
+            if let Some(v) = self.remove(&k).await {
+                napping.delivered();
+                return Some(v);
+            }
+            tracing::debug!("Taken by another task, napping again");
+        }
+    }
+
+    /// Removes all `keys` under a single write lock and returns the live
+    /// entries that were present.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn remove_many(&self, keys: impl IntoIterator<Item = K>) -> Vec<(K, V)> {
+        tracing::trace!("Remove many");
+        let mut map = self.map.write().await;
+        let keys: Vec<K> = keys
+            .into_iter()
+            .filter(|k| map.contains_key(k) && self.allows(k, Operation::Remove))
+            .collect();
+        if !self.delete_through(WriteOrder::BeforeVisible, &keys).await {
+            return Vec::new();
+        }
+        let (removed, expired): (Vec<_>, Vec<_>) = keys
+            .into_iter()
+            .filter_map(|k| self.take_entry(&mut map, &k))
+            .partition(|(_, slot)| slot.is_live());
+        let removed: Vec<(K, V)> = removed
+            .into_iter()
+            .map(|(k, slot)| (k, slot.value))
+            .inspect(|(k, v)| self.removed(k, v))
+            .collect();
+        drop(map);
+
+        let keys = removed.iter().map(|(k, _)| k);
+        self.delete_through(
+            WriteOrder::AfterVisible,
+            keys.chain(expired.iter().map(|(k, _)| k)),
+        )
+        .await;
+
+        match self.retires() {
+            true => {
+                for (k, v) in &removed {
+                    self.retire(k.clone(), v.clone(), RemovalCause::Removed);
+                }
+            }
+            // Nothing to retire, the room made still has to be told
+            false => self.room.made(),
+        }
+        for (k, slot) in expired {
+            self.retire(k, slot.value, RemovalCause::Expired);
+        }
+        removed
+    }
+
+    /// Removes every entry the authorizer lets go.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn clear(&self) {
+        tracing::trace!("Clear");
+        let mut map = self.map.write().await;
+        let allowed: Vec<bool> = map
+            .keys()
+            .map(|k| self.allows(k, Operation::Remove))
+            .collect();
+        let keys = map.keys().zip(&allowed).filter(|(_, a)| **a);
+        if !self
+            .delete_through(WriteOrder::BeforeVisible, keys.map(|(k, _)| k))
+            .await
+        {
+            return;
+        }
+        let drained = self.take_allowed(&mut map, allowed);
+        for (k, slot) in drained.iter().filter(|(_, s)| s.is_live()) {
+            self.removed(k, &slot.value);
+        }
+        drop(map);
+
+        self.delete_through(WriteOrder::AfterVisible, drained.iter().map(|(k, _)| k))
+            .await;
+        for (k, slot) in drained {
+            let cause = slot.leaving(RemovalCause::Removed);
+            self.retire(k, slot.value, cause);
+        }
+    }
+
+    /// Removes every entry like [`clear`](Self::clear), returning the ones
+    /// still live in the given order.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn drain(&self, order: EntryOrder) -> Vec<(K, V)> {
+        tracing::trace!("Drain");
+        let mut map = self.map.write().await;
+        let allowed: Vec<bool> = map
+            .keys()
+            .map(|k| self.allows(k, Operation::Remove))
+            .collect();
+        let keys = map.keys().zip(&allowed).filter(|(_, a)| **a);
+        if !self
+            .delete_through(WriteOrder::BeforeVisible, keys.map(|(k, _)| k))
+            .await
+        {
+            return Vec::new();
+        }
+        let mut drained = self.take_allowed(&mut map, allowed);
+        if order == EntryOrder::Written {
+            drained.sort_by_key(|(_, s)| s.version);
+        }
+        for (k, slot) in &drained {
+            self.removed(k, &slot.value);
+        }
+        drop(map);
+        // Live entries skip retire without a finalizer or listener
+        self.room.made();
+        self.delete_through(WriteOrder::AfterVisible, drained.iter().map(|(k, _)| k))
+            .await;
+
+        let mut live = Vec::with_capacity(drained.len());
+        for (k, slot) in drained {
+            match slot.is_live() {
+                true if self.retires() => {
+                    live.push((k.clone(), slot.value.clone()));
+                    self.retire(k, slot.value, RemovalCause::Removed);
+                }
+                true => live.push((k, slot.value)),
+                false => self.retire(k, slot.value, RemovalCause::Expired),
+            }
+        }
+        live
+    }
+
+    /// Takes the entries whose flag in `allowed`, in index order, is set,
+    /// keeping the order of the others.
+    fn take_allowed(
+        &self,
+        map: &mut IndexMap<K, Slot<V>, S>,
+        allowed: Vec<bool>,
+    ) -> Vec<(K, Slot<V>)> {
+        if allowed.iter().all(|a| *a) {
+            self.expiry.clear();
+            self.evictions.clear();
+            return map.drain(..).collect();
+        }
+        let (taken, kept): (Vec<_>, Vec<_>) = map.drain(..).zip(allowed).partition(|(_, a)| *a);
+        map.extend(kept.into_iter().map(|(entry, _)| entry));
+        taken
+            .into_iter()
+            .map(|((k, slot), _)| {
+                self.evictions.unlink(slot.lane, &slot.usage, slot.pinned);
+                if let Some(at) = slot.expires_at {
+                    self.expiry.unschedule(&k, at);
+                }
+                (k, slot)
+            })
+            .collect()
+    }
+
+    /// Like [`drain`](Self::drain), sorted by key.
+    pub async fn drain_sorted(&self) -> Vec<(K, V)>
+    where
+        K: Ord,
+    {
+        let mut drained = self.drain(EntryOrder::Any).await;
+        drained.sort_by(|(a, _), (b, _)| a.cmp(b));
+        drained
+    }
+
+    /// Whether enumerations and bulk reads hand out the entry.
+    fn visible(&self, k: &K, slot: &Slot<V>) -> bool {
+        slot.is_live() && self.allows(k, Operation::Get)
+    }
+
+    fn buried(&self, k: &K) -> bool {
+        self.tombstones.as_ref().is_some_and(|t| t.is_buried(k))
+    }
+
+    fn allows(&self, k: &K, operation: Operation) -> bool {
+        match &self.authorizer {
+            Some(authorize) => self
+                .hooks
+                .call("authorizer", || authorize(k, operation))
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+
+    fn removed(&self, k: &K, v: &V) {
+        self.counters.removal();
+        self.producers.cancel(k);
+        if let Some(tombstones) = &self.tombstones {
+            tombstones.bury(k);
+        }
+        if let Some(on_remove) = &self.on_remove {
+            self.hooks.call("on_remove", || on_remove(k, v));
+        }
+    }
+
+    /// Total weight of the entries, see [`with_weigher`](Self::with_weigher).
+    pub async fn weight(&self) -> usize {
+        let map = self.map.read().await;
+        map.iter().map(|(k, s)| self.weigh(k, &s.value)).sum()
+    }
+
+    /// Drops the expired entries, handing them to the finalizer if any.
+    /// Returns how many there were. Only the entries due are visited, not the
+    /// whole map.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn purge_expired(&self) -> usize {
+        // Entries within their grace window stay until it is over
+        let Some(horizon) = Instant::now().checked_sub(self.grace) else {
+            return 0;
+        };
+        let mut map = self.map.write().await;
+        let expired: Vec<(K, V)> = self
+            .expiry
+            .due(horizon)
+            .into_iter()
+            .filter_map(|(k, at)| {
+                // Only purge the deadline the slot still has
+                map.get(&k).filter(|s| s.expires_at == Some(at))?;
+                self.take_entry(&mut map, &k)
+            })
+            .map(|(k, s)| (k, s.value))
+            .collect();
+        drop(map);
+
+        let purged = expired.len();
+        self.swept("purge_expired", purged);
+        for (k, v) in expired {
+            self.retire(k, v, RemovalCause::Expired);
+        }
+        purged
+    }
+
+    /// The key due to expire first and when, e.g. to align an external timer
+    /// with the map. Entries already expired but not purged yet come first,
+    /// with an instant in the past.
+    pub async fn next_expiration(&self) -> Option<(K, Instant)> {
+        let map = self.map.read().await;
+        self.expiry
+            .earliest(|k, at| map.get(k).is_some_and(|s| s.expires_at == Some(at)))
+    }
+
+    /// Purges expired entries every `period` on a background task, which ends
+    /// at the first tick after every other handle to the map is dropped.
+    pub fn reap_expired_every(&self, period: Duration) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let map = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                // Every handle shares the counters, the task's own is the last
+                if Arc::strong_count(&map.counters) == 1 {
+                    break;
+                }
+                let reaped = map.purge_expired().await;
+                if reaped > 0 {
+                    tracing::debug!("Reaped {reaped} expired entries");
+                }
+            }
+        })
+    }
+
+    /// Evicts `fraction` (between 0 and 1) of the entries, in the same order
+    /// as capacity evictions. Returns how many entries were evicted.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn shed(&self, fraction: f64) -> usize {
+        tracing::trace!("Shed");
+        let mut map = self.map.write().await;
+        let count = (map.len() as f64 * fraction.clamp(0.0, 1.0)).ceil() as usize;
+        let evicted: Vec<_> = (0..count).map_while(|_| self.evict_one(&mut map)).collect();
+        drop(map);
+
+        let shed = evicted.len();
+        self.swept("shed", shed);
+        for (k, slot) in evicted {
+            let cause = slot.leaving(RemovalCause::Evicted);
+            self.retire(k, slot.value, cause);
+        }
+        shed
+    }
+
+    /// Evicts entries, in the same order as capacity evictions, until the
+    /// total weight is at most `target`. Returns how many entries were
+    /// evicted.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn shrink_to_weight(&self, target: usize) -> usize {
+        tracing::trace!("Shrink to weight");
+        let mut map = self.map.write().await;
+        let mut total: usize = map.iter().map(|(k, s)| self.weigh(k, &s.value)).sum();
+        let mut evicted = Vec::new();
+        while total > target {
+            let Some((k, slot)) = self.evict_one(&mut map) else {
+                break;
+            };
+            total -= self.weigh(&k, &slot.value);
+            evicted.push((k, slot));
+        }
+        drop(map);
+
+        let shed = evicted.len();
+        self.swept("shrink_to_weight", shed);
+        for (k, slot) in evicted {
+            let cause = slot.leaving(RemovalCause::Evicted);
+            self.retire(k, slot.value, cause);
+        }
+        shed
+    }
+
+    /// Sheds entries on a background task each time `signal` changes to a
+    /// fraction above 0, which lets a memory-pressure probe evict entries
+    /// proactively. The task ends when the sender of `signal` is dropped.
+    pub fn shed_on(&self, mut signal: watch::Receiver<f64>) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let map = self.clone();
+        tokio::spawn(async move {
+            while signal.changed().await.is_ok() {
+                let fraction = *signal.borrow_and_update();
+                if fraction > 0.0 {
+                    let shed = map.shed(fraction).await;
+                    tracing::debug!("Shed {shed} entries under memory pressure");
+                }
+            }
+        })
+    }
+
+    /// Inserts every pair received on `rx` on a background task, until all
+    /// senders are dropped.
+    pub fn feed_from_mpsc(&self, mut rx: mpsc::Receiver<(K, V)>) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let map = self.clone();
+        tokio::spawn(async move {
+            while let Some((k, v)) = rx.recv().await {
+                map.insert(k, v).await;
+            }
+            tracing::debug!("Feeding channel closed");
+        })
+    }
+
+    /// Inserts every pair received on `rx` on a background task, until the
+    /// channel is closed. Lagging behind the channel skips the missed pairs
+    /// with a warning, the next ones are still applied.
+    pub fn feed_from_broadcast(&self, mut rx: broadcast::Receiver<(K, V)>) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let map = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok((k, v)) => map.insert(k, v).await,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Feeding channel lagged, missed {missed} entries");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            tracing::debug!("Feeding channel closed");
+        })
+    }
+
+    fn weigh(&self, k: &K, v: &V) -> usize {
+        match &self.weigher {
+            Some(weigher) => self.hooks.call("weigher", || weigher(k, v)).unwrap_or(1),
+            None => 1,
+        }
+    }
+
+    fn check_weight(&self, k: &K, v: &V) -> Result<(), EntryTooLarge> {
+        let Some(max) = self.max_entry_weight else {
+            return Ok(());
+        };
+        let weight = self.weigh(k, v);
+        match weight > max {
+            true => Err(EntryTooLarge { weight, max }),
+            false => Ok(()),
+        }
+    }
+
+    pub async fn len(&self) -> usize {
+        self.map.read().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.map.read().await.is_empty()
+    }
+
+    /// The keys present now, in insertion order until an entry leaves, which
+    /// moves the last one in its place. Like the other enumerations, copies
+    /// out under the read lock, so callers can take their time over the
+    /// result.
+    pub async fn keys(&self) -> Vec<K> {
+        let map = self.map.read().await;
+        map.iter()
+            .filter(|(k, s)| self.visible(k, s))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    pub async fn values(&self) -> Vec<V> {
+        let map = self.map.read().await;
+        map.iter()
+            .filter(|(k, s)| self.visible(k, s))
+            .map(|(_, s)| s.value.clone())
+            .collect()
+    }
+
+    /// The entries present now.
+    pub async fn iter(&self) -> std::vec::IntoIter<(K, V)> {
+        let map = self.map.read().await;
+        let entries: Vec<(K, V)> = map
+            .iter()
+            .filter(|(k, s)| self.visible(k, s))
+            .map(|(k, s)| (k.clone(), s.value.clone()))
+            .collect();
+        entries.into_iter()
+    }
+
+    /// Like [`iter`](Self::iter), in the given order.
+    pub async fn iter_in(&self, order: EntryOrder) -> std::vec::IntoIter<(K, V)> {
+        let map = self.map.read().await;
+        let mut entries: Vec<(&K, &Slot<V>)> =
+            map.iter().filter(|(k, s)| self.visible(k, s)).collect();
+        if order == EntryOrder::Written {
+            entries.sort_by_key(|(_, s)| s.version);
+        }
+        let entries: Vec<(K, V)> = entries
+            .into_iter()
+            .map(|(k, s)| (k.clone(), s.value.clone()))
+            .collect();
+        entries.into_iter()
+    }
+
+    /// Like [`iter`](Self::iter), sorted by key.
+    pub async fn iter_sorted(&self) -> std::vec::IntoIter<(K, V)>
+    where
+        K: Ord,
+    {
+        let mut entries: Vec<(K, V)> = self.iter().await.collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries.into_iter()
+    }
+
+    /// A copy of the map, for debugging and bulk export.
+    pub async fn snapshot(&self) -> HashMap<K, V> {
+        self.iter().await.collect()
+    }
+
+    /// The live entries, oldest write first, to be persisted and handed back
+    /// to [`restore_snapshot`](Self::restore_snapshot), e.g. across restarts
+    /// with the `save_to_writer` of the `serde` feature.
+    pub async fn to_snapshot(&self) -> MapSnapshot<K, V> {
+        MapSnapshot {
+            entries: self.iter_in(EntryOrder::Written).await.collect(),
+        }
+    }
+
+    /// Inserts the entries of `snapshot` like
+    /// [`insert_many`](Self::insert_many), waking the tasks napping on them.
+    pub async fn restore_snapshot(&self, snapshot: MapSnapshot<K, V>) {
+        self.insert_many(snapshot.entries).await;
+    }
+
+    /// Resolves once no task is napping on or computing into the map, e.g.
+    /// before shutting down or between the phases of a test.
+    pub async fn quiesce(&self) {
+        self.napping.drained().await;
+        self.loading.drained().await;
+    }
+
+    /// Keeps the waiters of the keys for which `f` returns `true`. Tasks
+    /// napping on any other key are woken with [`GetError::Evicted`], stored
+    /// values are left alone. Returns how many keys had their waiters evicted.
+    pub async fn retain_waiters(&self, f: impl FnMut(&K) -> bool) -> usize {
+        self.notifiers.lock().await.retain(f)
+    }
+
+    /// Closes the map for waiters: the tasks napping in
+    /// [`get_checked`](Self::get_checked), the lookups built on it and the
+    /// waits like [`changed_since`](Self::changed_since),
+    /// [`wait_for`](Self::wait_for), [`get_all_atomic`](Self::get_all_atomic)
+    /// or [`wait_any`](Self::wait_any) are woken with [`GetError::Closed`],
+    /// and later lookups of missing keys fail with it right away, as do
+    /// inserts waiting for room in [`insert_timeout`](Self::insert_timeout).
+    /// Entries stay readable and writable.
+    pub async fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.room.wake_all();
+        let woken = self.notifiers.lock().await.retain(|_| false);
+        tracing::debug!("Closed, woke the waiters of {woken} keys");
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Like [`close`](Self::close), for the keys `f` returns `true` for only,
+    /// e.g. the keys of a decommissioned tenant. The rest of the map keeps
+    /// napping as usual.
+    pub async fn close_where(&self, f: impl Fn(&K) -> bool + Send + Sync + 'static) {
+        let f: KeyFilter<K> = Arc::new(f);
+        lock(&self.closed_keys).push(f.clone());
+        self.room.wake_all();
+        let woken = self.notifiers.lock().await.retain(|k| !f(k));
+        tracing::debug!("Closed some keys, woke the waiters of {woken}");
+    }
+
+    /// Closes the keys starting with `prefix`, see
+    /// [`close_where`](Self::close_where).
+    pub async fn close_prefix(&self, prefix: &str)
+    where
+        K: AsRef<str> + 'static,
+    {
+        let prefix = prefix.to_owned();
+        self.close_where(move |k| k.as_ref().starts_with(&prefix))
+            .await;
+    }
+
+    /// Whether lookups of `k` fail with [`GetError::Closed`] rather than nap,
+    /// through [`close`](Self::close) or [`close_where`](Self::close_where).
+    pub fn is_closed_for(&self, k: &K) -> bool {
+        self.is_closed() || lock(&self.closed_keys).iter().any(|f| f(k))
+    }
+
+    /// Size of the notifier table, see [`NotifierStats`].
+    pub async fn notifier_stats(&self) -> NotifierStats {
+        self.notifiers.stats().await
+    }
+
+    /// Most entries and waiters the map ever held at once, see [`Watermarks`].
+    pub fn watermarks(&self) -> Watermarks {
+        Watermarks {
+            entries: self.entries_peak.get(),
+            waiters: self.napping.peak(),
+        }
+    }
+
+    /// Starts a new observation window, from the current counts.
+    pub async fn reset_watermarks(&self) {
+        self.entries_peak.reset(self.len().await);
+        self.napping.reset_peak();
+    }
+
+    /// Lookup, write and nap counts since the map was built, see
+    /// [`NapMapStats`].
+    pub fn stats(&self) -> NapMapStats {
+        self.counters.stats(self.napping.count(), self.room.depth())
+    }
+
+    /// Returns the value without napping, along with how long ago it was
+    /// inserted.
+    pub(crate) async fn peek(&self, k: &K) -> Option<(V, Duration)> {
+        let map = self.map.read().await;
+        let slot = map.get(k).filter(|s| self.visible(k, s))?;
+        self.touch(slot);
+        Some((slot.value.clone(), slot.inserted_at.elapsed()))
+    }
+}
+
+impl<K, V, S> Debug for NapMap<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NapMap")
+            .field("map", &self.map)
+            .field("notifiers", &self.notifiers)
+            .finish()
+    }
+}
+
+/// A slot of a full [`NapMap`] held by [`reserve`](NapMap::reserve), given
+/// back when dropped unused.
+pub struct Permit<'a, K, V, S = RandomState>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    napmap: &'a NapMap<K, V, S>,
+}
+
+impl<K, V, S> Permit<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: BuildHasher + Clone,
+{
+    /// Inserts into the reserved slot.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn insert(self, k: K, v: V) -> Result<(), InsertError<V>> {
+        let napmap = self.napmap;
+        if !napmap.allows(&k, Operation::Insert) {
+            return Err(InsertError::Denied);
+        }
+        napmap.check_weight(&k, &v)?;
+        napmap
+            .persist_store(WriteOrder::BeforeVisible, &k, &v)
+            .await?;
+        let written = napmap.writes_after().then(|| v.clone());
+
+        let mut map = napmap.map.write().await;
+        // Handed over to the entry under the same lock, nobody can take it
+        std::mem::forget(self);
+        napmap.permits.fetch_sub(1, Ordering::AcqRel);
+        napmap.admit(&mut map, k.clone(), v, napmap.ttl, Lane::Normal);
+        drop(map);
+
+        napmap.wake(&k).await;
+        if let Some(v) = written {
+            napmap
+                .persist_store(WriteOrder::AfterVisible, &k, &v)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl<K, V, S> Drop for Permit<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn drop(&mut self) {
+        self.napmap.permits.fetch_sub(1, Ordering::AcqRel);
+        self.napmap.room.made();
+    }
+}
+
+impl<K, V, S> Debug for Permit<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Permit").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::napmap_with_policy;
+    use super::EvictionPolicy;
+    use super::Lane;
+    use super::NapMap;
+    use crate::backend::Backend;
+    use crate::backend::BoxError;
+    use crate::backend::BoxFuture;
+    use crate::backend::InMemoryBackend;
+    use crate::backend::WriteThrough;
+    use crate::error::EntryTooLarge;
+    use crate::error::GetError;
+    use crate::error::InsertError;
+    use crate::hooks::HookPanic;
+    use crate::hooks::Operation;
+    use crate::hooks::RemovalCause;
+    use crate::tombstones::AfterRemove;
+    use crate::version::EntryOrder;
+    use crate::version::SwapError;
+    use crate::version::Version;
+    use crate::version::VersionError;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::task::Context;
+    use std::task::Poll;
+    use std::task::Waker;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+    use tokio::sync::mpsc;
+    use tokio::sync::watch;
+    use tracing_subscriber::EnvFilter;
+
+    // Add this to a test to see the logs
+    fn _tracing_sub() {
+        let env_filter =
+            EnvFilter::from_default_env().add_directive("napmap=trace".parse().unwrap());
+        tracing_subscriber::fmt().with_env_filter(env_filter).init();
+    }
+
+    #[tokio::test]
+    async fn it_should_wait_until_data_is_inserted() {
+        let napmap = Arc::new(NapMap::new(10));
+
+        tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                map.insert("key", 7).await;
+            }
+        });
+
+        let res = napmap.get(&"key").await.unwrap();
+        assert_eq!(res, 7);
+    }
+
+    #[tokio::test]
+    async fn it_should_notify_all_waiters() {
+        let napmap = Arc::new(NapMap::new(10));
+
+        tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                map.insert("key", 7).await;
+            }
+        });
+
+        let first_handle = tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                let res = map.get(&"key").await.unwrap();
+                assert_eq!(res, 7);
+            }
+        });
+
+        let second_handle = tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                let res = map.get(&"key").await.unwrap();
+                assert_eq!(res, 7);
+            }
+        });
+
+        first_handle.await.unwrap();
+        second_handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_not_exceed_the_provided_buffer_size() {
+        let napmap = Arc::new(NapMap::new(3));
         napmap.insert(1, 1).await;
         napmap.insert(2, 2).await;
         napmap.insert(3, 3).await;
         napmap.insert(4, 4).await;
         assert_eq!(napmap.len().await, 3);
     }
+
+    #[tokio::test]
+    async fn it_should_publish_a_batch_at_once() {
+        let napmap = Arc::new(NapMap::new(10));
+
+        let waiter = tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                let first = map.get(&"first").await.unwrap();
+                assert_eq!(map.len().await, 2);
+                first
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        napmap
+            .insert_batch_atomic([("first", 1), ("second", 2)])
+            .await
+            .unwrap();
+        assert_eq!(waiter.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_read_many_keys_at_once() {
+        let napmap = Arc::new(NapMap::new(10));
+        napmap.insert("first", 1).await;
+
+        tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                map.insert("second", 2).await;
+            }
+        });
+
+        let res = napmap.get_all_atomic(["first", "second"]).await.unwrap();
+        assert_eq!(res, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn it_should_apply_concurrent_rcu_updates() {
+        let napmap = Arc::new(NapMap::new(10));
+
+        let handles: Vec<_> = (0..10)
+            .map(|_| {
+                let map = napmap.clone();
+                tokio::spawn(async move {
+                    map.rcu("counter", |v| v.copied().unwrap_or(0) + 1)
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(napmap.get(&"counter").await, Some(10));
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_stale_versions() {
+        let napmap = NapMap::new(10);
+        napmap.insert("key", 1).await;
+        let (_, version) = napmap.get_versioned("key").await.unwrap();
+
+        let next = napmap.insert_if_version("key", 2, Some(version)).await;
+        assert!(next.unwrap() > version);
+
+        let res = napmap.insert_if_version("key", 3, Some(version)).await;
+        assert!(matches!(res, Err(VersionError::Mismatch { .. })));
+        assert_eq!(napmap.get(&"key").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn it_should_finalize_evicted_values() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let napmap = NapMap::new(1).with_finalizer(move |k, v| {
+            let tx = tx.clone();
+            async move {
+                tx.send((k, v)).unwrap();
+            }
+        });
+
+        napmap.insert(1, 1).await;
+        napmap.insert(2, 2).await;
+        assert_eq!(rx.recv().await, Some((1, 1)));
+    }
+
+    #[tokio::test]
+    async fn it_should_report_removed_entries_inline() {
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let napmap = NapMap::new(10).with_on_remove({
+            let removed = removed.clone();
+            move |k: &&str, v: &i32| removed.lock().unwrap().push((*k, *v))
+        });
+
+        napmap.insert("first", 1).await;
+        napmap.insert("second", 2).await;
+        assert_eq!(napmap.remove(&"first").await, Some(1));
+        assert_eq!(*removed.lock().unwrap(), vec![("first", 1)]);
+
+        napmap.clear().await;
+        assert!(napmap.is_empty().await);
+        assert_eq!(removed.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_shed_entries() {
+        let napmap = Arc::new(NapMap::new(10).with_weigher(|_, v: &usize| *v));
+        for i in 1..=4 {
+            napmap.insert(i, i * 10).await;
+        }
+        assert_eq!(napmap.weight().await, 100);
+
+        assert_eq!(napmap.shed(0.5).await, 2);
+        assert_eq!(napmap.len().await, 2);
+
+        napmap.shrink_to_weight(0).await;
+        assert!(napmap.is_empty().await);
+
+        let (tx, rx) = watch::channel(0.0);
+        napmap.insert(1, 1).await;
+        let task = napmap.shed_on(rx);
+        tx.send(1.0).unwrap();
+        drop(tx);
+        task.await.unwrap();
+        assert!(napmap.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn it_should_never_evict_pinned_entries() {
+        let napmap = NapMap::new(2);
+        napmap.insert("pinned", 0).await;
+        assert!(napmap.pin("pinned").await);
+
+        for i in 1..10 {
+            napmap.insert("other", i).await;
+            napmap.insert("another", i).await;
+        }
+        assert_eq!(napmap.len().await, 2);
+        assert_eq!(napmap.get(&"pinned").await, Some(0));
+
+        napmap.shed(1.0).await;
+        assert_eq!(napmap.get(&"pinned").await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn it_should_exceed_capacity_when_everything_is_pinned() {
+        let napmap = NapMap::new(1);
+        napmap.insert(1, 1).await;
+        napmap.pin(1).await;
+        napmap.insert(2, 2).await;
+        assert_eq!(napmap.len().await, 2);
+
+        napmap.unpin(1).await;
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_remove_many_keys_at_once() {
+        let napmap = NapMap::new(10);
+        napmap
+            .insert_batch_atomic([("first", 1), ("second", 2), ("third", 3)])
+            .await
+            .unwrap();
+
+        let removed = napmap.remove_many(["first", "third", "missing"]).await;
+        assert_eq!(removed, vec![("first", 1), ("third", 3)]);
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_remove_only_matching_values() {
+        let napmap = NapMap::new(10);
+        napmap.insert("key", 1).await;
+
+        assert!(!napmap.remove_if("key", |v| *v == 2).await);
+        assert!(napmap.remove_if("key", |v| *v == 1).await);
+        assert!(!napmap.remove_if("key", |_| true).await);
+        assert!(napmap.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn it_should_feed_from_channels() {
+        let napmap = Arc::new(NapMap::new(10));
+
+        let (tx, rx) = mpsc::channel(4);
+        let task = napmap.feed_from_mpsc(rx);
+        tx.send(("first", 1)).await.unwrap();
+        drop(tx);
+        task.await.unwrap();
+        assert_eq!(napmap.get(&"first").await, Some(1));
+
+        let (tx, rx) = broadcast::channel(1);
+        tx.send(("lost", 0)).unwrap();
+        tx.send(("second", 2)).unwrap();
+        let task = napmap.feed_from_broadcast(rx);
+        drop(tx);
+        task.await.unwrap();
+        assert_eq!(napmap.get(&"second").await, Some(2));
+        assert_eq!(napmap.len().await, 2);
+    }
+
+    #[test]
+    fn it_should_reject_a_zero_buffer() {
+        assert!(NapMap::<&str, i32>::try_new(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_quiesce_once_nobody_naps() {
+        let napmap = Arc::new(NapMap::new(10));
+        napmap.quiesce().await;
+
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let quiesce = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.quiesce().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!quiesce.is_finished());
+
+        napmap.insert("key", 1).await;
+        quiesce.await.unwrap();
+        assert_eq!(get.await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_report_notifier_stats() {
+        let napmap = Arc::new(NapMap::new(10));
+        let gets: Vec<_> = ["first", "second"]
+            .into_iter()
+            .map(|k| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get(&k).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let stats = napmap.notifier_stats().await;
+        assert_eq!((stats.keys, stats.peak), (2, 2));
+
+        napmap.insert("first", 1).await;
+        napmap.insert("second", 1).await;
+        for get in gets {
+            get.await.unwrap();
+        }
+        let stats = napmap.notifier_stats().await;
+        assert_eq!((stats.keys, stats.peak), (0, 2));
+    }
+
+    #[tokio::test]
+    async fn it_should_refuse_keys_past_the_pending_limit() {
+        let napmap = Arc::new(NapMap::new(10).with_max_pending_keys(1));
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"first").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            napmap.get_checked("second").await,
+            Err(GetError::TooManyPendingKeys { limit: 1 })
+        );
+        assert_eq!(napmap.get(&"second").await, None);
+
+        napmap.insert("first", 1).await;
+        assert_eq!(get.await.unwrap(), Some(1));
+        napmap.insert("second", 2).await;
+        assert_eq!(napmap.get_checked("second").await, Ok(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_not_nap_on_tombstoned_keys() {
+        let napmap = NapMap::new(10).with_tombstones(Duration::from_secs(10));
+        napmap.insert("key", 1).await;
+        napmap.remove(&"key").await;
+        assert_eq!(napmap.get_checked("key").await, Err(GetError::Removed));
+        assert_eq!(napmap.get(&"key").await, None);
+
+        napmap.insert("key", 2).await;
+        assert_eq!(napmap.get_checked("key").await, Ok(2));
+
+        napmap.remove(&"key").await;
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let napped = tokio::time::timeout(Duration::from_secs(1), napmap.get(&"key")).await;
+        assert!(napped.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_pick_what_get_does_after_a_removal() {
+        let napmap = NapMap::new(10).with_after_remove(AfterRemove::Removed);
+        napmap.insert("key", 1).await;
+        napmap.remove(&"key").await;
+        assert_eq!(napmap.get_checked("key").await, Err(GetError::Removed));
+
+        let napmap = NapMap::new(10).with_after_remove(AfterRemove::Nap);
+        napmap.insert("key", 1).await;
+        napmap.remove(&"key").await;
+        let napped = tokio::time::timeout(Duration::from_millis(50), napmap.get(&"key")).await;
+        assert!(napped.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_wait_for_the_next_update() {
+        let napmap = Arc::new(NapMap::new(10));
+        napmap.insert("key", 1).await;
+
+        let update = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.next_update("key").await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!update.is_finished());
+
+        napmap.insert("key", 1).await;
+        assert_eq!(update.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_return_changes_past_a_version() {
+        let napmap = Arc::new(NapMap::new(10));
+        napmap.insert("key", 1).await;
+        let (_, seen) = napmap.get_versioned("key").await.unwrap();
+        assert_eq!(
+            napmap.changed_since("key", Version(0)).await.unwrap(),
+            (1, seen)
+        );
+
+        let changed = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.changed_since("key", seen).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!changed.is_finished());
+
+        napmap.insert("key", 2).await;
+        let (v, version) = changed.await.unwrap();
+        assert_eq!(v, 2);
+        assert!(version > seen);
+    }
+
+    #[tokio::test]
+    async fn it_should_clone_heavy_values_on_the_blocking_pool() {
+        let napmap = NapMap::new(10)
+            .with_weigher(|_, v: &Vec<u8>| v.len())
+            .with_blocking_clone(1024);
+        napmap.insert("light", vec![0; 8]).await;
+        napmap.insert("heavy", vec![0; 4096]).await;
+
+        assert_eq!(napmap.get(&"light").await.unwrap().len(), 8);
+        assert_eq!(napmap.get(&"heavy").await.unwrap().len(), 4096);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_oversized_entries() {
+        let napmap = NapMap::new(10)
+            .with_weigher(|_, v: &Vec<u8>| v.len())
+            .with_max_entry_weight(16);
+        napmap.insert_checked("small", vec![0; 16]).await.unwrap();
+
+        let err = napmap
+            .insert_checked("rogue", vec![0; 17])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InsertError::TooLarge(EntryTooLarge {
+                weight: 17,
+                max: 16
+            })
+        ));
+        assert!(napmap
+            .insert_batch_atomic([("other", vec![0; 1]), ("rogue", vec![0; 17])])
+            .await
+            .is_err());
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_report_slow_waits_before_they_end() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let napmap = Arc::new(NapMap::new(10).with_slow_wait(
+            Duration::from_secs(5),
+            move |k, elapsed| {
+                tx.send((*k, elapsed)).unwrap();
+            },
+        ));
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+
+        assert_eq!(rx.recv().await, Some(("key", Duration::from_secs(5))));
+        assert!(!get.is_finished());
+        napmap.insert("key", 1).await;
+        assert_eq!(get.await.unwrap(), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_share_a_nap_per_request() {
+        let slow = Arc::new(AtomicUsize::new(0));
+        let napmap = Arc::new(NapMap::new(10).with_slow_wait(Duration::from_secs(1), {
+            let slow = slow.clone();
+            move |_, _| {
+                slow.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+        let gets: Vec<_> = (0..3)
+            .map(|_| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get_for_request("key", 7).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(slow.load(Ordering::SeqCst), 1);
+
+        napmap.insert("key", 1).await;
+        for get in gets {
+            assert_eq!(get.await.unwrap(), Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_ack_once_waiters_observed_the_value() {
+        let napmap = Arc::new(NapMap::new(10));
+        let gets: Vec<_> = (0..3)
+            .map(|_| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get(&"key").await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let delivered = napmap.insert_acked("key", 1, Duration::from_secs(1)).await;
+        assert_eq!(delivered, 3);
+        for get in gets {
+            assert_eq!(get.await.unwrap(), Some(1));
+        }
+        assert_eq!(
+            napmap.insert_acked("key", 2, Duration::from_secs(1)).await,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_give_up_at_the_deadline() {
+        let napmap = Arc::new(NapMap::new(10));
+        let abandoned = napmap.get_timeout("key", Duration::from_millis(20)).await;
+        assert_eq!(abandoned, Err(GetError::TimedOut));
+        assert_eq!(napmap.notifier_stats().await.keys, 0);
+
+        tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                napmap.insert("key", 1).await;
+            }
+        });
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        assert_eq!(napmap.get_deadline("key", deadline).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_evict_waiters() {
+        let napmap = Arc::new(NapMap::new(10));
+        let gets: Vec<_> = ["tenant-a/1", "tenant-b/1"]
+            .into_iter()
+            .map(|k| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get_checked(k).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let evicted = napmap.retain_waiters(|k| !k.starts_with("tenant-a/")).await;
+        assert_eq!(evicted, 1);
+        napmap.insert("tenant-b/1", 1).await;
+
+        let [a, b] = <[_; 2]>::try_from(gets).unwrap();
+        assert!(matches!(a.await.unwrap(), Err(GetError::Evicted)));
+        assert_eq!(b.await.unwrap(), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_probe_without_napping() {
+        let napmap = NapMap::new(10);
+        assert_eq!(napmap.try_get(&"key").await, None);
+        assert!(!napmap.contains_key(&"key").await);
+        assert_eq!(napmap.notifier_stats().await.keys, 0);
+
+        napmap.insert("key", 1).await;
+        assert_eq!(napmap.try_get(&"key").await, Some(1));
+        assert!(napmap.contains_key(&"key").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_expire_entries() {
+        let napmap = Arc::new(NapMap::new(10).with_ttl(Duration::from_secs(60)));
+        napmap.insert("default", 1).await;
+        napmap
+            .insert_with_ttl("short", 2, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(napmap.try_get(&"short").await, None);
+        assert_eq!(napmap.try_get(&"default").await, Some(1));
+
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"short").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!get.is_finished());
+
+        assert_eq!(napmap.purge_expired().await, 1);
+        napmap.insert("short", 3).await;
+        assert_eq!(get.await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn it_should_evict_by_policy() {
+        let lru = napmap_with_policy(2, EvictionPolicy::Lru);
+        lru.insert("a", 1).await;
+        lru.insert("b", 2).await;
+        assert_eq!(lru.get(&"a").await, Some(1));
+        lru.insert("c", 3).await;
+        assert!(lru.contains_key(&"a").await);
+        assert!(!lru.contains_key(&"b").await);
+
+        let lfu = napmap_with_policy(2, EvictionPolicy::Lfu);
+        lfu.insert("a", 1).await;
+        lfu.insert("b", 2).await;
+        lfu.get(&"b").await;
+        lfu.get(&"b").await;
+        lfu.get(&"a").await;
+        lfu.insert("c", 3).await;
+        assert!(!lfu.contains_key(&"a").await);
+
+        let fifo = napmap_with_policy(2, EvictionPolicy::Fifo);
+        fifo.insert("a", 1).await;
+        fifo.insert("b", 2).await;
+        fifo.insert("c", 3).await;
+        assert!(!fifo.contains_key(&"a").await);
+        assert!(fifo.contains_key(&"c").await);
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_the_eviction_order_across_removals_and_pins() {
+        let napmap = napmap_with_policy(3, EvictionPolicy::Fifo);
+        for (k, v) in [("a", 1), ("b", 2), ("c", 3)] {
+            napmap.insert(k, v).await;
+        }
+        napmap.pin("a").await;
+        napmap.remove(&"b").await;
+        napmap.insert("d", 4).await;
+        napmap.insert("e", 5).await;
+        assert_eq!(napmap.keys().await.len(), 3);
+        assert!(!napmap.contains_key(&"c").await);
+
+        napmap.unpin("a").await;
+        napmap.insert("f", 6).await;
+        assert!(!napmap.contains_key(&"a").await);
+
+        let napmap = napmap.with_eviction_policy(EvictionPolicy::Lifo);
+        napmap.insert("g", 7).await;
+        let mut keys = napmap.keys().await;
+        keys.sort();
+        assert_eq!(keys, ["d", "e", "g"]);
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_record_lifecycle_events_under_the_map_span() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::new("napmap=debug"))
+            .with_writer({
+                let captured = captured.clone();
+                move || captured.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let napmap = NapMap::new(1).with_span_name("orders");
+        napmap.insert("a", 1).await;
+        napmap.insert("b", 2).await;
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains(r#"napmap{name="orders"}: napmap::bounded: insert key="a""#));
+        assert!(logs.contains(r#"napmap{name="orders"}: napmap::bounded: evict key="a""#));
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_high_priority_entries_under_bulk_traffic() {
+        let napmap = NapMap::new(4).with_priority_reserve(0.5);
+        napmap.insert_priority(100, 0, Lane::High).await;
+        napmap.insert_priority(101, 0, Lane::High).await;
+        for i in 0..10 {
+            napmap.insert(i, i).await;
+        }
+
+        assert_eq!(napmap.lane_occupancy(Lane::High).await, 2);
+        assert_eq!(napmap.lane_occupancy(Lane::Normal).await, 2);
+        assert!(napmap.contains_key(&100).await);
+        assert!(napmap.contains_key(&101).await);
+    }
+
+    #[tokio::test]
+    async fn it_should_look_up_borrowed_keys() {
+        let napmap: Arc<NapMap<String, i32>> = Arc::new(NapMap::new(10));
+        napmap.insert("key".to_string(), 1).await;
+        assert!(napmap.contains_key("key").await);
+        assert_eq!(napmap.get("key").await, Some(1));
+        assert_eq!(napmap.remove("key").await, Some(1));
+
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get("late").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        napmap.insert("late".to_string(), 2).await;
+        assert_eq!(get.await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn it_should_compute_a_missing_value_once() {
+        let napmap = Arc::new(NapMap::new(4));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut handles = Vec::new();
+        for _ in 0..5 {
+            let napmap = napmap.clone();
+            let calls = calls.clone();
+            handles.push(tokio::spawn(async move {
+                napmap
+                    .get_or_compute("key", || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        1
+                    })
+                    .await
+            }));
+        }
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), 1);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(napmap.get_or_compute("key", || async { 2 }).await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_yield_to_peers_in_a_loop_of_lookups() {
+        let napmap = NapMap::new(4);
+        napmap.insert("key", 1).await;
+        let peer = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let peer = peer.clone();
+            async move { peer.fetch_add(1, Ordering::SeqCst) }
+        });
+
+        for _ in 0..1_000 {
+            napmap.get_uncooperative(&"key").await;
+        }
+        assert_eq!(peer.load(Ordering::SeqCst), 0);
+        for _ in 0..1_000 {
+            napmap.try_get(&"key").await;
+        }
+        assert_eq!(peer.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_serve_hits_without_awaiting() {
+        let napmap = NapMap::new(4);
+        napmap.insert("key", 1).await;
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let hit = std::pin::pin!(napmap.get(&"key"));
+        assert_eq!(hit.poll(&mut cx), Poll::Ready(Some(1)));
+        let _writer = napmap.map.write().await;
+        let contended = std::pin::pin!(napmap.get(&"key"));
+        assert_eq!(contended.poll(&mut cx), Poll::Pending);
+    }
+
+    #[tokio::test]
+    async fn it_should_return_the_present_subset_of_many_keys() {
+        let napmap = NapMap::new(4);
+        napmap.insert("a", 1).await;
+        napmap.insert("b", 2).await;
+
+        let got = napmap.try_get_many(["a", "b", "c"]).await;
+        assert_eq!(got.len(), 2);
+        assert_eq!(got["a"], 1);
+        assert_eq!(got["b"], 2);
+        assert!(napmap.try_get_many(["c"]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_should_enumerate_entries_in_insertion_order() {
+        let napmap = NapMap::new(4);
+        napmap.insert("a", 1).await;
+        napmap.insert("b", 2).await;
+        napmap.insert_with_ttl("gone", 0, Duration::ZERO).await;
+
+        assert_eq!(napmap.keys().await, ["a", "b"]);
+        assert_eq!(napmap.values().await, [1, 2]);
+        assert_eq!(
+            napmap.iter().await.collect::<Vec<_>>(),
+            [("a", 1), ("b", 2)]
+        );
+        assert_eq!(napmap.snapshot().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_drain_in_a_stable_order() {
+        let napmap = NapMap::new(4);
+        napmap.insert("b", 1).await;
+        napmap.insert("a", 2).await;
+        napmap.insert("b", 3).await;
+
+        let written: Vec<_> = napmap.iter_in(EntryOrder::Written).await.collect();
+        assert_eq!(written, [("a", 2), ("b", 3)]);
+        assert_eq!(napmap.drain(EntryOrder::Any).await, [("b", 3), ("a", 2)]);
+        assert!(napmap.is_empty().await);
+
+        napmap.insert("b", 1).await;
+        napmap.insert("a", 2).await;
+        assert_eq!(napmap.drain_sorted().await, [("a", 2), ("b", 1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_count_lookups_writes_and_naps() {
+        let napmap = Arc::new(NapMap::new(4));
+        napmap.insert("a", 1).await;
+        assert_eq!(napmap.get(&"a").await, Some(1));
+        assert_eq!(napmap.try_get(&"b").await, None);
+
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"b").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(napmap.stats().napping, 1);
+        napmap.insert("b", 2).await;
+        assert_eq!(waiter.await.unwrap(), Some(2));
+        napmap.remove(&"a").await;
+
+        let stats = napmap.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!((stats.inserts, stats.removals), (2, 1));
+        assert_eq!((stats.napping, stats.naps), (0, 1));
+        assert_eq!(stats.max_nap, Duration::from_millis(10));
+        assert_eq!(stats.nap_time, stats.max_nap);
+    }
+
+    #[tokio::test]
+    async fn it_should_share_entries_between_cloned_handles() {
+        let napmap = NapMap::new(4);
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        napmap.clone().insert("key", 1).await;
+        assert_eq!(waiter.await.unwrap(), Some(1));
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_disable_a_panicking_hook() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let napmap = NapMap::new(4)
+            .with_hook_panic(HookPanic::Disable)
+            .with_on_remove({
+                let calls = calls.clone();
+                move |_, _| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    panic!("listener failed");
+                }
+            });
+        napmap.insert("a", 1).await;
+        napmap.insert("b", 2).await;
+
+        assert_eq!(napmap.remove(&"a").await, Some(1));
+        assert_eq!(napmap.remove(&"b").await, Some(2));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_hand_entries_back_when_full() {
+        let napmap = NapMap::new(2);
+        napmap.insert("a", 1).await;
+        let permit = napmap.reserve().await;
+
+        assert!(napmap.try_insert("a", 2).await.is_ok());
+        assert!(matches!(
+            napmap.try_insert("b", 3).await,
+            Err(InsertError::Full(3))
+        ));
+        let timed_out = napmap.insert_timeout("b", 3, Duration::from_millis(10));
+        assert!(matches!(timed_out.await, Err(InsertError::Full(3))));
+
+        permit.insert("c", 4).await.unwrap();
+        let (inserted, _) = tokio::join!(
+            napmap.insert_timeout("b", 3, Duration::from_secs(1)),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                napmap.remove(&"a").await
+            }
+        );
+        assert!(inserted.is_ok());
+        assert_eq!(napmap.keys().await, ["c", "b"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_wait_for_room_to_reserve() {
+        let napmap = Arc::new(NapMap::new(1));
+        napmap.insert("a", 1).await;
+        let reserved = tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                let permit = napmap.reserve().await;
+                permit.insert("b", 2).await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!reserved.is_finished());
+
+        napmap.remove(&"a").await;
+        reserved.await.unwrap();
+        assert_eq!(napmap.try_get(&"b").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn it_should_serve_room_waiters_in_order() {
+        let napmap = Arc::new(NapMap::new(1));
+        napmap.insert("a", 1).await;
+        let mut waiters = Vec::new();
+        for k in ["b", "c", "d"] {
+            waiters.push(tokio::spawn({
+                let napmap = napmap.clone();
+                async move { napmap.insert_timeout(k, 2, Duration::from_secs(10)).await }
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(napmap.stats().waiting_for_room, 3);
+
+        let mut current = "a";
+        for (waiter, k) in waiters.into_iter().zip(["b", "c", "d"]) {
+            napmap.remove(&current).await;
+            assert!(waiter.await.unwrap().is_ok());
+            assert_eq!(napmap.keys().await, [k]);
+            current = k;
+        }
+        assert_eq!(napmap.stats().waiting_for_room, 0);
+    }
+
+    #[tokio::test]
+    async fn it_should_make_room_when_removing_many_or_draining() {
+        let napmap = Arc::new(NapMap::new(1));
+        for drain in [false, true] {
+            napmap.insert("a", 1).await;
+            let reserved = tokio::spawn({
+                let napmap = napmap.clone();
+                async move {
+                    napmap.reserve().await;
+                }
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert!(!reserved.is_finished());
+
+            match drain {
+                false => assert_eq!(napmap.remove_many(["a"]).await, [("a", 1)]),
+                true => assert_eq!(napmap.drain(EntryOrder::Written).await, [("a", 1)]),
+            }
+            tokio::time::timeout(Duration::from_secs(1), reserved)
+                .await
+                .expect("the reservation should get the freed slot")
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_resize_without_evicting() {
+        let napmap = NapMap::new(2);
+        napmap.insert("a", 1).await;
+        napmap.insert("b", 2).await;
+        assert_eq!((napmap.capacity(), napmap.remaining().await), (2, 0));
+
+        let (permit, resized) = tokio::join!(napmap.reserve(), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.resize(3)
+        });
+        assert!(resized.is_ok());
+        drop(permit);
+        assert_eq!(napmap.remaining().await, 1);
+
+        napmap.resize(1).unwrap();
+        assert_eq!(napmap.len().await, 2);
+        napmap.insert("c", 3).await;
+        assert_eq!(napmap.len().await, 2);
+        napmap.remove(&"c").await;
+        napmap.insert("d", 4).await;
+        assert_eq!(napmap.keys().await, ["d"]);
+        assert!(napmap.resize(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_hand_each_value_to_one_taker() {
+        let napmap = Arc::new(NapMap::new(10));
+        let takers: Vec<_> = (0..3)
+            .map(|_| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.take("reply").await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        for reply in [1, 2] {
+            napmap.insert("reply", reply).await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        napmap.close().await;
+
+        let mut taken = Vec::new();
+        for taker in takers {
+            taken.push(taker.await.unwrap());
+        }
+        taken.sort();
+        assert_eq!(taken, [None, Some(1), Some(2)]);
+        assert!(napmap.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn it_should_swap_only_the_expected_value() {
+        let napmap = NapMap::new(10);
+        assert!(napmap.compare_and_swap("key", None, 1).await.is_ok());
+        let err = napmap.compare_and_swap("key", Some(&0), 2).await;
+        assert!(matches!(err, Err(SwapError::Mismatch { current: Some(1) })));
+        assert!(napmap.compare_and_swap("key", Some(&1), 2).await.is_ok());
+
+        let (v, version) = napmap.update("key", |v| *v += 1).await.unwrap().unwrap();
+        assert_eq!(v, 3);
+        assert_eq!(napmap.get_versioned("key").await.unwrap(), (3, version));
+        assert!(matches!(
+            napmap.update("missing", |v| *v += 1).await,
+            Ok(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_should_tell_why_entries_left() {
+        let left = Arc::new(Mutex::new(Vec::new()));
+        let napmap = NapMap::new(2).with_on_evict({
+            let left = left.clone();
+            move |k: &&str, v: &i32, cause| left.lock().unwrap().push((*k, *v, cause))
+        });
+
+        napmap.insert("a", 1).await;
+        napmap.insert("a", 2).await;
+        napmap
+            .insert_with_ttl("b", 3, Duration::from_millis(1))
+            .await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(napmap.purge_expired().await, 1);
+        napmap.insert("c", 4).await;
+        napmap.insert("d", 5).await;
+        assert_eq!(napmap.remove(&"d").await, Some(5));
+
+        assert_eq!(
+            *left.lock().unwrap(),
+            [
+                ("a", 1, RemovalCause::Replaced),
+                ("b", 3, RemovalCause::Expired),
+                ("c", 4, RemovalCause::Evicted),
+                ("d", 5, RemovalCause::Removed),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_not_take_expired_values() {
+        let napmap = Arc::new(NapMap::new(10));
+        napmap
+            .insert_with_ttl("reply", 1, Duration::from_millis(1))
+            .await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(!napmap.remove_if("reply", |_| true).await);
+
+        let taken = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.take("reply").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!taken.is_finished());
+
+        napmap.insert("reply", 2).await;
+        assert_eq!(taken.await.unwrap(), Some(2));
+        assert!(napmap.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_waiting_for_room_once_closed() {
+        let napmap = NapMap::new(1);
+        napmap.insert("a", 1).await;
+
+        let (inserted, _) = tokio::join!(
+            napmap.insert_timeout("b", 2, Duration::from_secs(1)),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                napmap.close().await
+            }
+        );
+        assert!(matches!(inserted, Err(InsertError::Closed)));
+        assert_eq!(napmap.keys().await, ["a"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_changed_since_on_close() {
+        let napmap = NapMap::new(10);
+        napmap.insert("job", 1).await;
+        let (_, version) = napmap.get_versioned("job").await.unwrap();
+
+        let (changed, _) = tokio::join!(napmap.changed_since("job", version), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close().await
+        });
+        assert_eq!(changed, Err(GetError::Closed));
+        assert_eq!(
+            napmap.changed_since("job", version).await,
+            Err(GetError::Closed)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_wait_for_on_close() {
+        let napmap = NapMap::new(10);
+        napmap.insert("job", 1).await;
+
+        let (done, _) = tokio::join!(napmap.wait_for("job", |v| *v == 2), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close_where(|k| *k == "job").await
+        });
+        assert_eq!(done, Err(GetError::Closed));
+        assert_eq!(napmap.wait_for("job", |v| *v == 1).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_get_all_atomic_on_close() {
+        let napmap = NapMap::new(10);
+        napmap.insert("a/1", 1).await;
+
+        // Closing the second missing key wakes the wait napping on the first
+        let (all, _) = tokio::join!(napmap.get_all_atomic(["b/1", "a/2"]), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close_prefix("a/").await
+        });
+        assert_eq!(all, Err(GetError::Closed));
+        assert_eq!(napmap.get_all_atomic(["a/1"]).await, Ok(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_wait_any_once_every_key_is_closed() {
+        let napmap = NapMap::new(10);
+        let (any, _) = tokio::join!(napmap.wait_any(["a/1", "b/1"]), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close_prefix("a/").await;
+            napmap.insert("b/1", 1).await;
+        });
+        assert_eq!(any, Ok(("b/1", 1)));
+
+        let (any, _) = tokio::join!(napmap.wait_any(["a/2", "c/1"]), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close().await
+        });
+        assert_eq!(any, Err(GetError::Closed));
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_tenants_to_their_namespace() {
+        let napmap = NapMap::new(10)
+            .with_authorizer(|k: &&str, op| k.starts_with("a/") || op == Operation::Get);
+        napmap.insert("a/1", 1).await;
+        assert!(matches!(
+            napmap.insert_checked("b/1", 2).await,
+            Err(InsertError::Denied)
+        ));
+        assert!(matches!(
+            napmap.try_insert("b/1", 2).await,
+            Err(InsertError::Denied)
+        ));
+        assert_eq!(napmap.get(&"a/1").await, Some(1));
+
+        let napmap = napmap.with_authorizer(|k: &&str, _| k.starts_with("b/"));
+        assert_eq!(napmap.try_get(&"a/1").await, None);
+        assert_eq!(napmap.get_checked("a/1").await, Err(GetError::Denied));
+        assert!(matches!(
+            napmap.update("a/1", |v| *v += 1).await,
+            Err(InsertError::Denied)
+        ));
+        assert_eq!(napmap.remove(&"a/1").await, None);
+        assert!(!napmap.remove_if("a/1", |_| true).await);
+        assert!(napmap.remove_many(["a/1"]).await.is_empty());
+        assert_eq!(napmap.take("a/1").await, None);
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_produce_for_the_clones_left() {
+        let napmap = Arc::new(NapMap::new(10));
+        let clone = (*napmap).clone();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        napmap.insert_from_future("produced", async move { rx.await.unwrap() });
+        drop(napmap);
+        assert!(clone.is_pending(&"produced").await);
+
+        tx.send(1).unwrap();
+        assert_eq!(clone.get(&"produced").await, Some(1));
+        assert!(!clone.is_pending(&"produced").await);
+    }
+
+    #[tokio::test]
+    async fn it_should_deny_conditional_writes() {
+        let napmap = NapMap::new(10).with_authorizer(|_: &&str, op| op != Operation::Insert);
+        assert!(matches!(
+            napmap.insert_if_version("key", 1, None).await,
+            Err(VersionError::Denied)
+        ));
+        assert!(matches!(
+            napmap.compare_and_swap("key", None, 1).await,
+            Err(SwapError::Denied)
+        ));
+        assert!(matches!(
+            napmap.rcu("key", |_| 1).await,
+            Err(VersionError::Denied)
+        ));
+        napmap.insert("key", 1).await;
+        assert!(!napmap.pin("key").await);
+        assert!(!napmap.unpin("key").await);
+        assert_eq!(napmap.try_get(&"key").await, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_refuse_waits_on_denied_keys() {
+        let napmap = NapMap::new(10);
+        napmap.insert("a/1", 1).await;
+        let napmap = napmap.with_authorizer(|k: &&str, _| k.starts_with("b/"));
+        assert_eq!(napmap.get_versioned("a/1").await, Err(GetError::Denied));
+        assert_eq!(
+            napmap.changed_since("a/1", Version(0)).await,
+            Err(GetError::Denied)
+        );
+        assert_eq!(
+            napmap.wait_for("a/1", |_| true).await,
+            Err(GetError::Denied)
+        );
+        assert_eq!(napmap.next_update("a/1").await, Err(GetError::Denied));
+        assert_eq!(
+            napmap.get_all_atomic(["b/1", "a/1"]).await,
+            Err(GetError::Denied)
+        );
+        assert_eq!(napmap.wait_any(["b/1", "a/1"]).await, Err(GetError::Denied));
+    }
+
+    #[tokio::test]
+    async fn it_should_hide_denied_keys_from_bulk_reads() {
+        let napmap = NapMap::new(10);
+        napmap.insert("a/1", 1).await;
+        napmap.insert("b/1", 2).await;
+        let napmap = napmap.with_authorizer(|k: &&str, _| k.starts_with("b/"));
+        assert_eq!(
+            napmap.try_get_many(["a/1", "b/1"]).await,
+            HashMap::from([("b/1", 2)])
+        );
+        assert_eq!(napmap.keys().await, vec!["b/1"]);
+        assert_eq!(napmap.values().await, vec![2]);
+        assert_eq!(napmap.iter().await.collect::<Vec<_>>(), vec![("b/1", 2)]);
+        assert_eq!(napmap.snapshot().await, HashMap::from([("b/1", 2)]));
+        assert!(!napmap.contains_key(&"a/1").await);
+        assert!(!napmap.is_pending(&"a/1").await);
+        assert_eq!(napmap.peek(&"a/1").await, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_denied_keys_on_clear() {
+        let napmap = NapMap::new(10);
+        napmap.insert("a/1", 1).await;
+        napmap.insert("b/1", 2).await;
+        napmap.insert("b/2", 3).await;
+        let napmap =
+            napmap.with_authorizer(|k: &&str, op| k.starts_with("b/") || op != Operation::Remove);
+        assert_eq!(
+            napmap.drain(EntryOrder::Written).await,
+            vec![("b/1", 2), ("b/2", 3)]
+        );
+        assert_eq!(napmap.get(&"a/1").await, Some(1));
+
+        napmap.insert("b/3", 4).await;
+        napmap.clear().await;
+        assert_eq!(napmap.keys().await, vec!["a/1"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_write_through_and_load_back_evicted_keys() {
+        let backend: InMemoryBackend<_, _> = [("stored", 7)].into_iter().collect();
+        let napmap = NapMap::new(1).with_backend(backend.clone(), WriteThrough::default());
+
+        assert_eq!(napmap.get(&"stored").await, Some(7));
+        napmap.insert("fresh", 1).await;
+        assert_eq!(backend.len(), 2);
+        assert_eq!(napmap.keys().await, ["fresh"]);
+
+        assert_eq!(napmap.get(&"stored").await, Some(7));
+        assert_eq!(napmap.remove(&"stored").await, Some(7));
+        assert_eq!(backend.len(), 1);
+        assert_eq!(napmap.try_get(&"stored").await, None);
+    }
+
+    struct Unavailable;
+
+    impl Backend<&'static str, i32> for Unavailable {
+        fn store<'a>(
+            &'a self,
+            _: &'a &'static str,
+            _: &'a i32,
+        ) -> BoxFuture<'a, Result<(), BoxError>> {
+            Box::pin(async { Err("unavailable".into()) })
+        }
+
+        fn delete<'a>(&'a self, _: &'a &'static str) -> BoxFuture<'a, Result<(), BoxError>> {
+            Box::pin(async { Err("unavailable".into()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_not_publish_when_the_backend_fails() {
+        let napmap = NapMap::new(10).with_backend(Unavailable, WriteThrough::default());
+
+        assert!(matches!(
+            napmap.insert_checked("key", 7).await,
+            Err(InsertError::Backend(_))
+        ));
+        assert!(matches!(
+            napmap.try_insert("key", 7).await,
+            Err(InsertError::Backend(_))
+        ));
+        assert!(napmap.is_empty().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_tell_expired_keys_from_missing_ones() {
+        let napmap = Arc::new(NapMap::new(10));
+        let start = tokio::time::Instant::now();
+        napmap
+            .insert_with_ttl("key", 1, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(
+            napmap.get_checked("key").await,
+            Err(GetError::Expired {
+                at: start + Duration::from_secs(1)
+            })
+        );
+
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!get.is_finished());
+        napmap.insert("key", 2).await;
+        assert_eq!(get.await.unwrap(), Some(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_read_stale_values_within_the_grace_window() {
+        let napmap = NapMap::new(10).with_grace(Duration::from_secs(10));
+        napmap
+            .insert_with_ttl("key", 1, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(napmap.try_get(&"key").await, None);
+        assert_eq!(napmap.get_stale(&"key").await, Some(1));
+        assert_eq!(napmap.purge_expired().await, 0);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(napmap.get_stale(&"key").await, None);
+        assert_eq!(napmap.purge_expired().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_scale_the_priority_reserve_with_the_capacity() {
+        let napmap = NapMap::new(4).with_priority_reserve(0.5);
+        napmap.resize(8).unwrap();
+        for i in 0..10 {
+            napmap.insert(i, i).await;
+        }
+        assert_eq!(napmap.lane_occupancy(Lane::Normal).await, 4);
+
+        for i in 0..4 {
+            napmap.insert_priority(100 + i, 0, Lane::High).await;
+        }
+        assert_eq!(napmap.lane_occupancy(Lane::High).await, 4);
+        assert_eq!(napmap.len().await, 8);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_stop_reaping_once_the_map_is_dropped() {
+        let napmap = NapMap::new(10).with_ttl(Duration::from_secs(1));
+        let reaper = napmap.reap_expired_every(Duration::from_secs(1));
+        napmap.insert("key", 1).await;
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(napmap.len().await, 0);
+        assert!(!reaper.is_finished());
+
+        drop(napmap);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(reaper.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_unschedule_overwritten_and_removed_deadlines() {
+        let napmap = NapMap::new(10).with_ttl(Duration::from_secs(60));
+        for _ in 0..3 {
+            napmap.insert("key", 1).await;
+            tokio::time::advance(Duration::from_secs(2)).await;
+        }
+        napmap.insert("other", 2).await;
+        assert_eq!(napmap.expiry.len(), 2);
+
+        napmap.remove(&"key").await;
+        assert_eq!(napmap.expiry.len(), 1);
+        napmap.clear().await;
+        assert_eq!(napmap.expiry.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_retire_expired_entries_of_bulk_removals_as_expired() {
+        let left = Arc::new(Mutex::new(Vec::new()));
+        let napmap = NapMap::new(10).with_on_evict({
+            let left = left.clone();
+            move |k: &&str, v: &i32, cause| left.lock().unwrap().push((*k, *v, cause))
+        });
+        napmap.insert("live", 1).await;
+        napmap
+            .insert_with_ttl("stale", 2, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(napmap.remove_many(["live", "stale"]).await, [("live", 1)]);
+
+        napmap
+            .insert_with_ttl("stale", 3, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        napmap.clear().await;
+
+        napmap
+            .insert_with_ttl("stale", 4, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(napmap.shed(1.0).await, 1);
+        assert_eq!(
+            *left.lock().unwrap(),
+            [
+                ("live", 1, RemovalCause::Removed),
+                ("stale", 2, RemovalCause::Expired),
+                ("stale", 3, RemovalCause::Expired),
+                ("stale", 4, RemovalCause::Expired),
+            ]
+        );
+    }
 }
@@ -0,0 +1,201 @@
+use crate::error::lock;
+use crate::hooks::Authorizer;
+use crate::hooks::Hooks;
+use crate::hooks::Operation;
+use crate::version::Version;
+use futures_core::Stream;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::Display;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+
+/// One mutation recorded in a map's changelog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<K, V> {
+    Insert { key: K, value: V, version: Version },
+    Remove { key: K, version: Version },
+}
+
+impl<K, V> Change<K, V> {
+    pub fn version(&self) -> Version {
+        match self {
+            Change::Insert { version, .. } | Change::Remove { version, .. } => *version,
+        }
+    }
+
+    pub(crate) fn key(&self) -> &K {
+        match self {
+            Change::Insert { key, .. } | Change::Remove { key, .. } => key,
+        }
+    }
+}
+
+/// The consumer fell behind and this many changes were dropped from the
+/// changelog before it could read them. The stream resumes with the oldest
+/// change still retained, a replica should resynchronize from a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+impl Display for Lagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "missed {} changes", self.0)
+    }
+}
+
+impl Error for Lagged {}
+
+pub(crate) struct Changelog<K, V> {
+    inner: Mutex<Inner<K, V>>,
+    capacity: usize,
+}
+
+struct Inner<K, V> {
+    changes: VecDeque<Change<K, V>>,
+    wakers: Vec<Waker>,
+    closed: bool,
+}
+
+impl<K, V> Changelog<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "changelog requires capacity > 0");
+        Self {
+            inner: Mutex::new(Inner {
+                changes: VecDeque::with_capacity(capacity),
+                wakers: Vec::new(),
+                closed: false,
+            }),
+            capacity,
+        }
+    }
+
+    /// Must be called in version order, i.e. under the map's write lock.
+    pub(crate) fn record(&self, change: Change<K, V>) {
+        let mut inner = lock(&self.inner);
+        if inner.changes.len() == self.capacity {
+            inner.changes.pop_front();
+        }
+        inner.changes.push_back(change);
+        inner.wakers.drain(..).for_each(Waker::wake);
+    }
+
+    fn close(&self) {
+        let mut inner = lock(&self.inner);
+        inner.closed = true;
+        inner.wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// Owned by the map, ends every [`ChangeStream`] once the map is dropped.
+pub(crate) struct Recorder<K, V>(pub(crate) Arc<Changelog<K, V>>);
+
+impl<K, V> Drop for Recorder<K, V> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Decides which changes a [`ChangeStream`] yields.
+pub(crate) type ChangeFilter<K, V> = Box<dyn Fn(&Change<K, V>) -> bool + Send + Sync>;
+
+/// Stream of the changes recorded after a given version, see
+/// `UnboundedNapMap::changes_since`. Ends once the map is dropped.
+pub struct ChangeStream<K, V> {
+    log: Arc<Changelog<K, V>>,
+    cursor: Version,
+    filter: Option<ChangeFilter<K, V>>,
+    authorizer: Option<(Authorizer<K>, Arc<Hooks>)>,
+}
+
+impl<K, V> ChangeStream<K, V> {
+    pub(crate) fn new(log: Arc<Changelog<K, V>>, cursor: Version) -> Self {
+        Self {
+            log,
+            cursor,
+            filter: None,
+            authorizer: None,
+        }
+    }
+
+    /// Skips the changes `filter` rejects while still holding the changelog,
+    /// so they are never cloned.
+    pub(crate) fn with_filter(mut self, filter: ChangeFilter<K, V>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Skips the changes to keys `authorizer` keeps from reads, like the
+    /// filter.
+    pub(crate) fn with_authorizer(mut self, authorizer: Authorizer<K>, hooks: Arc<Hooks>) -> Self {
+        self.authorizer = Some((authorizer, hooks));
+        self
+    }
+
+    fn accepts(&self, change: &Change<K, V>) -> bool {
+        let allowed = self.authorizer.as_ref().is_none_or(|(authorize, hooks)| {
+            hooks
+                .call("authorizer", || authorize(change.key(), Operation::Get))
+                .unwrap_or(false)
+        });
+        allowed && self.filter.as_ref().is_none_or(|f| f(change))
+    }
+
+    /// Version of the last change yielded or filtered out, to resume from
+    /// after a reconnect.
+    pub fn cursor(&self) -> Version {
+        self.cursor
+    }
+
+    /// Waits for the next change, for callers not using a stream combinator
+    /// crate.
+    pub async fn next(&mut self) -> Option<Result<Change<K, V>, Lagged>>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl<K, V> Stream for ChangeStream<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    type Item = Result<Change<K, V>, Lagged>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut inner = lock(&this.log.inner);
+        let mut next = inner
+            .changes
+            .partition_point(|c| c.version() <= this.cursor);
+
+        loop {
+            let Some(change) = inner.changes.get(next) else {
+                if inner.closed {
+                    return Poll::Ready(None);
+                }
+                if !inner.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    inner.wakers.push(cx.waker().clone());
+                }
+                return Poll::Pending;
+            };
+
+            let missed = change.version().0 - this.cursor.0 - 1;
+            if next == 0 && missed > 0 {
+                this.cursor = Version(change.version().0 - 1);
+                return Poll::Ready(Some(Err(Lagged(missed))));
+            }
+            this.cursor = change.version();
+            if this.accepts(change) {
+                return Poll::Ready(Some(Ok(change.clone())));
+            }
+            next += 1;
+        }
+    }
+}
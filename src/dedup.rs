@@ -0,0 +1,85 @@
+use crate::error::lock;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+type Outcome<V> = watch::Receiver<Option<Option<V>>>;
+
+/// Gets in flight per `(key, request)`, so repeated gets of one request share
+/// a single nap.
+#[derive(Debug)]
+pub(crate) struct Requests<K, V> {
+    in_flight: Mutex<HashMap<(K, u64), Outcome<V>>>,
+}
+
+impl<K, V> Requests<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Awaits `get`, unless a get for the same `k` and `request` is already in
+    /// flight, in which case its outcome is shared instead.
+    pub(crate) async fn dedup(
+        &self,
+        k: K,
+        request: u64,
+        get: impl Future<Output = Option<V>>,
+    ) -> Option<V> {
+        let id = (k, request);
+        let (tx, rx) = watch::channel(None);
+        let joined = {
+            let mut table = lock(&self.in_flight);
+            match table.get(&id) {
+                Some(outcome) => Some(outcome.clone()),
+                None => {
+                    table.insert(id.clone(), rx);
+                    None
+                }
+            }
+        };
+        if let Some(mut outcome) = joined {
+            tracing::trace!("Joining the get of the same request");
+            if let Ok(v) = outcome.wait_for(Option::is_some).await {
+                return v.clone().flatten();
+            }
+            // The first get was cancelled, nap on our own
+            return get.await;
+        }
+
+        let _done = Done {
+            requests: self,
+            id: Some(id),
+        };
+        let v = get.await;
+        let _ = tx.send(Some(v.clone()));
+        v
+    }
+}
+
+/// Clears the in-flight marker, even if the get is cancelled.
+struct Done<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    requests: &'a Requests<K, V>,
+    id: Option<(K, u64)>,
+}
+
+impl<K, V> Drop for Done<'_, K, V>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            lock(&self.requests.in_flight).remove(&id);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::sync::AsyncMutex;
+use crate::UnboundedNapMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// An unbounded napmap whose entries are reachable by either of two keys,
+/// e.g. a request id and a correlation id. Both lookups nap until an entry
+/// with that key is inserted.
+///
+/// Writes are serialized, so the two indexes always agree: every secondary
+/// key points at a live primary key and the other way around.
+pub struct DualNapMap<P, S, V>
+where
+    P: Eq + Hash + Clone + Debug,
+    S: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    primary: UnboundedNapMap<P, (S, V)>,
+    secondary: UnboundedNapMap<S, P>,
+    writes: AsyncMutex<()>,
+}
+
+impl<P, S, V> DualNapMap<P, S, V>
+where
+    P: Eq + Hash + Clone + Debug,
+    S: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    pub fn new() -> Self {
+        Self {
+            primary: UnboundedNapMap::new(),
+            secondary: UnboundedNapMap::new(),
+            writes: AsyncMutex::new(()),
+        }
+    }
+
+    /// Inserts `v` under both keys, replacing the entry of either key. The
+    /// primary key goes in first, so a secondary lookup never finds a
+    /// primary key that isn't there yet.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub async fn insert(&self, p: P, s: S, v: V) {
+        tracing::trace!("Insert");
+        let _writes = self.writes.lock().await;
+        if let Some((old, _)) = self.primary.try_get(&p).await {
+            if old != s {
+                self.secondary.remove(&old).await;
+            }
+        }
+        if let Some(old) = self.secondary.try_get(&s).await {
+            if old != p {
+                self.primary.remove(&old).await;
+            }
+        }
+        self.primary.insert(p.clone(), (s.clone(), v)).await;
+        self.secondary.insert(s, p).await;
+    }
+
+    pub async fn get_by_primary(&self, p: &P) -> Option<V> {
+        self.primary.get(p).await.map(|(_, v)| v)
+    }
+
+    pub async fn get_by_secondary(&self, s: &S) -> Option<V> {
+        let p = self.secondary.get(s).await?;
+        // Removed in between at worst, never not yet inserted
+        self.primary.try_get(&p).await.map(|(_, v)| v)
+    }
+
+    pub async fn remove_by_primary(&self, p: &P) -> Option<V> {
+        let _writes = self.writes.lock().await;
+        let (s, v) = self.primary.remove(p).await?;
+        self.secondary.remove(&s).await;
+        Some(v)
+    }
+
+    pub async fn remove_by_secondary(&self, s: &S) -> Option<V> {
+        let _writes = self.writes.lock().await;
+        let p = self.secondary.remove(s).await?;
+        self.primary.remove(&p).await.map(|(_, v)| v)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.primary.len().await
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.primary.is_empty().await
+    }
+}
+
+impl<P, S, V> Default for DualNapMap<P, S, V>
+where
+    P: Eq + Hash + Clone + Debug,
+    S: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P, S, V> Debug for DualNapMap<P, S, V>
+where
+    P: Eq + Hash + Clone + Debug,
+    S: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DualNapMap")
+            .field("primary", &self.primary)
+            .field("secondary", &self.secondary)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DualNapMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn it_should_nap_on_either_key() {
+        let napmap = Arc::new(DualNapMap::new());
+        let by_primary = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get_by_primary(&1).await }
+        });
+        let by_secondary = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get_by_secondary(&"correlation").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        napmap.insert(1, "correlation", "value").await;
+        assert_eq!(by_primary.await.unwrap(), Some("value"));
+        assert_eq!(by_secondary.await.unwrap(), Some("value"));
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_both_indexes_in_sync() {
+        let napmap = DualNapMap::new();
+        napmap.insert(1, "a", 10).await;
+        napmap.insert(1, "b", 11).await;
+        assert_eq!(napmap.get_by_secondary(&"b").await, Some(11));
+        assert_eq!(napmap.len().await, 1);
+
+        napmap.insert(2, "b", 20).await;
+        assert_eq!(napmap.len().await, 1);
+        assert_eq!(napmap.remove_by_secondary(&"b").await, Some(20));
+        assert!(napmap.is_empty().await);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::BackendError;
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use tokio::time::Instant;
+
+/// Failures coming from inside the library rather than from the caller's
+/// data, surfaced as values so a shared cache never takes the process down.
+#[derive(Debug)]
+pub enum NapMapInternalError {
+    /// A configuration value the map can't work with, like a zero capacity.
+    InvalidConfig(&'static str),
+    /// The backend refused a change, see [`BackendError`].
+    Backend(BackendError),
+}
+
+impl Display for NapMapInternalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NapMapInternalError::InvalidConfig(reason) => write!(f, "invalid config: {reason}"),
+            NapMapInternalError::Backend(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for NapMapInternalError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NapMapInternalError::InvalidConfig(_) => None,
+            NapMapInternalError::Backend(e) => Some(e),
+        }
+    }
+}
+
+impl From<BackendError> for NapMapInternalError {
+    fn from(e: BackendError) -> Self {
+        NapMapInternalError::Backend(e)
+    }
+}
+
+/// Returned by `get_checked` and the lookups built on it when they give up
+/// instead of napping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GetError {
+    /// The key has no napping task yet and the map already reached its limit
+    /// of keys with napping tasks.
+    TooManyPendingKeys { limit: usize },
+    /// The key was deliberately removed, see `with_tombstones`.
+    Removed,
+    /// The waiter was evicted by `retain_waiters`.
+    Evicted,
+    /// The key is missing and the map was closed, see `close`.
+    Closed,
+    /// The deadline of `get_timeout` or `get_deadline` passed first.
+    TimedOut,
+    /// The authorizer refused the lookup, see `with_authorizer`.
+    Denied,
+    /// The key is still held but its TTL ran out `at`, see `with_ttl`.
+    Expired { at: Instant },
+}
+
+impl Display for GetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GetError::TooManyPendingKeys { limit } => {
+                write!(f, "more than {limit} keys with napping tasks")
+            }
+            GetError::Removed => write!(f, "key was removed"),
+            GetError::Evicted => write!(f, "waiter was evicted"),
+            GetError::Closed => write!(f, "napmap was closed"),
+            GetError::TimedOut => write!(f, "timed out waiting for the key"),
+            GetError::Denied => write!(f, "lookup was denied"),
+            GetError::Expired { .. } => write!(f, "key has expired"),
+        }
+    }
+}
+
+impl Error for GetError {}
+
+/// Returned by `get_as` when the value is not of the requested type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DowncastError {
+    pub expected: &'static str,
+}
+
+impl Display for DowncastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "value is not a {}", self.expected)
+    }
+}
+
+impl Error for DowncastError {}
+
+/// An entry weighing more than the map's `with_max_entry_weight` allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryTooLarge {
+    pub weight: usize,
+    pub max: usize,
+}
+
+impl Display for EntryTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "entry weighs {}, more than {}", self.weight, self.max)
+    }
+}
+
+impl Error for EntryTooLarge {}
+
+/// Returned by the inserts that did not store the entry, with the value
+/// when it did not fit.
+#[derive(Debug)]
+pub enum InsertError<V> {
+    /// The entry was rejected without touching the map, see [`EntryTooLarge`].
+    TooLarge(EntryTooLarge),
+    /// The backend refused the change, see [`BackendError`].
+    Backend(BackendError),
+    /// The authorizer refused the insert, see `with_authorizer`.
+    Denied,
+    /// The key was closed while the insert waited for room, see `close`.
+    Closed,
+    /// The map is at capacity and the insert refuses to evict, see
+    /// `try_insert`.
+    Full(V),
+}
+
+impl<V> Display for InsertError<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InsertError::TooLarge(e) => e.fmt(f),
+            InsertError::Backend(e) => e.fmt(f),
+            InsertError::Denied => write!(f, "insert was denied"),
+            InsertError::Closed => write!(f, "napmap was closed"),
+            InsertError::Full(_) => write!(f, "napmap is full"),
+        }
+    }
+}
+
+impl<V: std::fmt::Debug> Error for InsertError<V> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InsertError::TooLarge(e) => Some(e),
+            InsertError::Backend(e) => Some(e),
+            InsertError::Denied | InsertError::Closed | InsertError::Full(_) => None,
+        }
+    }
+}
+
+impl<V> From<EntryTooLarge> for InsertError<V> {
+    fn from(e: EntryTooLarge) -> Self {
+        InsertError::TooLarge(e)
+    }
+}
+
+impl<V> From<BackendError> for InsertError<V> {
+    fn from(e: BackendError) -> Self {
+        InsertError::Backend(e)
+    }
+}
+
+/// Locks `mutex` even if a panic poisoned it. The critical sections guarded
+/// this way leave their state consistent at every step, so a panic elsewhere
+/// must not cascade into every later caller.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lock;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    #[test]
+    fn it_should_lock_a_poisoned_mutex() {
+        let mutex = Arc::new(Mutex::new(1));
+        let poisoner = mutex.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison");
+        })
+        .join();
+
+        assert!(mutex.is_poisoned());
+        assert_eq!(*lock(&mutex), 1);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::bounded::EvictionPolicy;
+use crate::bounded::Lane;
+use crate::error::lock;
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+/// Where an entry stands for the eviction policies.
+#[derive(Debug)]
+pub(crate) struct Usage {
+    /// When the key was first inserted, overwrites keep it.
+    seq: u64,
+    last_used: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Usage {
+    pub(crate) fn new(tick: u64) -> Self {
+        Self {
+            seq: tick,
+            last_used: AtomicU64::new(tick),
+            hits: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Unique per entry, the victim of a policy ranks first, or last for
+/// [`EvictionPolicy::Lifo`].
+type Rank = (u64, u64);
+
+/// The unpinned entries of each lane ordered by the eviction policy, so
+/// picking a victim never scans the map. Kept in step with the map under its
+/// write lock, reads only re-rank under the read lock.
+///
+/// Changing the policy leaves the order stale until the next victim is picked,
+/// which rebuilds it from the map.
+#[derive(Debug)]
+pub(crate) struct Evictions<K> {
+    inner: Mutex<Inner<K>>,
+    tracks_reads: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Inner<K> {
+    policy: EvictionPolicy,
+    stale: bool,
+    ranked: [BTreeMap<Rank, K>; 2],
+    // Pinned entries included
+    lanes: [usize; 2],
+}
+
+impl<K> Inner<K> {
+    fn rank(&self, usage: &Usage) -> Rank {
+        match self.policy {
+            EvictionPolicy::Lifo | EvictionPolicy::Fifo => (usage.seq, 0),
+            EvictionPolicy::Lru => (usage.last_used.load(Ordering::Relaxed), 0),
+            EvictionPolicy::Lfu => (usage.hits.load(Ordering::Relaxed), usage.seq),
+        }
+    }
+}
+
+fn index(lane: Lane) -> usize {
+    match lane {
+        Lane::Normal => 0,
+        Lane::High => 1,
+    }
+}
+
+impl<K> Evictions<K>
+where
+    K: Clone,
+{
+    pub(crate) fn new(policy: EvictionPolicy) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                policy,
+                stale: false,
+                ranked: [BTreeMap::new(), BTreeMap::new()],
+                lanes: [0, 0],
+            }),
+            tracks_reads: AtomicBool::new(tracks_reads(policy)),
+        }
+    }
+
+    pub(crate) fn set_policy(&self, policy: EvictionPolicy) {
+        let mut inner = lock(&self.inner);
+        if inner.policy != policy {
+            inner.policy = policy;
+            inner.stale = true;
+        }
+        self.tracks_reads
+            .store(tracks_reads(policy), Ordering::Relaxed);
+    }
+
+    /// Whether reads move entries, see [`touch`](Self::touch).
+    pub(crate) fn tracks_reads(&self) -> bool {
+        self.tracks_reads.load(Ordering::Relaxed)
+    }
+
+    /// Adds a new entry.
+    pub(crate) fn link(&self, k: K, lane: Lane, usage: &Usage) {
+        let mut inner = lock(&self.inner);
+        let rank = inner.rank(usage);
+        inner.ranked[index(lane)].insert(rank, k);
+        inner.lanes[index(lane)] += 1;
+    }
+
+    /// Forgets an entry that left the map.
+    pub(crate) fn unlink(&self, lane: Lane, usage: &Usage, pinned: bool) {
+        let mut inner = lock(&self.inner);
+        if !pinned {
+            let rank = inner.rank(usage);
+            inner.ranked[index(lane)].remove(&rank);
+        }
+        inner.lanes[index(lane)] -= 1;
+    }
+
+    /// Moves an overwritten entry to the lane it was overwritten through.
+    pub(crate) fn relane(&self, from: Lane, to: Lane, usage: &Usage, pinned: bool) {
+        let mut inner = lock(&self.inner);
+        if !pinned {
+            let rank = inner.rank(usage);
+            if let Some(k) = inner.ranked[index(from)].remove(&rank) {
+                inner.ranked[index(to)].insert(rank, k);
+            }
+        }
+        inner.lanes[index(from)] -= 1;
+        inner.lanes[index(to)] += 1;
+    }
+
+    /// Takes a newly pinned entry out of the order.
+    pub(crate) fn pin(&self, lane: Lane, usage: &Usage) {
+        let mut inner = lock(&self.inner);
+        let rank = inner.rank(usage);
+        inner.ranked[index(lane)].remove(&rank);
+    }
+
+    /// Puts a newly unpinned entry back in the order.
+    pub(crate) fn unpin(&self, k: K, lane: Lane, usage: &Usage) {
+        let mut inner = lock(&self.inner);
+        let rank = inner.rank(usage);
+        inner.ranked[index(lane)].insert(rank, k);
+    }
+
+    /// Records a read or an overwrite at `tick`.
+    pub(crate) fn touch(&self, lane: Lane, usage: &Usage, pinned: bool, tick: u64) {
+        let mut inner = lock(&self.inner);
+        let old = inner.rank(usage);
+        usage.last_used.store(tick, Ordering::Relaxed);
+        usage.hits.fetch_add(1, Ordering::Relaxed);
+        if pinned || inner.stale {
+            return;
+        }
+        let new = inner.rank(usage);
+        let ranked = &mut inner.ranked[index(lane)];
+        if let Some(k) = ranked.remove(&old) {
+            ranked.insert(new, k);
+        }
+    }
+
+    /// The next entry to evict, among the entries of `lane` if given.
+    /// `entries` lists the map's entries, as `(key, lane, usage, pinned)`,
+    /// in case the order has to be rebuilt.
+    pub(crate) fn victim<'a, I>(&self, lane: Option<Lane>, entries: impl FnOnce() -> I) -> Option<K>
+    where
+        K: 'a,
+        I: Iterator<Item = (&'a K, Lane, &'a Usage, bool)>,
+    {
+        let mut inner = lock(&self.inner);
+        if inner.stale {
+            let mut ranked = [BTreeMap::new(), BTreeMap::new()];
+            for (k, lane, usage, pinned) in entries() {
+                if !pinned {
+                    ranked[index(lane)].insert(inner.rank(usage), k.clone());
+                }
+            }
+            inner.ranked = ranked;
+            inner.stale = false;
+        }
+
+        let lanes = match lane {
+            Some(lane) => &inner.ranked[index(lane)..=index(lane)],
+            None => &inner.ranked[..],
+        };
+        let victim = match inner.policy {
+            EvictionPolicy::Lifo => lanes
+                .iter()
+                .filter_map(|ranked| ranked.last_key_value())
+                .max_by_key(|(rank, _)| **rank),
+            _ => lanes
+                .iter()
+                .filter_map(|ranked| ranked.first_key_value())
+                .min_by_key(|(rank, _)| **rank),
+        };
+        victim.map(|(_, k)| k.clone())
+    }
+
+    /// Number of entries inserted through `lane`, pinned ones included.
+    pub(crate) fn occupancy(&self, lane: Lane) -> usize {
+        lock(&self.inner).lanes[index(lane)]
+    }
+
+    /// Forgets every entry, e.g. once the map is cleared.
+    pub(crate) fn clear(&self) {
+        let mut inner = lock(&self.inner);
+        inner.ranked.iter_mut().for_each(BTreeMap::clear);
+        inner.lanes = [0, 0];
+    }
+}
+
+fn tracks_reads(policy: EvictionPolicy) -> bool {
+    matches!(policy, EvictionPolicy::Lru | EvictionPolicy::Lfu)
+}
@@ -0,0 +1,108 @@
+use crate::error::lock;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How far apart the deadlines sharing a bucket can be.
+const BUCKET: Duration = Duration::from_secs(1);
+
+/// The deadlines of the entries inserted with a TTL, bucketed by the second
+/// they fall in, so purging only visits the entries that are due.
+///
+/// Overwriting or removing a key unschedules its old deadline, so the index
+/// holds at most one deadline per key and never outgrows the map.
+#[derive(Debug)]
+pub(crate) struct Expiry<K> {
+    origin: Instant,
+    buckets: Mutex<BTreeMap<u64, HashMap<K, Instant>>>,
+}
+
+impl<K> Expiry<K>
+where
+    K: Eq + Hash,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn schedule(&self, k: K, at: Instant) {
+        let bucket = self.bucket(at);
+        lock(&self.buckets).entry(bucket).or_default().insert(k, at);
+    }
+
+    /// Drops the deadline `k` was scheduled at, if still there.
+    pub(crate) fn unschedule(&self, k: &K, at: Instant) {
+        let mut buckets = lock(&self.buckets);
+        let Some(deadlines) = buckets.get_mut(&self.bucket(at)) else {
+            return;
+        };
+        if deadlines.get(k) == Some(&at) {
+            deadlines.remove(k);
+            if deadlines.is_empty() {
+                buckets.remove(&self.bucket(at));
+            }
+        }
+    }
+
+    /// Takes every deadline at or before `now`.
+    pub(crate) fn due(&self, now: Instant) -> Vec<(K, Instant)> {
+        let current = self.bucket(now);
+        let mut buckets = lock(&self.buckets);
+        let later = buckets.split_off(&(current + 1));
+        let due = std::mem::replace(&mut *buckets, later);
+
+        let mut expired = Vec::new();
+        for (bucket, deadlines) in due {
+            for (k, at) in deadlines {
+                // Only the current bucket holds deadlines still ahead
+                match at <= now {
+                    true => expired.push((k, at)),
+                    false => {
+                        buckets.entry(bucket).or_default().insert(k, at);
+                    }
+                }
+            }
+        }
+        expired
+    }
+
+    /// The earliest deadline `is_current` accepts, dropping the ones it
+    /// rejects on the way.
+    pub(crate) fn earliest(&self, is_current: impl Fn(&K, Instant) -> bool) -> Option<(K, Instant)>
+    where
+        K: Clone,
+    {
+        let mut buckets = lock(&self.buckets);
+        while let Some(mut first) = buckets.first_entry() {
+            first.get_mut().retain(|k, at| is_current(k, *at));
+            match first.get().iter().min_by_key(|(_, at)| **at) {
+                Some((k, at)) => return Some((k.clone(), *at)),
+                None => {
+                    first.remove();
+                }
+            }
+        }
+        None
+    }
+
+    /// Forgets every deadline, e.g. once the map is cleared.
+    pub(crate) fn clear(&self) {
+        lock(&self.buckets).clear();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        lock(&self.buckets).values().map(HashMap::len).sum()
+    }
+
+    fn bucket(&self, at: Instant) -> u64 {
+        let since = at.saturating_duration_since(self.origin);
+        (since.as_nanos() / BUCKET.as_nanos()) as u64
+    }
+}
@@ -0,0 +1,78 @@
+use crate::watermark::HighWater;
+use crate::watermark::Watermark;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Counts the tasks currently in some state, like napping on a map, and lets
+/// others wait until there are none.
+pub(crate) struct Gauge {
+    count: watch::Sender<usize>,
+    peak: HighWater,
+    observe: Option<Box<dyn Fn(usize) + Send + Sync>>,
+}
+
+impl Gauge {
+    pub(crate) fn new() -> Self {
+        Self {
+            count: watch::channel(0).0,
+            peak: HighWater::new(),
+            observe: None,
+        }
+    }
+
+    /// Like [`new`](Self::new), calling `observe` with every new count.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn observed(observe: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        Self {
+            observe: Some(Box::new(observe)),
+            ..Self::new()
+        }
+    }
+
+    /// Counts the caller until the returned guard is dropped.
+    pub(crate) fn enter(self: &Arc<Self>) -> Entered {
+        self.count.send_modify(|n| {
+            *n += 1;
+            self.peak.observe(*n);
+            self.report(*n);
+        });
+        Entered(self.clone())
+    }
+
+    fn report(&self, count: usize) {
+        if let Some(observe) = &self.observe {
+            observe(count);
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        *self.count.borrow()
+    }
+
+    /// Most tasks ever counted at once.
+    pub(crate) fn peak(&self) -> Watermark {
+        self.peak.get()
+    }
+
+    pub(crate) fn reset_peak(&self) {
+        self.peak.reset(*self.count.borrow());
+    }
+
+    /// Resolves once no task is counted.
+    pub(crate) async fn drained(&self) {
+        let mut rx = self.count.subscribe();
+        // The sender lives in `self`, so this can't fail
+        let _ = rx.wait_for(|n| *n == 0).await;
+    }
+}
+
+pub(crate) struct Entered(Arc<Gauge>);
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        self.0.count.send_modify(|n| {
+            *n -= 1;
+            self.0.report(*n);
+        });
+    }
+}
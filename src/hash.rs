@@ -0,0 +1,96 @@
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+
+const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// A hasher for integer and UUID keys, like the request ids most rendezvous
+/// maps are keyed by. Each word written costs one multiply, which is enough
+/// to spread sequential ids over the table but no defense against keys
+/// chosen to collide: keep it to ids the map hands out or trusts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdHasher(u64);
+
+impl IdHasher {
+    fn mix(&mut self, word: u64) {
+        self.0 = (self.0 ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    /// Folds the bytes in 8 at a time, e.g. the 16 of a `Uuid`.
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.mix(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut word = [0; 8];
+            word[..rest.len()].copy_from_slice(rest);
+            self.mix(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u8(&mut self, n: u8) {
+        self.mix(n.into());
+    }
+
+    fn write_u16(&mut self, n: u16) {
+        self.mix(n.into());
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.mix(n.into());
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.mix(n);
+    }
+
+    fn write_u128(&mut self, n: u128) {
+        self.mix(n as u64);
+        self.mix((n >> 64) as u64);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.mix(n as u64);
+    }
+}
+
+/// Builds [`IdHasher`]s, for `HashMap::with_hasher` and the like.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuildIdHasher;
+
+impl BuildHasher for BuildIdHasher {
+    type Hasher = IdHasher;
+
+    fn build_hasher(&self) -> IdHasher {
+        IdHasher::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BuildIdHasher;
+    use std::collections::HashSet;
+    use std::hash::BuildHasher;
+
+    #[test]
+    fn it_should_spread_sequential_ids() {
+        let hashes: HashSet<u64> = (0..1024u64).map(|id| BuildIdHasher.hash_one(id)).collect();
+        assert_eq!(hashes.len(), 1024);
+        // hashbrown tags buckets with the top 7 bits, they must vary too
+        let tags: HashSet<u64> = hashes.iter().map(|h| h >> 57).collect();
+        assert!(tags.len() > 64);
+
+        let uuid = [7u8; 16];
+        assert_ne!(
+            BuildIdHasher.hash_one(uuid),
+            BuildIdHasher.hash_one([8u8; 16])
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use crate::backend::BoxFuture;
+use crate::error::lock;
+use crate::sync::AsyncRwLock;
+use std::any::Any;
+use std::collections::HashSet;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Hands a value that left the map over to the user's async finalizer, on a
+/// task of its own.
+pub(crate) type Finalizer<K, V> = Arc<dyn Fn(K, V) + Send + Sync>;
+
+/// Called inline with every entry removed explicitly.
+pub(crate) type OnRemove<K, V> = Arc<dyn Fn(&K, &V) + Send + Sync>;
+
+/// Called inline with every entry leaving the map, and why it left.
+pub(crate) type OnEvict<K, V> = Arc<dyn Fn(&K, &V, RemovalCause) + Send + Sync>;
+
+/// Consulted with every lookup, insert and removal, see `Operation`.
+pub(crate) type Authorizer<K> = Arc<dyn Fn(&K, Operation) -> bool + Send + Sync>;
+
+/// Picks the keys closed through `close_where`.
+pub(crate) type KeyFilter<K> = Arc<dyn Fn(&K) -> bool + Send + Sync>;
+
+/// Weighs an entry for `shrink_to_weight`, entries weigh 1 without one.
+pub(crate) type Weigher<K, V> = Arc<dyn Fn(&K, &V) -> usize + Send + Sync>;
+
+/// Called with the key and the elapsed time once a single nap outlasts the
+/// configured threshold.
+pub(crate) type SlowWait<K> = Arc<dyn Fn(&K, Duration) + Send + Sync>;
+
+/// Clones the value of a key on the blocking pool, for values too large to
+/// clone on an async worker thread.
+pub(crate) type BlockingClone<K, V> = Arc<dyn Fn(K) -> BoxFuture<'static, Option<V>> + Send + Sync>;
+
+pub(crate) fn finalizer<K, V, F, Fut>(f: F) -> Finalizer<K, V>
+where
+    K: Send + 'static,
+    V: Send + 'static,
+    F: Fn(K, V) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |k, v| {
+        tokio::spawn(f(k, v));
+    })
+}
+
+/// Clones with `read` on the blocking pool, holding an owned read lock of
+/// `map` meanwhile. A panicking clone is resumed in the caller.
+pub(crate) fn blocking_clone<K, V, M>(
+    map: Arc<AsyncRwLock<M>>,
+    read: fn(&M, &K) -> Option<V>,
+) -> BlockingClone<K, V>
+where
+    K: Send + 'static,
+    V: Send + 'static,
+    M: Send + Sync + 'static,
+{
+    Arc::new(move |k| {
+        let map = map.clone();
+        Box::pin(async move {
+            let guard = map.read_owned().await;
+            match tokio::task::spawn_blocking(move || read(&guard, &k)).await {
+                Ok(v) => v,
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Err(_) => None,
+            }
+        })
+    })
+}
+
+/// What an authorizer is asked to allow on a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Get,
+    Insert,
+    Remove,
+}
+
+/// Why an entry left the map, as told to an `on_evict` listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemovalCause {
+    /// Removed through `remove`, `clear`, `drain` and the like.
+    Removed,
+    /// Overwritten by an insert of the same key.
+    Replaced,
+    /// Outlived its time to live.
+    Expired,
+    /// Pushed out to make room, or shed.
+    Evicted,
+}
+
+/// What happens when a user hook (a finalizer, an `on_remove` or slow-wait
+/// callback, a weigher, an authorizer) panics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HookPanic {
+    /// The panic unwinds into the map operation that ran the hook.
+    #[default]
+    Unwind,
+    /// The panic is logged and the process aborted.
+    Abort,
+    /// The panic is logged and the operation goes on as if the hook was not
+    /// set, a weigher then weighs the entry 1. An authorizer denies instead.
+    Log,
+    /// Like `Log`, and the hook is never called again.
+    Disable,
+}
+
+/// Runs hooks under a [`HookPanic`] policy, remembering disabled ones.
+#[derive(Debug, Default)]
+pub(crate) struct Hooks {
+    on_panic: HookPanic,
+    disabled: Mutex<HashSet<&'static str>>,
+}
+
+impl Hooks {
+    pub(crate) fn new(on_panic: HookPanic) -> Self {
+        Self {
+            on_panic,
+            disabled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Runs `f` as hook `name`, `None` when it panicked or was disabled.
+    pub(crate) fn call<R>(&self, name: &'static str, f: impl FnOnce() -> R) -> Option<R> {
+        if self.on_panic == HookPanic::Unwind {
+            return Some(f());
+        }
+        if lock(&self.disabled).contains(name) {
+            return None;
+        }
+        let panic = match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(r) => return Some(r),
+            Err(panic) => panic,
+        };
+        let message = panic_message(panic.as_ref());
+        tracing::error!("The {name} hook panicked: {message}");
+        match self.on_panic {
+            HookPanic::Unwind => unreachable!(),
+            HookPanic::Abort => std::process::abort(),
+            HookPanic::Log => {}
+            HookPanic::Disable => {
+                tracing::warn!("Disabling the {name} hook");
+                lock(&self.disabled).insert(name);
+            }
+        }
+        None
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("..", String::as_str),
+    }
+}
@@ -0,0 +1,162 @@
+use crate::error::lock;
+use crate::UnboundedNapMap;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Compact id standing for an interned string key.
+pub type KeyId = u32;
+
+#[derive(Debug, Default)]
+struct Interner {
+    ids: HashMap<Arc<str>, KeyId>,
+    keys: Vec<Arc<str>>,
+}
+
+impl Interner {
+    fn intern(&mut self, k: &str) -> KeyId {
+        if let Some(id) = self.ids.get(k) {
+            return *id;
+        }
+        let id = KeyId::try_from(self.keys.len()).expect("more than u32::MAX interned keys");
+        let k: Arc<str> = Arc::from(k);
+        self.ids.insert(k.clone(), id);
+        self.keys.push(k);
+        id
+    }
+}
+
+/// An unbounded napmap keyed by strings, storing every distinct key once and
+/// hashing compact [`KeyId`]s instead of the strings themselves.
+///
+/// Meant for maps with millions of long keys. Ids are never given back, so
+/// the interning table only grows with the number of distinct keys seen,
+/// including the ones only ever asked for.
+pub struct InternedNapMap<V>
+where
+    V: Clone + Debug,
+{
+    interner: Mutex<Interner>,
+    map: UnboundedNapMap<KeyId, V>,
+}
+
+impl<V> InternedNapMap<V>
+where
+    V: Clone + Debug,
+{
+    pub fn new() -> Self {
+        Self::with_map(UnboundedNapMap::new())
+    }
+
+    /// Interns the keys of `map`, once configured through its own builders.
+    pub fn with_map(map: UnboundedNapMap<KeyId, V>) -> Self {
+        Self {
+            interner: Mutex::new(Interner::default()),
+            map,
+        }
+    }
+
+    /// The id of `k`, if it was ever seen.
+    pub fn id(&self, k: &str) -> Option<KeyId> {
+        lock(&self.interner).ids.get(k).copied()
+    }
+
+    /// The key standing behind `id`.
+    pub fn key(&self, id: KeyId) -> Option<Arc<str>> {
+        lock(&self.interner).keys.get(id as usize).cloned()
+    }
+
+    fn intern(&self, k: &str) -> KeyId {
+        lock(&self.interner).intern(k)
+    }
+
+    /// The underlying map, keyed by id.
+    pub fn inner(&self) -> &UnboundedNapMap<KeyId, V> {
+        &self.map
+    }
+
+    pub async fn insert(&self, k: &str, v: V) {
+        self.map.insert(self.intern(k), v).await;
+    }
+
+    pub async fn get(&self, k: &str) -> Option<V> {
+        self.map.get(&self.intern(k)).await
+    }
+
+    pub async fn remove(&self, k: &str) -> Option<V> {
+        let id = self.id(k)?;
+        self.map.remove(&id).await
+    }
+
+    pub async fn len(&self) -> usize {
+        self.map.len().await
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.map.is_empty().await
+    }
+
+    /// Number of distinct keys interned so far.
+    pub fn interned(&self) -> usize {
+        lock(&self.interner).keys.len()
+    }
+}
+
+impl<V> Default for InternedNapMap<V>
+where
+    V: Clone + Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Debug for InternedNapMap<V>
+where
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InternedNapMap")
+            .field("interned", &self.interned())
+            .field("map", &self.map)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InternedNapMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn it_should_intern_every_key_once() {
+        let napmap = InternedNapMap::new();
+        napmap.insert("a/very/long/key", 1).await;
+        napmap.insert("a/very/long/key", 2).await;
+        napmap.insert("another/long/key", 3).await;
+
+        assert_eq!(napmap.get("a/very/long/key").await, Some(2));
+        assert_eq!(napmap.interned(), 2);
+        let id = napmap.id("another/long/key").unwrap();
+        assert_eq!(napmap.key(id).as_deref(), Some("another/long/key"));
+
+        assert_eq!(napmap.remove("unknown").await, None);
+        assert_eq!(napmap.interned(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_nap_on_interned_keys() {
+        let napmap = Arc::new(InternedNapMap::new());
+        tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                napmap.insert("key", 1).await;
+            }
+        });
+
+        assert_eq!(napmap.get("key").await, Some(1));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::unbounded::UnboundedNapMap;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+
+/// Frames larger than this are rejected instead of allocated.
+const MAX_FRAME: u32 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+enum Request<K, V> {
+    Insert(K, V),
+    Get(K),
+    Remove(K),
+}
+
+#[derive(Serialize, Deserialize)]
+enum Response<V> {
+    Inserted,
+    Value(Option<V>),
+}
+
+#[derive(Debug)]
+pub enum IpcError {
+    Io(io::Error),
+    Codec(bincode::Error),
+    /// The peer answered with a response that doesn't match the request.
+    Protocol,
+}
+
+impl Display for IpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpcError::Io(e) => write!(f, "ipc io error: {e}"),
+            IpcError::Codec(e) => write!(f, "ipc codec error: {e}"),
+            IpcError::Protocol => write!(f, "unexpected ipc response"),
+        }
+    }
+}
+
+impl Error for IpcError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IpcError::Io(e) => Some(e),
+            IpcError::Codec(e) => Some(e),
+            IpcError::Protocol => None,
+        }
+    }
+}
+
+impl From<io::Error> for IpcError {
+    fn from(e: io::Error) -> Self {
+        IpcError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for IpcError {
+    fn from(e: bincode::Error) -> Self {
+        IpcError::Codec(e)
+    }
+}
+
+/// Frames are a big endian `u32` length followed by a bincode payload.
+async fn write_frame<T: Serialize>(stream: &mut UnixStream, msg: &T) -> Result<(), IpcError> {
+    let payload = bincode::serialize(msg)?;
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(&payload).await?;
+    Ok(())
+}
+
+/// Returns `None` when the peer closed the connection between frames.
+async fn read_frame<T: DeserializeOwned>(stream: &mut UnixStream) -> Result<Option<T>, IpcError> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large").into());
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok(Some(bincode::deserialize(&payload)?))
+}
+
+/// Serves a map hosted in this process to [`IpcClient`]s over a unix domain
+/// socket.
+pub struct IpcServer<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    listener: UnixListener,
+    map: Arc<UnboundedNapMap<K, V>>,
+}
+
+impl<K, V> IpcServer<K, V>
+where
+    K: Eq + Hash + Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static,
+    V: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    pub fn bind(path: impl AsRef<Path>, map: Arc<UnboundedNapMap<K, V>>) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        Ok(Self { listener, map })
+    }
+
+    /// Accepts connections until accepting fails, each one served on its own
+    /// task. Requests on a connection are answered in order, so a client
+    /// waiting on `get` blocks only its own connection.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn serve(self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            tracing::debug!("Accepted ipc connection");
+            let map = self.map.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle(map, stream).await {
+                    tracing::warn!("Ipc connection failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(
+        map: Arc<UnboundedNapMap<K, V>>,
+        mut stream: UnixStream,
+    ) -> Result<(), IpcError> {
+        while let Some(request) = read_frame::<Request<K, V>>(&mut stream).await? {
+            let response = match request {
+                Request::Insert(k, v) => {
+                    map.insert(k, v).await;
+                    Response::Inserted
+                }
+                Request::Get(k) => Response::Value(map.get(&k).await),
+                Request::Remove(k) => Response::Value(map.remove(&k).await),
+            };
+            write_frame(&mut stream, &response).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Client side of [`IpcServer`], one request in flight at a time.
+pub struct IpcClient<K, V> {
+    stream: UnixStream,
+    _marker: PhantomData<fn(K, V)>,
+}
+
+impl<K, V> IpcClient<K, V>
+where
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+{
+    pub async fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        let stream = UnixStream::connect(path).await?;
+        Ok(Self {
+            stream,
+            _marker: PhantomData,
+        })
+    }
+
+    pub async fn insert(&mut self, k: K, v: V) -> Result<(), IpcError> {
+        match self.call(Request::Insert(k, v)).await? {
+            Response::Inserted => Ok(()),
+            Response::Value(_) => Err(IpcError::Protocol),
+        }
+    }
+
+    /// Waits on the server until `k` is inserted, like
+    /// [`UnboundedNapMap::get`].
+    pub async fn get(&mut self, k: K) -> Result<Option<V>, IpcError> {
+        self.value(Request::Get(k)).await
+    }
+
+    pub async fn remove(&mut self, k: K) -> Result<Option<V>, IpcError> {
+        self.value(Request::Remove(k)).await
+    }
+
+    async fn value(&mut self, request: Request<K, V>) -> Result<Option<V>, IpcError> {
+        match self.call(request).await? {
+            Response::Value(v) => Ok(v),
+            Response::Inserted => Err(IpcError::Protocol),
+        }
+    }
+
+    async fn call(&mut self, request: Request<K, V>) -> Result<Response<V>, IpcError> {
+        write_frame(&mut self.stream, &request).await?;
+        read_frame(&mut self.stream)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpcClient;
+    use super::IpcServer;
+    use crate::unbounded::UnboundedNapMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn it_should_wait_on_a_remote_map() {
+        let path = std::env::temp_dir().join(format!("napmap-ipc-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let napmap = Arc::new(UnboundedNapMap::<String, u32>::new());
+        let server = IpcServer::bind(&path, napmap.clone()).unwrap();
+        tokio::spawn(server.serve());
+
+        let mut waiter = IpcClient::<String, u32>::connect(&path).await.unwrap();
+        let get = tokio::spawn(async move { waiter.get("key".to_string()).await.unwrap() });
+
+        let mut writer = IpcClient::connect(&path).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        writer.insert("key".to_string(), 7).await.unwrap();
+
+        assert_eq!(get.await.unwrap(), Some(7));
+        assert_eq!(napmap.get("key").await, Some(7));
+        assert_eq!(writer.remove("key".to_string()).await.unwrap(), Some(7));
+        let _ = std::fs::remove_file(&path);
+    }
+}
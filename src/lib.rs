@@ -1,8 +1,112 @@
+pub mod any;
+pub mod backend;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod blocking;
 #[doc = include_str!("../README.md")]
 pub mod bounded;
+pub mod changelog;
+mod dedup;
+pub mod dual;
+pub mod error;
+mod eviction;
+mod expiry;
+mod gauge;
+pub mod hash;
+mod hooks;
+pub mod intern;
+#[cfg(feature = "ipc")]
+pub mod ipc;
+mod loads;
+pub mod local;
+pub mod memo;
+pub mod multi;
+mod notifiers;
+mod producers;
+mod reentrant;
+mod room;
+pub mod sharded;
+pub mod shared;
+pub mod snapshot;
+mod stats;
+pub mod stream;
+mod sync;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod tiered;
+mod tombstones;
 pub mod unbounded;
+pub mod version;
+mod watermark;
+pub mod weak;
 
+pub use any::AnyValue;
+pub use backend::Backend;
+pub use backend::BackendError;
+pub use backend::InMemoryBackend;
+pub use backend::Mutation;
+pub use backend::OnBackendError;
+pub use backend::WriteBehind;
+pub use backend::WriteOrder;
+pub use backend::WriteThrough;
 pub use bounded::napmap;
+pub use bounded::napmap_with_policy;
+pub use bounded::EvictionPolicy;
+pub use bounded::Lane;
 pub use bounded::NapMap;
+pub use bounded::Permit;
+pub use changelog::Change;
+pub use changelog::ChangeStream;
+pub use changelog::Lagged;
+pub use dual::DualNapMap;
+pub use error::DowncastError;
+pub use error::EntryTooLarge;
+pub use error::GetError;
+pub use error::InsertError;
+pub use error::NapMapInternalError;
+pub use hash::BuildIdHasher;
+pub use hash::IdHasher;
+pub use hooks::HookPanic;
+pub use hooks::Operation;
+pub use hooks::RemovalCause;
+pub use intern::InternedNapMap;
+pub use intern::KeyId;
+#[cfg(feature = "ipc")]
+pub use ipc::IpcClient;
+#[cfg(feature = "ipc")]
+pub use ipc::IpcError;
+#[cfg(feature = "ipc")]
+pub use ipc::IpcServer;
+pub use local::LocalNapMap;
+pub use memo::Memo;
+pub use multi::NapMultiMap;
+#[cfg(feature = "macros")]
+pub use napmap_macros::napmemo;
+pub use notifiers::NotifierStats;
+pub use sharded::ShardedNapMap;
+pub use shared::Shared;
+pub use shared::SharedNapMap;
+pub use snapshot::MapSnapshot;
+#[cfg(feature = "serde")]
+pub use snapshot::SnapshotError;
+pub use stats::NapMapStats;
+pub use stream::NapMapExt;
+pub use tiered::AsyncSource;
+pub use tiered::MaxAge;
+pub use tiered::RefreshPolicy;
+pub use tiered::TieredNapMap;
+pub use tombstones::AfterRemove;
 pub use unbounded::unbounded;
+pub use unbounded::Entry;
+pub use unbounded::KeyUpdates;
+pub use unbounded::MapValues;
+pub use unbounded::Overlay;
+pub use unbounded::ParentNap;
 pub use unbounded::UnboundedNapMap;
+pub use version::EntryOrder;
+pub use version::SwapError;
+pub use version::Version;
+pub use version::VersionError;
+pub use watermark::Watermark;
+pub use watermark::Watermarks;
+pub use weak::WeakNapMap;
@@ -0,0 +1,58 @@
+use crate::error::lock;
+use crate::gauge::Entered;
+use crate::gauge::Gauge;
+use crate::sync::Notify;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Keys being loaded, with the `Notify` their coalesced waiters nap on.
+pub(crate) type Loads<K> = Arc<Mutex<HashMap<K, Arc<Notify>>>>;
+
+/// The `Notify` of the load of `k` in flight, if any.
+pub(crate) fn in_flight<K: Eq + Hash>(loads: &Loads<K>, k: &K) -> Option<Arc<Notify>> {
+    lock(loads).get(k).cloned()
+}
+
+/// Marks a key as being loaded. The marker is cleared even if the loading
+/// future is cancelled, so coalesced waiters never hang on an abandoned load.
+pub(crate) struct LoadGuard<K>
+where
+    K: Eq + Hash,
+{
+    loads: Loads<K>,
+    pub(crate) k: K,
+    notify: Arc<Notify>,
+    _loading: Entered,
+}
+
+impl<K> LoadGuard<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub(crate) fn acquire(loads: &Loads<K>, loading: &Arc<Gauge>, k: &K) -> Option<Self> {
+        let mut table = lock(loads);
+        if table.contains_key(k) {
+            return None;
+        }
+        let notify = Arc::new(Notify::new());
+        table.insert(k.clone(), notify.clone());
+        Some(Self {
+            loads: loads.clone(),
+            k: k.clone(),
+            notify,
+            _loading: loading.enter(),
+        })
+    }
+}
+
+impl<K> Drop for LoadGuard<K>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        lock(&self.loads).remove(&self.k);
+        self.notify.notify_waiters();
+    }
+}
@@ -0,0 +1,221 @@
+use crate::sync::Notify;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::rc::Rc;
+
+/// An unbounded napmap for a single thread, e.g. a current-thread runtime or
+/// a `LocalSet`. Nothing is locked, so neither keys nor values need to be
+/// `Send` or `Sync`, and the map itself is neither.
+///
+/// Clones share the entries and the waiters, like the other maps.
+pub struct LocalNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    map: Rc<RefCell<HashMap<K, V>>>,
+    notifiers: Rc<RefCell<HashMap<K, Rc<Notify>>>>,
+}
+
+// `Borrow` is spelled out, importing it would shadow `RefCell::borrow` on `Rc`
+impl<K, V> LocalNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    pub fn new() -> Self {
+        Self {
+            map: Rc::new(RefCell::new(HashMap::new())),
+            notifiers: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// Inserts right away and wakes the tasks napping on `k`.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub fn insert(&self, k: K, v: V) -> Option<V> {
+        tracing::trace!("Insert");
+        let old = self.map.borrow_mut().insert(k.clone(), v);
+        if let Some(notify) = self.notifiers.borrow_mut().remove(&k) {
+            tracing::trace!("Notifying waiters");
+            notify.notify_waiters();
+        }
+        old
+    }
+
+    /// Naps until `k` is available. Nothing closes or evicts the waiters of
+    /// this map, so the nap only ends with a value.
+    pub async fn get<Q>(&self, k: &Q) -> V
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        loop {
+            if let Some(v) = self.try_get(k) {
+                return v;
+            }
+            // Nothing runs between the lookup and the registration
+            let registration = self.register(k.to_owned());
+            tracing::trace!("Waiting...");
+            registration.notify().notified().await;
+        }
+    }
+
+    fn register(&self, k: K) -> Registration<K> {
+        let notify = self
+            .notifiers
+            .borrow_mut()
+            .entry(k.clone())
+            .or_default()
+            .clone();
+        Registration {
+            notifiers: self.notifiers.clone(),
+            k,
+            notify: Some(notify),
+        }
+    }
+
+    /// Returns the value right away, `None` if the key is absent rather than
+    /// napping on it.
+    pub fn try_get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.borrow().get(k).cloned()
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.borrow().contains_key(k)
+    }
+
+    pub fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.borrow_mut().remove(k)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.borrow().is_empty()
+    }
+}
+
+/// A task's hold on the notifier of a key. Dropping the last one drops the
+/// notifier too, so gets cancelled mid-nap don't leave it behind.
+struct Registration<K>
+where
+    K: Eq + Hash,
+{
+    notifiers: Rc<RefCell<HashMap<K, Rc<Notify>>>>,
+    k: K,
+    /// Only taken on drop.
+    notify: Option<Rc<Notify>>,
+}
+
+impl<K> Registration<K>
+where
+    K: Eq + Hash,
+{
+    fn notify(&self) -> &Notify {
+        self.notify.as_ref().expect("notify taken before drop")
+    }
+}
+
+impl<K> Drop for Registration<K>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        drop(self.notify.take());
+        let mut notifiers = self.notifiers.borrow_mut();
+        // An insert may have replaced or removed it meanwhile
+        if notifiers
+            .get(&self.k)
+            .is_some_and(|notify| Rc::strong_count(notify) == 1)
+        {
+            notifiers.remove(&self.k);
+        }
+    }
+}
+
+impl<K, V> Clone for LocalNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            notifiers: self.notifiers.clone(),
+        }
+    }
+}
+
+impl<K, V> Default for LocalNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Debug for LocalNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalNapMap")
+            .field("map", &self.map.borrow())
+            .field("napping_keys", &self.notifiers.borrow().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalNapMap;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tokio::task::LocalSet;
+
+    #[tokio::test]
+    async fn it_should_nap_on_values_that_are_not_send() {
+        let napmap: LocalNapMap<&str, Rc<u32>> = LocalNapMap::new();
+        LocalSet::new()
+            .run_until(async {
+                let waiter = tokio::task::spawn_local({
+                    let napmap = napmap.clone();
+                    async move { napmap.get(&"handle").await }
+                });
+                tokio::time::sleep(Duration::from_millis(10)).await;
+
+                assert_eq!(napmap.insert("handle", Rc::new(7)), None);
+                assert_eq!(*waiter.await.unwrap(), 7);
+                assert_eq!(napmap.remove(&"handle").as_deref(), Some(&7));
+                assert!(napmap.is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn it_should_drop_the_notifier_of_cancelled_gets() {
+        let napmap: LocalNapMap<&str, u32> = LocalNapMap::new();
+        let napped = tokio::time::timeout(Duration::from_millis(10), napmap.get(&"missing")).await;
+        assert!(napped.is_err());
+        assert_eq!(napmap.notifiers.borrow().len(), 0);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::error::lock;
+use crate::gauge::Gauge;
+use crate::loads::in_flight;
+use crate::loads::LoadGuard;
+use crate::loads::Loads;
+use crate::NapMap;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Memoized results of an async function, kept in a bounded `NapMap`.
+/// Backs the `#[napmemo]` attribute of the `macros` feature.
+///
+/// Concurrent calls with the same key are coalesced, the first one computes
+/// the result while the others nap until it lands in the map.
+pub struct Memo<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    map: NapMap<K, V>,
+    loads: Loads<K>,
+    loading: Arc<Gauge>,
+    ttl: Option<Duration>,
+}
+
+impl<K, V> Memo<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            map: NapMap::new(capacity),
+            loads: Arc::new(Mutex::new(HashMap::new())),
+            loading: Arc::new(Gauge::new()),
+            ttl: None,
+        }
+    }
+
+    /// Results older than `ttl` are computed again.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Returns the memoized result for `k`, awaiting `compute` only when there
+    /// is none and no other call is computing it already.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn get_or_compute(&self, k: K, compute: impl Future<Output = V>) -> V {
+        let guard = loop {
+            if let Some(v) = self.fresh(&k).await {
+                tracing::debug!("Memoized");
+                return v;
+            }
+            if let Some(guard) = LoadGuard::acquire(&self.loads, &self.loading, &k) {
+                break guard;
+            }
+            if let Some(notify) = in_flight(&self.loads, &k) {
+                let notified = notify.notified();
+                // The computation might have finished between the lookup and the registration
+                if lock(&self.loads).contains_key(&k) {
+                    tracing::trace!("Waiting for in-flight computation...");
+                    notified.await;
+                }
+            }
+        };
+
+        tracing::trace!("Computing");
+        let v = compute.await;
+        self.map.insert(guard.k.clone(), v.clone()).await;
+        v
+    }
+
+    async fn fresh(&self, k: &K) -> Option<V> {
+        let (v, age) = self.map.peek(k).await?;
+        match self.ttl {
+            Some(ttl) if age >= ttl => None,
+            _ => Some(v),
+        }
+    }
+}
+
+impl<K, V> Debug for Memo<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Memo")
+            .field("map", &self.map)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Memo;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_compute_once_until_the_ttl() {
+        let memo = Arc::new(Memo::new(10).with_ttl(Duration::from_secs(60)));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let call = |memo: Arc<Memo<u32, u32>>, calls: Arc<AtomicUsize>| async move {
+            memo.get_or_compute(1, async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                7
+            })
+            .await
+        };
+
+        let handles: Vec<_> = (0..5)
+            .map(|_| tokio::spawn(call(memo.clone(), calls.clone())))
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), 7);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(call(memo.clone(), calls.clone()).await, 7);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::version::Version;
+use crate::UnboundedNapMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// An unbounded napmap collecting any number of values per key, e.g. the
+/// responses of every shard a request fanned out to, gathered under its id.
+/// Every append wakes the tasks napping on the key.
+#[derive(Clone)]
+pub struct NapMultiMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    values: UnboundedNapMap<K, Vec<V>>,
+}
+
+impl<K, V> NapMultiMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    pub fn new() -> Self {
+        Self {
+            values: UnboundedNapMap::new(),
+        }
+    }
+
+    /// Adds `v` after the values of `k` appended so far.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub async fn append(&self, k: K, v: V) {
+        tracing::trace!("Append");
+        let mut v = Some(v);
+        let entry = self
+            .values
+            .entry(k)
+            .await
+            .and_modify(|values| values.extend(v.take()))
+            .await;
+        if let Some(v) = v {
+            entry.or_insert(vec![v]).await;
+        }
+    }
+
+    /// Naps until `k` has at least one value, then returns all of them.
+    pub async fn get_all(&self, k: &K) -> Option<Vec<V>> {
+        self.values.get(k).await
+    }
+
+    /// Naps until `k` has at least `n` values, e.g. a response from each of
+    /// `n` shards, then returns all of them.
+    pub async fn get_at_least(&self, k: K, n: usize) -> Vec<V> {
+        let mut seen = Version(0);
+        loop {
+            let (values, version) = self
+                .values
+                .changed_since(k.clone(), seen)
+                .await
+                .expect("the values map has no authorizer");
+            if values.len() >= n {
+                return values;
+            }
+            seen = version;
+        }
+    }
+
+    /// Returns the values of `k` right away, `None` if it has none yet.
+    pub async fn try_get_all(&self, k: &K) -> Option<Vec<V>> {
+        self.values.try_get(k).await
+    }
+
+    /// Removes and returns the values of `k`, in the order they were appended.
+    pub async fn drain(&self, k: &K) -> Vec<V> {
+        self.values.remove(k).await.unwrap_or_default()
+    }
+
+    /// Number of keys with at least one value.
+    pub async fn len(&self) -> usize {
+        self.values.len().await
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.values.is_empty().await
+    }
+}
+
+impl<K, V> Default for NapMultiMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Debug for NapMultiMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NapMultiMap")
+            .field("values", &self.values)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NapMultiMap;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn it_should_gather_values_under_one_key() {
+        let napmap = NapMultiMap::new();
+        let gathered = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get_at_least("request", 3).await }
+        });
+        for shard in 0..3 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            napmap.append("request", shard).await;
+        }
+
+        assert_eq!(gathered.await.unwrap(), [0, 1, 2]);
+        assert_eq!(napmap.get_all(&"request").await, Some(vec![0, 1, 2]));
+        assert_eq!(napmap.drain(&"request").await, [0, 1, 2]);
+        assert_eq!(napmap.try_get_all(&"request").await, None);
+    }
+}
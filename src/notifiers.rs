@@ -0,0 +1,287 @@
+use crate::error::GetError;
+use crate::sync::AsyncMutex;
+use crate::sync::MutexGuard;
+use crate::sync::Notify;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Size of a map's notifier table, i.e. of the keys that tasks napped on and
+/// that were not inserted since. Kept apart from the value map's size, as a
+/// flood of lookups for keys that never arrive only grows this table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotifierStats {
+    /// Keys currently holding a notifier.
+    pub keys: usize,
+    /// Most keys ever held at once.
+    pub peak: usize,
+}
+
+/// The per-key [`Nap`]s napping tasks wait on, hashed like the map they
+/// belong to.
+pub(crate) struct Notifiers<K, S = RandomState> {
+    table: AsyncMutex<HashMap<K, Arc<Nap>, S>>,
+    peak: AtomicUsize,
+    /// Keys allowed to hold a notifier through `try_register`, `usize::MAX`
+    /// for no limit.
+    limit: AtomicUsize,
+    /// Set when a [`Registration`] couldn't prune its notifier right away.
+    stale: AtomicBool,
+}
+
+impl<K> Notifiers<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub(crate) fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, S> Notifiers<K, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn with_hasher(hasher: S) -> Self {
+        Self {
+            table: AsyncMutex::new(HashMap::with_hasher(hasher)),
+            peak: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::MAX),
+            stale: AtomicBool::new(false),
+        }
+    }
+
+    /// Refuses [`try_register`](Table::try_register) on new keys once `limit`
+    /// keys hold a notifier. Set in place, the table stays shared by every
+    /// handle of the map.
+    pub(crate) fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub(crate) async fn lock(self: &Arc<Self>) -> Table<'_, K, S> {
+        let mut table = self.table.lock().await;
+        if self.stale.swap(false, Ordering::Relaxed) {
+            table.retain(|_, nap| Arc::strong_count(nap) > 1);
+        }
+        Table { table, owner: self }
+    }
+
+    pub(crate) async fn stats(&self) -> NotifierStats {
+        let keys = self.table.lock().await.len();
+        NotifierStats {
+            keys,
+            peak: self.peak.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<K, S> Debug for Notifiers<K, S>
+where
+    K: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Notifiers")
+            .field("table", &self.table)
+            .field("peak", &self.peak)
+            .field("limit", &self.limit)
+            .field("stale", &self.stale)
+            .finish()
+    }
+}
+
+pub(crate) struct Table<'a, K, S = RandomState> {
+    table: MutexGuard<'a, HashMap<K, Arc<Nap>, S>>,
+    owner: &'a Arc<Notifiers<K, S>>,
+}
+
+impl<K, S> Table<'_, K, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// The notifier of `k`, created if no task napped on it yet.
+    pub(crate) fn register(&mut self, k: &K) -> Registration<K, S> {
+        let nap = self.table.entry(k.clone()).or_default().clone();
+        self.owner
+            .peak
+            .fetch_max(self.table.len(), Ordering::Relaxed);
+        Registration {
+            notifiers: self.owner.clone(),
+            k: k.clone(),
+            nap: Some(nap),
+        }
+    }
+
+    /// Like [`register`](Self::register), but honors the limit of the table.
+    pub(crate) fn try_register(&mut self, k: &K) -> Result<Registration<K, S>, GetError> {
+        match self.owner.limit.load(Ordering::Relaxed) {
+            limit if self.table.len() >= limit && !self.table.contains_key(k) => {
+                Err(GetError::TooManyPendingKeys { limit })
+            }
+            _ => Ok(self.register(k)),
+        }
+    }
+
+    pub(crate) fn get(&self, k: &K) -> Option<Arc<Nap>> {
+        self.table.get(k).cloned()
+    }
+
+    pub(crate) fn remove(&mut self, k: &K) -> Option<Arc<Nap>> {
+        self.table.remove(k)
+    }
+
+    /// Whether a task holds the notifier of `k`, i.e. naps on it or is about to.
+    pub(crate) fn is_held(&self, k: &K) -> bool {
+        self.table
+            .get(k)
+            .is_some_and(|nap| Arc::strong_count(nap) > 1)
+    }
+
+    /// Keeps the keys for which `f` returns `true`, waking the tasks napping
+    /// on the others as evicted. Returns how many keys were evicted.
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&K) -> bool) -> usize {
+        let before = self.table.len();
+        self.table.retain(|k, nap| {
+            let keep = f(k);
+            if !keep {
+                nap.evict();
+            }
+            keep
+        });
+        before - self.table.len()
+    }
+}
+
+/// Drops the notifier of `k` once no task holds it anymore.
+fn prune<K: Eq + Hash, S: BuildHasher>(table: &mut HashMap<K, Arc<Nap>, S>, k: &K) {
+    if table.get(k).is_some_and(|nap| Arc::strong_count(nap) == 1) {
+        table.remove(k);
+    }
+}
+
+/// A task's hold on the notifier of a key. Dropping the last one drops the
+/// notifier too, so tasks cancelled mid-nap, e.g. by a `select!` or a
+/// timeout, don't leave it behind.
+pub(crate) struct Registration<K, S = RandomState>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    notifiers: Arc<Notifiers<K, S>>,
+    k: K,
+    /// Only taken on drop.
+    nap: Option<Arc<Nap>>,
+}
+
+impl<K, S> Deref for Registration<K, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    type Target = Nap;
+
+    fn deref(&self) -> &Nap {
+        self.nap.as_ref().expect("nap taken before drop")
+    }
+}
+
+impl<K, S> Drop for Registration<K, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        drop(self.nap.take());
+        match self.notifiers.table.try_lock() {
+            Ok(mut table) => prune(&mut table, &self.k),
+            // Swept by the next lock of the table instead
+            Err(_) => self.notifiers.stale.store(true, Ordering::Relaxed),
+        }
+    }
+}
+
+/// The `Notify` of one key, counting the tasks napping on it so an insert
+/// can wait for them to observe its value.
+#[derive(Debug)]
+pub(crate) struct Nap {
+    notify: Notify,
+    napping: AtomicUsize,
+    delivered: watch::Sender<usize>,
+    evicted: AtomicBool,
+}
+
+impl Default for Nap {
+    fn default() -> Self {
+        Self {
+            notify: Notify::new(),
+            napping: AtomicUsize::new(0),
+            delivered: watch::channel(0).0,
+            evicted: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Deref for Nap {
+    type Target = Notify;
+
+    fn deref(&self) -> &Notify {
+        &self.notify
+    }
+}
+
+impl Nap {
+    /// Counts the caller as napping until the returned guard is dropped.
+    pub(crate) fn enter(&self) -> Napping<'_> {
+        self.napping.fetch_add(1, Ordering::Relaxed);
+        Napping(self)
+    }
+
+    fn evict(&self) {
+        self.evicted.store(true, Ordering::Relaxed);
+        self.notify.notify_waiters();
+    }
+
+    /// Whether the napping tasks were woken by `retain_waiters` rather than
+    /// by an insert.
+    pub(crate) fn is_evicted(&self) -> bool {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn napping(&self) -> usize {
+        self.napping.load(Ordering::Relaxed)
+    }
+
+    /// Waits, at most `timeout`, for `napping` tasks to observe the value
+    /// they were woken for. Returns how many did.
+    pub(crate) async fn acked(&self, napping: usize, timeout: Duration) -> usize {
+        let mut delivered = self.delivered.subscribe();
+        let _ = tokio::time::timeout(timeout, delivered.wait_for(|d| *d >= napping)).await;
+        let delivered = *delivered.borrow();
+        delivered
+    }
+}
+
+pub(crate) struct Napping<'a>(&'a Nap);
+
+impl Napping<'_> {
+    /// The napping task observed the value it was woken for.
+    pub(crate) fn delivered(self) {
+        self.0.delivered.send_modify(|d| *d += 1);
+    }
+}
+
+impl Drop for Napping<'_> {
+    fn drop(&mut self) {
+        self.0.napping.fetch_sub(1, Ordering::Relaxed);
+    }
+}
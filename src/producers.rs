@@ -0,0 +1,105 @@
+use crate::error::lock;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use tokio::task::AbortHandle;
+
+/// Futures registered through `insert_from_future`, by the key their output
+/// goes to. They are aborted when their key is removed, when another one is
+/// registered for it, or once the map is dropped.
+#[derive(Debug)]
+pub(crate) struct Producers<K> {
+    running: Mutex<HashMap<K, (u64, AbortHandle)>>,
+    ids: AtomicU64,
+}
+
+impl<K> Producers<K>
+where
+    K: Eq + Hash,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            running: Mutex::new(HashMap::new()),
+            ids: AtomicU64::new(0),
+        }
+    }
+
+    /// Spawns the task `produce` builds from its id, replacing the producer
+    /// of `k` if any. The task reports back through [`finished`](Self::finished).
+    pub(crate) fn spawn<F, Fut>(&self, k: K, produce: F)
+    where
+        F: FnOnce(u64) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let id = self.ids.fetch_add(1, Ordering::Relaxed);
+        // Registered before the task can report back
+        let mut running = lock(&self.running);
+        let task = tokio::spawn(produce(id));
+        if let Some((_, old)) = running.insert(k, (id, task.abort_handle())) {
+            old.abort();
+        }
+    }
+
+    /// Forgets producer `id` of `k`, unless it was replaced meanwhile.
+    pub(crate) fn finished(&self, k: &K, id: u64) {
+        let mut running = lock(&self.running);
+        if running.get(k).is_some_and(|(current, _)| *current == id) {
+            running.remove(k);
+        }
+    }
+
+    /// Reports producer `id` of `k` finished once the returned guard is
+    /// dropped, so a producer that panics is forgotten too.
+    pub(crate) fn running<'a>(&'a self, k: &'a K, id: u64) -> Running<'a, K> {
+        Running {
+            producers: self,
+            k,
+            id,
+        }
+    }
+
+    /// Aborts the producer of `k`, if any.
+    pub(crate) fn cancel(&self, k: &K) {
+        if let Some((_, task)) = lock(&self.running).remove(k) {
+            task.abort();
+        }
+    }
+
+    pub(crate) fn contains(&self, k: &K) -> bool {
+        lock(&self.running).contains_key(k)
+    }
+}
+
+pub(crate) struct Running<'a, K>
+where
+    K: Eq + Hash,
+{
+    producers: &'a Producers<K>,
+    k: &'a K,
+    id: u64,
+}
+
+impl<K> Drop for Running<'_, K>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        self.producers.finished(self.k, self.id);
+    }
+}
+
+impl<K> Drop for Producers<K> {
+    fn drop(&mut self) {
+        let running = self
+            .running
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (_, (_, task)) in running.drain() {
+            task.abort();
+        }
+    }
+}
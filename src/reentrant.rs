@@ -0,0 +1,51 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+
+#[cfg(debug_assertions)]
+tokio::task_local! {
+    /// The `(map, key hash)` pairs the current task is producing. Debug
+    /// builds only, release builds skip the bookkeeping.
+    static PRODUCING: Vec<(usize, u64)>;
+}
+
+#[cfg(debug_assertions)]
+fn hashed(k: &impl Hash) -> u64 {
+    use std::hash::Hasher;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    k.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Runs `fut`, which is going to insert `k` into the map identified by
+/// `map`, marking the current task as its producer meanwhile.
+#[cfg(debug_assertions)]
+pub(crate) async fn producing<F: Future>(map: usize, k: &impl Hash, fut: F) -> F::Output {
+    let mut producing = PRODUCING.try_with(Vec::clone).unwrap_or_default();
+    producing.push((map, hashed(k)));
+    PRODUCING.scope(producing, fut).await
+}
+
+#[cfg(not(debug_assertions))]
+pub(crate) async fn producing<F: Future>(_map: usize, _k: &impl Hash, fut: F) -> F::Output {
+    fut.await
+}
+
+/// Panics if the current task is about to nap on `k` while producing it,
+/// e.g. a `get_or_compute` whose `compute` gets the same key: the nap would
+/// never wake, and a panic is easier to track down than a hang.
+#[cfg(debug_assertions)]
+#[track_caller]
+pub(crate) fn check_nap(map: usize, k: &(impl Hash + Debug)) {
+    let key = (map, hashed(k));
+    if PRODUCING.try_with(|p| p.contains(&key)).unwrap_or(false) {
+        panic!(
+            "napmap: a task napped on {k:?} while producing it; the value can only come from \
+             this task, so the nap would never wake"
+        );
+    }
+}
+
+#[cfg(not(debug_assertions))]
+#[inline]
+pub(crate) fn check_nap(_map: usize, _k: &(impl Hash + Debug)) {}
@@ -0,0 +1,82 @@
+use crate::error::lock;
+use crate::sync::Notify;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// The producers waiting for room in a full `NapMap`, served first come, first
+/// served. Only the producer at the front tries to take the room made, so a
+/// newcomer never takes it from one that waited longer. The queue is apart
+/// from the key notifiers: making room never wakes a get, and inserts wake
+/// their key waiters without going through it.
+#[derive(Debug, Default)]
+pub(crate) struct Room {
+    queue: Mutex<VecDeque<Arc<Notify>>>,
+}
+
+impl Room {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the caller until the returned [`Turn`] is dropped.
+    pub(crate) fn join(&self) -> Turn<'_> {
+        let notify = Arc::new(Notify::new());
+        lock(&self.queue).push_back(notify.clone());
+        Turn { room: self, notify }
+    }
+
+    /// Tells the producer at the front that room was made. The wakeup is kept
+    /// if it isn't waiting yet, so room made while it tries isn't missed.
+    pub(crate) fn made(&self) {
+        if let Some(first) = lock(&self.queue).front() {
+            first.notify_one();
+        }
+    }
+
+    /// Wakes every queued producer, e.g. for them to notice a close.
+    pub(crate) fn wake_all(&self) {
+        lock(&self.queue).iter().for_each(|n| n.notify_one());
+    }
+
+    /// Producers waiting for room.
+    pub(crate) fn depth(&self) -> usize {
+        lock(&self.queue).len()
+    }
+}
+
+/// A producer's place in the [`Room`] queue.
+pub(crate) struct Turn<'a> {
+    room: &'a Room,
+    notify: Arc<Notify>,
+}
+
+impl Turn<'_> {
+    /// Whether the producer is at the front, and may take the room made.
+    pub(crate) fn is_first(&self) -> bool {
+        lock(&self.room.queue)
+            .front()
+            .is_some_and(|first| Arc::ptr_eq(first, &self.notify))
+    }
+
+    /// Waits for room to be made or the queue to move up.
+    pub(crate) async fn wait(&self) {
+        self.notify.notified().await;
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut queue = lock(&self.room.queue);
+        let Some(at) = queue.iter().position(|n| Arc::ptr_eq(n, &self.notify)) else {
+            return;
+        };
+        queue.remove(at);
+        // More room may be left, whether the producer took some or gave up
+        if at == 0 {
+            if let Some(next) = queue.front() {
+                next.notify_one();
+            }
+        }
+    }
+}
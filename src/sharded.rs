@@ -0,0 +1,367 @@
+use crate::error::NapMapInternalError;
+use crate::sync::AsyncRwLock;
+use crate::UnboundedNapMap;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::hash::Hash;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::task::Poll;
+use tokio::sync::watch;
+
+/// An unbounded napmap split into shards keyed by hash, each with its own
+/// lock and notifier table, so writers and first-time waiters of keys in
+/// different shards don't contend.
+///
+/// Every operation touches a single shard, or two while the map is resized
+/// through [`resize`](Self::resize): the key's shard in the old layout and in
+/// the new one. There are no atomic operations spanning several keys.
+pub struct ShardedNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    layout: AsyncRwLock<Layout<K, V>>,
+    shard_count: AtomicUsize,
+    /// Bumped whenever the layout changes, so napping gets follow their key.
+    epoch: watch::Sender<u64>,
+    hasher: RandomState,
+}
+
+#[derive(Debug)]
+struct Layout<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    shards: Vec<UnboundedNapMap<K, V>>,
+    /// The shards being migrated from, until every entry left them.
+    old: Option<Vec<UnboundedNapMap<K, V>>>,
+}
+
+fn route<'a, K, V, Q>(
+    hasher: &RandomState,
+    shards: &'a [UnboundedNapMap<K, V>],
+    k: &Q,
+) -> &'a UnboundedNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    Q: Hash + ?Sized,
+{
+    &shards[hasher.hash_one(k) as usize % shards.len()]
+}
+
+impl<K, V> ShardedNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    /// Panics if `shards` is 0, see [`try_new`](Self::try_new).
+    pub fn new(shards: usize) -> Self {
+        match Self::try_new(shards) {
+            Ok(napmap) => napmap,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    pub fn try_new(shards: usize) -> Result<Self, NapMapInternalError> {
+        Self::try_with_shards(shards, UnboundedNapMap::new)
+    }
+
+    /// Builds every shard with `shard`, e.g. to configure them through the
+    /// builders of [`UnboundedNapMap`].
+    pub fn try_with_shards(
+        shards: usize,
+        shard: impl FnMut() -> UnboundedNapMap<K, V>,
+    ) -> Result<Self, NapMapInternalError> {
+        let shards = build(shards, shard)?;
+        Ok(Self {
+            shard_count: AtomicUsize::new(shards.len()),
+            layout: AsyncRwLock::new(Layout { shards, old: None }),
+            epoch: watch::Sender::new(0),
+            hasher: RandomState::new(),
+        })
+    }
+
+    /// The number of shards, the new one as soon as a resize starts.
+    pub fn shard_count(&self) -> usize {
+        self.shard_count.load(Ordering::Relaxed)
+    }
+
+    /// The shard holding `k`, a handle to it.
+    pub async fn shard<Q>(&self, k: &Q) -> UnboundedNapMap<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        let layout = self.layout.read().await;
+        route(&self.hasher, &layout.shards, k).clone()
+    }
+
+    pub async fn insert(&self, k: K, v: V) {
+        let layout = self.layout.read().await;
+        route(&self.hasher, &layout.shards, &k)
+            .insert(k.clone(), v)
+            .await;
+        if let Some(old) = &layout.old {
+            route(&self.hasher, old, &k).remove(&k).await;
+        }
+    }
+
+    /// Naps in the key's shard, following the key to its new shard if the
+    /// map is resized meanwhile.
+    pub async fn get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        loop {
+            let layout = self.layout.read().await;
+            // Subscribed under the layout, so no change after the read is missed
+            let mut epoch = self.epoch.subscribe();
+            if let Some(old) = &layout.old {
+                if let Some(v) = route(&self.hasher, old, k).try_get(k).await {
+                    return Some(v);
+                }
+            }
+            let shard = route(&self.hasher, &layout.shards, k).clone();
+            drop(layout);
+
+            let mut nap = std::pin::pin!(shard.get(k));
+            let mut moved = std::pin::pin!(epoch.changed());
+            let napped = std::future::poll_fn(|cx| {
+                if let Poll::Ready(v) = nap.as_mut().poll(cx) {
+                    return Poll::Ready(Some(v));
+                }
+                moved.as_mut().poll(cx).map(|_| None)
+            })
+            .await;
+            if let Some(v) = napped {
+                return v;
+            }
+            tracing::trace!("Resized, napping on the new shard");
+        }
+    }
+
+    /// Returns the value right away, `None` if the key is absent rather than
+    /// napping on it.
+    pub async fn try_get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let layout = self.layout.read().await;
+        if let Some(old) = &layout.old {
+            if let Some(v) = route(&self.hasher, old, k).try_get(k).await {
+                return Some(v);
+            }
+        }
+        route(&self.hasher, &layout.shards, k).try_get(k).await
+    }
+
+    pub async fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let layout = self.layout.read().await;
+        if let Some(old) = &layout.old {
+            if route(&self.hasher, old, k).contains_key(k).await {
+                return true;
+            }
+        }
+        route(&self.hasher, &layout.shards, k).contains_key(k).await
+    }
+
+    pub async fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let layout = self.layout.read().await;
+        let v = route(&self.hasher, &layout.shards, k).remove(k).await;
+        match &layout.old {
+            Some(old) => v.or(route(&self.hasher, old, k).remove(k).await),
+            None => v,
+        }
+    }
+
+    /// Sums the shards one after the other, not a consistent snapshot.
+    pub async fn len(&self) -> usize {
+        let layout = self.layout.read().await;
+        let mut len = 0;
+        for shard in layout.shards.iter().chain(layout.old.iter().flatten()) {
+            len += shard.len().await;
+        }
+        len
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Starts moving the map to `shards` new shards, see
+    /// [`resize_with`](Self::resize_with).
+    pub async fn resize(&self, shards: usize) -> Result<(), NapMapInternalError> {
+        self.resize_with(shards, UnboundedNapMap::new).await
+    }
+
+    /// Starts moving the map to `shards` new shards built with `shard`. The
+    /// entries are moved in steps of [`migrate`](Self::migrate), meanwhile
+    /// the map stays usable: lookups check the old shard of a key then its
+    /// new one, writes go to the new one, and gets napping on a key move to
+    /// its new shard. Moved entries are inserted anew, so their TTL starts
+    /// over. Fails while a resize is still migrating.
+    pub async fn resize_with(
+        &self,
+        shards: usize,
+        shard: impl FnMut() -> UnboundedNapMap<K, V>,
+    ) -> Result<(), NapMapInternalError> {
+        let shards = build(shards, shard)?;
+        let mut layout = self.layout.write().await;
+        if layout.old.is_some() {
+            return Err(NapMapInternalError::InvalidConfig(
+                "sharded napmap is still migrating",
+            ));
+        }
+        self.shard_count.store(shards.len(), Ordering::Relaxed);
+        layout.old = Some(std::mem::replace(&mut layout.shards, shards));
+        self.epoch.send_modify(|epoch| *epoch += 1);
+        tracing::debug!("Resizing to {} shards", self.shard_count());
+        Ok(())
+    }
+
+    /// Moves up to `batch` entries of a resize to their new shard, holding
+    /// off the map's writers meanwhile. Returns `true` once every entry
+    /// moved, right away if no resize is migrating.
+    pub async fn migrate(&self, batch: usize) -> bool {
+        let batch = batch.max(1);
+        let mut layout = self.layout.write().await;
+        let Some(old) = &layout.old else {
+            return true;
+        };
+        let mut moved = 0;
+        for from in old {
+            let entries = from.some_entries(batch - moved).await;
+            moved += entries.len();
+            for (k, v) in entries {
+                // Expired entries are dropped rather than moved
+                if let Some(v) = v {
+                    route(&self.hasher, &layout.shards, &k)
+                        .insert(k.clone(), v)
+                        .await;
+                }
+                from.remove(&k).await;
+            }
+            if moved == batch {
+                return false;
+            }
+        }
+        layout.old = None;
+        self.epoch.send_modify(|epoch| *epoch += 1);
+        tracing::debug!("Resized");
+        true
+    }
+
+    /// Whether a resize still has entries to move.
+    pub async fn is_migrating(&self) -> bool {
+        self.layout.read().await.old.is_some()
+    }
+}
+
+fn build<K, V>(
+    shards: usize,
+    shard: impl FnMut() -> UnboundedNapMap<K, V>,
+) -> Result<Vec<UnboundedNapMap<K, V>>, NapMapInternalError>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    if shards == 0 {
+        return Err(NapMapInternalError::InvalidConfig(
+            "sharded napmap requires shards > 0",
+        ));
+    }
+    Ok(std::iter::repeat_with(shard).take(shards).collect())
+}
+
+impl<K, V> Debug for ShardedNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedNapMap")
+            .field("layout", &self.layout)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShardedNapMap;
+    use crate::error::NapMapInternalError;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn it_should_route_keys_to_their_shard() {
+        let napmap = Arc::new(ShardedNapMap::new(4));
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&7).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        for k in 0..16 {
+            napmap.insert(k, k * 10).await;
+        }
+        assert_eq!(waiter.await.unwrap(), Some(70));
+        assert_eq!(napmap.len().await, 16);
+        assert_eq!(napmap.shard(&3).await.try_get(&3).await, Some(30));
+        assert_eq!(napmap.remove(&3).await, Some(30));
+        assert!(!napmap.contains_key(&3).await);
+    }
+
+    #[tokio::test]
+    async fn it_should_move_entries_and_waiters_on_resize() {
+        let napmap = Arc::new(ShardedNapMap::new(2));
+        for k in 0..10 {
+            napmap.insert(k, k * 10).await;
+        }
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&100).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        napmap.resize(8).await.unwrap();
+        assert_eq!(napmap.shard_count(), 8);
+        assert!(napmap.resize(4).await.is_err());
+        assert!(!napmap.migrate(4).await);
+        for k in 0..10 {
+            assert_eq!(napmap.try_get(&k).await, Some(k * 10));
+        }
+        napmap.insert(3, 33).await;
+        assert_eq!(napmap.remove(&4).await, Some(40));
+        while !napmap.migrate(4).await {}
+
+        assert!(!napmap.is_migrating().await);
+        assert_eq!(napmap.len().await, 9);
+        assert_eq!(napmap.shard(&3).await.try_get(&3).await, Some(33));
+        napmap.insert(100, 1).await;
+        assert_eq!(waiter.await.unwrap(), Some(1));
+    }
+
+    #[test]
+    fn it_should_refuse_zero_shards() {
+        assert!(matches!(
+            ShardedNapMap::<u8, u8>::try_new(0),
+            Err(NapMapInternalError::InvalidConfig(_))
+        ));
+    }
+}
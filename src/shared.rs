@@ -0,0 +1,147 @@
+use crate::UnboundedNapMap;
+use std::borrow::Borrow;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// A value shared behind an `Arc`, cloneable and debuggable whatever `V` is.
+pub struct Shared<V: ?Sized>(pub Arc<V>);
+
+impl<V: ?Sized> Clone for Shared<V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<V: ?Sized> Debug for Shared<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Shared")
+            .field(&std::any::type_name::<V>())
+            .finish()
+    }
+}
+
+/// An unbounded napmap storing its values as `Arc<V>`, so values need to be
+/// neither `Clone` nor `Debug`, and unsized ones like `str` work too. A `get`
+/// hands out another reference to the stored value.
+pub struct SharedNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: ?Sized,
+{
+    map: UnboundedNapMap<K, Shared<V>>,
+}
+
+impl<K, V> SharedNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: ?Sized,
+{
+    pub fn new() -> Self {
+        Self::with_map(UnboundedNapMap::new())
+    }
+
+    /// Shares the values of `map`, once configured through its own builders.
+    pub fn with_map(map: UnboundedNapMap<K, Shared<V>>) -> Self {
+        Self { map }
+    }
+
+    /// The underlying map.
+    pub fn inner(&self) -> &UnboundedNapMap<K, Shared<V>> {
+        &self.map
+    }
+
+    pub async fn insert(&self, k: K, v: impl Into<Arc<V>>) {
+        self.map.insert(k, Shared(v.into())).await;
+    }
+
+    pub async fn get<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.map.get(k).await.map(|v| v.0)
+    }
+
+    /// Returns the value right away, `None` if the key is absent rather than
+    /// napping on it.
+    pub async fn try_get<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.try_get(k).await.map(|v| v.0)
+    }
+
+    pub async fn remove<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.map.remove(k).await.map(|v| v.0)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.map.len().await
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.map.is_empty().await
+    }
+}
+
+impl<K, V> Default for SharedNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: ?Sized,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Debug for SharedNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: ?Sized,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedNapMap")
+            .field("map", &self.map)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedNapMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Neither Clone nor Debug
+    struct Payload(Vec<u8>);
+
+    #[tokio::test]
+    async fn it_should_share_unclonable_values() {
+        let napmap = Arc::new(SharedNapMap::new());
+        tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                napmap.insert("key", Payload(vec![7])).await;
+            }
+        });
+
+        let first = napmap.get(&"key").await.unwrap();
+        let second = napmap.try_get(&"key").await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.0, [7]);
+    }
+
+    #[tokio::test]
+    async fn it_should_store_unsized_values() {
+        let napmap: SharedNapMap<&str, str> = SharedNapMap::new();
+        napmap.insert("key", "value").await;
+        assert_eq!(napmap.get(&"key").await.as_deref(), Some("value"));
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "serde")]
+use std::error::Error;
+#[cfg(feature = "serde")]
+use std::fmt::Display;
+
+/// The live entries of a map at one point in time, oldest write first, e.g.
+/// to warm-start a cache after a restart. See `to_snapshot` and
+/// `restore_snapshot` on either map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MapSnapshot<K, V> {
+    pub entries: Vec<(K, V)>,
+}
+
+#[cfg(feature = "serde")]
+impl<K, V> MapSnapshot<K, V>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    /// Writes the snapshot to `writer` as bincode.
+    pub fn save_to_writer(&self, writer: impl std::io::Write) -> Result<(), SnapshotError> {
+        Ok(bincode::serialize_into(writer, self)?)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, V> MapSnapshot<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    /// Reads back a snapshot written by [`save_to_writer`](Self::save_to_writer).
+    pub fn load_from_reader(reader: impl std::io::Read) -> Result<Self, SnapshotError> {
+        Ok(bincode::deserialize_from(reader)?)
+    }
+}
+
+/// A snapshot couldn't be written or read back.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct SnapshotError(pub bincode::Error);
+
+#[cfg(feature = "serde")]
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "snapshot codec error: {}", self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<bincode::Error> for SnapshotError {
+    fn from(e: bincode::Error) -> Self {
+        SnapshotError(e)
+    }
+}
@@ -0,0 +1,131 @@
+use crate::gauge::Gauge;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+#[cfg(feature = "metrics")]
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Activity of a map since it was built, see `stats` on either map.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NapMapStats {
+    /// Lookups answered from the map without napping.
+    pub hits: u64,
+    /// Lookups that found no entry, whether they napped on it or not.
+    pub misses: u64,
+    /// Entries written, new or overwritten.
+    pub inserts: u64,
+    /// Entries removed on request, evictions and expiries aside.
+    pub removals: u64,
+    /// Tasks napping right now.
+    pub napping: usize,
+    /// Naps that ended with a wakeup.
+    pub naps: u64,
+    /// Time those naps took overall.
+    pub nap_time: Duration,
+    /// Longest nap so far.
+    pub max_nap: Duration,
+    /// Producers waiting for room in a full [`NapMap`](crate::NapMap), through
+    /// `reserve` or `insert_timeout`. Always zero for the unbounded map.
+    pub waiting_for_room: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    removals: AtomicU64,
+    naps: AtomicU64,
+    nap_nanos: AtomicU64,
+    max_nap_nanos: AtomicU64,
+    // Labels the exported metrics as `map`, see `with_span_name`
+    #[cfg(feature = "metrics")]
+    name: RwLock<Option<String>>,
+}
+
+/// Every counter is also exported through the `metrics` facade under the
+/// `metrics` feature, as `napmap_hits_total`, `napmap_misses_total`,
+/// `napmap_inserts_total` and `napmap_removals_total`, next to the
+/// `napmap_napping` gauge and the `napmap_nap_seconds` histogram.
+impl Counters {
+    pub(crate) fn lookup(&self, hit: bool) {
+        match hit {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        #[cfg(feature = "metrics")]
+        match hit {
+            true => metrics::counter!("napmap_hits_total", self.labels()).increment(1),
+            false => metrics::counter!("napmap_misses_total", self.labels()).increment(1),
+        }
+    }
+
+    pub(crate) fn insert(&self) {
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("napmap_inserts_total", self.labels()).increment(1);
+    }
+
+    pub(crate) fn removal(&self) {
+        self.removals.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("napmap_removals_total", self.labels()).increment(1);
+    }
+
+    pub(crate) fn napped(&self, took: Duration) {
+        let nanos = u64::try_from(took.as_nanos()).unwrap_or(u64::MAX);
+        self.naps.fetch_add(1, Ordering::Relaxed);
+        self.nap_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nap_nanos.fetch_max(nanos, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("napmap_nap_seconds", self.labels()).record(took.as_secs_f64());
+    }
+
+    /// The gauge counting the tasks napping on the map, exported as
+    /// `napmap_napping` under the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn napping(self: &Arc<Self>) -> Gauge {
+        // Weak, so the map's handles stay the only owners of its counters
+        let counters = Arc::downgrade(self);
+        Gauge::observed(move |n| {
+            if let Some(counters) = counters.upgrade() {
+                metrics::gauge!("napmap_napping", counters.labels()).set(n as f64);
+            }
+        })
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    pub(crate) fn napping(&self) -> Gauge {
+        Gauge::new()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn name(&self, name: &str) {
+        let mut current = self.name.write().unwrap_or_else(|e| e.into_inner());
+        *current = Some(name.to_owned());
+    }
+
+    #[cfg(feature = "metrics")]
+    fn labels(&self) -> Vec<metrics::Label> {
+        let name = self.name.read().unwrap_or_else(|e| e.into_inner());
+        name.iter()
+            .map(|name| metrics::Label::new("map", name.clone()))
+            .collect()
+    }
+
+    pub(crate) fn stats(&self, napping: usize, waiting_for_room: usize) -> NapMapStats {
+        NapMapStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            removals: self.removals.load(Ordering::Relaxed),
+            napping,
+            naps: self.naps.load(Ordering::Relaxed),
+            nap_time: Duration::from_nanos(self.nap_nanos.load(Ordering::Relaxed)),
+            max_nap: Duration::from_nanos(self.max_nap_nanos.load(Ordering::Relaxed)),
+            waiting_for_room,
+        }
+    }
+}
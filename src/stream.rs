@@ -0,0 +1,164 @@
+use futures_core::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::time::Sleep;
+
+/// Adapters for the streams of this crate, like
+/// [`ChangeStream`](crate::ChangeStream), usable on any [`Stream`].
+pub trait NapMapExt: Stream + Sized {
+    /// Groups the items that are ready into batches of at most `n`. A batch
+    /// is yielded as soon as the stream has nothing more ready, so items are
+    /// never held back waiting for a full batch.
+    fn batched(self, n: usize) -> Batched<Self> {
+        assert!(n > 0, "batched requires n > 0");
+        Batched {
+            inner: self,
+            n,
+            done: false,
+        }
+    }
+
+    /// Yields an item only once `period` passed without a newer one, which
+    /// then replaces it. The last item is flushed when the stream ends.
+    fn debounce(self, period: Duration) -> Debounce<Self> {
+        Debounce {
+            inner: self,
+            period,
+            latest: None,
+            timer: Box::pin(tokio::time::sleep(period)),
+            done: false,
+        }
+    }
+}
+
+impl<S: Stream> NapMapExt for S {}
+
+/// See [`NapMapExt::batched`].
+pub struct Batched<S> {
+    inner: S,
+    n: usize,
+    done: bool,
+}
+
+impl<S> Stream for Batched<S>
+where
+    S: Stream + Unpin,
+{
+    type Item = Vec<S::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut batch = Vec::new();
+        while !this.done && batch.len() < this.n {
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(item)) => batch.push(item),
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending if batch.is_empty() => return Poll::Pending,
+                Poll::Pending => break,
+            }
+        }
+        match batch.is_empty() {
+            true => Poll::Ready(None),
+            false => Poll::Ready(Some(batch)),
+        }
+    }
+}
+
+/// See [`NapMapExt::debounce`].
+pub struct Debounce<S: Stream> {
+    inner: S,
+    period: Duration,
+    latest: Option<S::Item>,
+    timer: Pin<Box<Sleep>>,
+    done: bool,
+}
+
+impl<S> Stream for Debounce<S>
+where
+    S: Stream + Unpin,
+    S::Item: Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        while !this.done {
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    this.latest = Some(item);
+                    let deadline = tokio::time::Instant::now() + this.period;
+                    this.timer.as_mut().reset(deadline);
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+        if this.done {
+            return Poll::Ready(this.latest.take());
+        }
+        if this.latest.is_some() && this.timer.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(this.latest.take());
+        }
+        Poll::Pending
+    }
+}
+
+/// Stream over items collected up front, see
+/// [`UnboundedNapMap::keys_stream`](crate::UnboundedNapMap::keys_stream).
+pub struct Iter<I>(pub(crate) I);
+
+impl<I> Stream for Iter<I>
+where
+    I: Iterator + Unpin,
+{
+    type Item = I::Item;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.get_mut().0.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NapMapExt;
+    use crate::changelog::Change;
+    use crate::UnboundedNapMap;
+    use futures_core::Stream;
+    use std::pin::Pin;
+    use std::time::Duration;
+
+    async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+        std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn it_should_batch_ready_items() {
+        let napmap = UnboundedNapMap::new();
+        napmap
+            .insert_batch_atomic([(1, 1), (2, 2), (3, 3)])
+            .await
+            .unwrap();
+
+        let mut keys = napmap.keys_stream().await.batched(2);
+        let mut all = next(&mut keys).await.unwrap();
+        assert_eq!(all.len(), 2);
+        all.extend(next(&mut keys).await.unwrap());
+        all.sort();
+        assert_eq!(all, vec![1, 2, 3]);
+        assert_eq!(next(&mut keys).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_debounce_bursts() {
+        let napmap = UnboundedNapMap::new().with_changelog(8);
+        let mut changes = napmap.subscribe().unwrap().debounce(Duration::from_secs(1));
+        napmap.insert("key", 1).await;
+        napmap.insert("key", 2).await;
+
+        let change = next(&mut changes).await.unwrap().unwrap();
+        assert!(matches!(change, Change::Insert { value: 2, .. }));
+    }
+}
@@ -0,0 +1,8 @@
+// The async locks and notifications the maps nap and write through, kept
+// in one place so they can be swapped for another executor's. Channels and
+// timers still come from tokio directly.
+pub(crate) use tokio::sync::Mutex as AsyncMutex;
+pub(crate) use tokio::sync::MutexGuard;
+pub(crate) use tokio::sync::Notify;
+pub(crate) use tokio::sync::RwLock as AsyncRwLock;
+pub(crate) use tokio::sync::RwLockWriteGuard;
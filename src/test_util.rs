@@ -0,0 +1,235 @@
+use crate::unbounded::UnboundedNapMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// Polls `fut` once and panics if it resolved, e.g. to check that a `get`
+/// naps on a missing key. The future can be awaited afterwards.
+pub async fn assert_naps<F>(mut fut: Pin<&mut F>)
+where
+    F: Future,
+    F::Output: Debug,
+{
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(v) = fut.as_mut().poll(cx) {
+            panic!("expected the future to nap, it resolved to {v:?}");
+        }
+        Poll::Ready(())
+    })
+    .await;
+}
+
+/// Awaits `fut` while the paused clock auto-advances, and returns its output
+/// along with the virtual time it napped for. Panics if it still naps after
+/// `limit`, instead of hanging the test.
+///
+/// Requires a paused clock, e.g. `#[tokio::test(start_paused = true)]`.
+pub async fn advance_until_woken<F: Future>(fut: F, limit: Duration) -> (F::Output, Duration) {
+    let start = Instant::now();
+    match tokio::time::timeout(limit, fut).await {
+        Ok(output) => (output, start.elapsed()),
+        Err(_) => panic!("still napping after {limit:?}"),
+    }
+}
+
+/// One operation of a [`Scenario`].
+#[derive(Debug, Clone)]
+pub enum Step<K, V> {
+    Insert(K, V),
+    Get(K),
+    Remove(K),
+}
+
+/// What a step returned and when it completed, relative to the start of the
+/// scenario. Inserts complete with `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome<V> {
+    pub step: usize,
+    pub at: Duration,
+    pub value: Option<V>,
+}
+
+/// A script of timed operations against a map, for testing protocols built on
+/// napmap deterministically.
+///
+/// Run it under a paused clock, e.g. `#[tokio::test(start_paused = true)]`.
+/// Steps start in time order, steps sharing a time start in the order they
+/// were added, and every `get` is napping before the next step starts.
+pub struct Scenario<K, V> {
+    steps: Vec<(Duration, Step<K, V>)>,
+    horizon: Duration,
+}
+
+impl<K, V> Scenario<K, V>
+where
+    K: Eq + Hash + Clone + Debug + Send + Sync + 'static,
+    V: Clone + Debug + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            steps: Vec::new(),
+            horizon: Duration::from_secs(60),
+        }
+    }
+
+    pub fn step(mut self, at: Duration, step: Step<K, V>) -> Self {
+        self.steps.push((at, step));
+        self
+    }
+
+    pub fn insert(self, at: Duration, k: K, v: V) -> Self {
+        self.step(at, Step::Insert(k, v))
+    }
+
+    pub fn get(self, at: Duration, k: K) -> Self {
+        self.step(at, Step::Get(k))
+    }
+
+    pub fn remove(self, at: Duration, k: K) -> Self {
+        self.step(at, Step::Remove(k))
+    }
+
+    /// Gets still napping this long after the start are left out of the
+    /// outcomes. Defaults to a minute.
+    pub fn horizon(mut self, horizon: Duration) -> Self {
+        self.horizon = horizon;
+        self
+    }
+
+    /// Runs the steps against `map` and returns their outcomes ordered by
+    /// completion time, then by step.
+    pub async fn run(self, map: Arc<UnboundedNapMap<K, V>>) -> Vec<Outcome<V>> {
+        let start = Instant::now();
+        let mut steps: Vec<_> = self.steps.into_iter().enumerate().collect();
+        steps.sort_by_key(|(i, (at, _))| (*at, *i));
+
+        let mut outcomes = Vec::new();
+        let mut gets = JoinSet::new();
+        for (i, (at, step)) in steps {
+            tokio::time::sleep_until(start + at).await;
+            match step {
+                Step::Insert(k, v) => {
+                    map.insert(k, v).await;
+                    outcomes.push(Outcome {
+                        step: i,
+                        at: start.elapsed(),
+                        value: None,
+                    });
+                }
+                Step::Remove(k) => {
+                    let value = map.remove(&k).await;
+                    outcomes.push(Outcome {
+                        step: i,
+                        at: start.elapsed(),
+                        value,
+                    });
+                }
+                Step::Get(k) => {
+                    let map = map.clone();
+                    gets.spawn(async move {
+                        let value = map.get(&k).await;
+                        Outcome {
+                            step: i,
+                            at: start.elapsed(),
+                            value,
+                        }
+                    });
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+
+        let deadline = start + self.horizon;
+        while let Ok(Some(outcome)) = tokio::time::timeout_at(deadline, gets.join_next()).await {
+            outcomes.push(outcome.expect("scenario get panicked"));
+        }
+        gets.abort_all();
+        outcomes.sort_by_key(|o| (o.at, o.step));
+        outcomes
+    }
+}
+
+impl<K, V> Default for Scenario<K, V>
+where
+    K: Eq + Hash + Clone + Debug + Send + Sync + 'static,
+    V: Clone + Debug + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::advance_until_woken;
+    use super::assert_naps;
+    use super::Outcome;
+    use super::Scenario;
+    use crate::unbounded::UnboundedNapMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_replay_a_scenario_deterministically() {
+        let secs = Duration::from_secs;
+        let outcomes = Scenario::new()
+            .get(secs(0), "key")
+            .insert(secs(2), "key", 1)
+            .get(secs(3), "key")
+            .remove(secs(3), "key")
+            .get(secs(4), "never")
+            .horizon(secs(10))
+            .run(Arc::new(UnboundedNapMap::new()))
+            .await;
+
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome {
+                    step: 0,
+                    at: secs(2),
+                    value: Some(1)
+                },
+                Outcome {
+                    step: 1,
+                    at: secs(2),
+                    value: None
+                },
+                Outcome {
+                    step: 2,
+                    at: secs(3),
+                    value: Some(1)
+                },
+                Outcome {
+                    step: 3,
+                    at: secs(3),
+                    value: Some(1)
+                },
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_measure_naps_in_virtual_time() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let get = napmap.get(&"key");
+        tokio::pin!(get);
+        assert_naps(get.as_mut()).await;
+
+        tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                napmap.insert("key", 1).await;
+            }
+        });
+        let (value, napped) = advance_until_woken(get, Duration::from_secs(60)).await;
+        assert_eq!((value, napped), (Some(1), Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::error::lock;
+use crate::gauge::Gauge;
+use crate::loads::in_flight;
+use crate::loads::LoadGuard;
+use crate::loads::Loads;
+use crate::NapMap;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// A slower, second-tier source (disk, network, ...) consulted when a key is
+/// missing from the in-memory map.
+///
+/// Returning `None` means the source doesn't know the key either, in which
+/// case the caller naps on the in-memory map as usual.
+pub trait AsyncSource<K, V>: Send + Sync {
+    fn fetch(&self, k: &K) -> impl Future<Output = Option<V>> + Send;
+}
+
+/// Decides, on every L1 hit, whether the entry should be refreshed from the
+/// source in the background. The caller gets the current value either way.
+pub trait RefreshPolicy<K>: Send + Sync {
+    /// `age` is the time since the value was last inserted into the L1.
+    fn should_refresh(&self, k: &K, age: Duration) -> bool;
+}
+
+impl<K, F> RefreshPolicy<K> for F
+where
+    F: Fn(&K, Duration) -> bool + Send + Sync,
+{
+    fn should_refresh(&self, k: &K, age: Duration) -> bool {
+        self(k, age)
+    }
+}
+
+/// Refreshes entries once they are older than the given duration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxAge(pub Duration);
+
+impl<K> RefreshPolicy<K> for MaxAge {
+    fn should_refresh(&self, _k: &K, age: Duration) -> bool {
+        age >= self.0
+    }
+}
+
+type Refresher<K> = Box<dyn Fn(&K, Duration) + Send + Sync>;
+
+/// Caps how many loads run at once, see
+/// [`with_max_concurrent_loads`](TieredNapMap::with_max_concurrent_loads).
+struct Limiter {
+    permits: OnceLock<Semaphore>,
+    queued: Arc<Gauge>,
+}
+
+/// A bounded `NapMap` acting as an L1 cache in front of an [`AsyncSource`].
+///
+/// Concurrent misses for the same key are coalesced, so the source is asked
+/// once while every other requester naps until the result lands in the map.
+pub struct TieredNapMap<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: AsyncSource<K, V>,
+{
+    l1: NapMap<K, V>,
+    source: Arc<S>,
+    loads: Loads<K>,
+    loading: Arc<Gauge>,
+    refresh: Option<Refresher<K>>,
+    loaders: Arc<Mutex<JoinSet<()>>>,
+    limiter: Arc<Limiter>,
+}
+
+impl<K, V, S> TieredNapMap<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: AsyncSource<K, V>,
+{
+    pub fn new(l1: NapMap<K, V>, source: S) -> Self {
+        Self {
+            l1,
+            source: Arc::new(source),
+            loads: Arc::new(Mutex::new(HashMap::new())),
+            loading: Arc::new(Gauge::new()),
+            refresh: None,
+            loaders: Arc::new(Mutex::new(JoinSet::new())),
+            limiter: Arc::new(Limiter {
+                permits: OnceLock::new(),
+                queued: Arc::new(Gauge::new()),
+            }),
+        }
+    }
+
+    /// Runs at most `limit` loads from the source at once, background
+    /// refreshes included, queueing the others. Only the first call applies.
+    pub fn with_max_concurrent_loads(self, limit: usize) -> Self {
+        let _ = self.limiter.permits.set(Semaphore::new(limit));
+        self
+    }
+
+    /// Consults `policy` on every L1 hit and reloads the entry from the source
+    /// on a background task when it asks to. A key is never loaded twice at
+    /// the same time, so a refresh is skipped while a load is in flight.
+    ///
+    /// Refreshes run on tasks owned by the map, aborted once it is dropped,
+    /// see [`abort_all_loaders`](Self::abort_all_loaders).
+    ///
+    /// Must be used from within a tokio runtime.
+    pub fn with_refresh(mut self, policy: impl RefreshPolicy<K> + 'static) -> Self
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: 'static,
+    {
+        let l1 = self.l1.clone();
+        let source = self.source.clone();
+        let loads = self.loads.clone();
+        let loading = self.loading.clone();
+        let loaders = self.loaders.clone();
+        let limiter = self.limiter.clone();
+        self.refresh = Some(Box::new(move |k, age| {
+            if !policy.should_refresh(k, age) {
+                return;
+            }
+            let Some(guard) = LoadGuard::acquire(&loads, &loading, k) else {
+                return;
+            };
+            tracing::trace!("Refreshing in the background");
+            let l1 = l1.clone();
+            let source = source.clone();
+            let limiter = limiter.clone();
+            let mut loaders = lock(&loaders);
+            while loaders.try_join_next().is_some() {}
+            loaders.spawn(async move {
+                load(&l1, source.as_ref(), &limiter, guard).await;
+            });
+        }));
+        self
+    }
+
+    pub fn l1(&self) -> &NapMap<K, V> {
+        &self.l1
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub async fn insert(&self, k: K, v: V) {
+        self.l1.insert(k, v).await;
+    }
+
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn get(&self, k: K) -> Option<V> {
+        tracing::trace!("Get");
+        if let Some((v, age)) = self.l1.peek(&k).await {
+            tracing::debug!("L1 hit");
+            if let Some(refresh) = &self.refresh {
+                refresh(&k, age);
+            }
+            return Some(v);
+        }
+
+        match in_flight(&self.loads, &k) {
+            Some(notify) => {
+                let notified = notify.notified();
+                // The loader might have finished between the lookup and the registration
+                if lock(&self.loads).contains_key(&k) {
+                    tracing::trace!("Waiting for in-flight load...");
+                    notified.await;
+                }
+            }
+            None => {
+                if let Some(guard) = LoadGuard::acquire(&self.loads, &self.loading, &k) {
+                    load(&self.l1, self.source.as_ref(), &self.limiter, guard).await;
+                }
+            }
+        }
+
+        self.l1.get(&k).await
+    }
+
+    /// Number of background loads still running.
+    pub fn loader_count(&self) -> usize {
+        let mut loaders = lock(&self.loaders);
+        while loaders.try_join_next().is_some() {}
+        loaders.len()
+    }
+
+    /// Number of loads waiting for
+    /// [`with_max_concurrent_loads`](Self::with_max_concurrent_loads) to let
+    /// them run.
+    pub fn queued_loads(&self) -> usize {
+        self.limiter.queued.count()
+    }
+
+    /// Aborts every background load, the keys they were loading are left
+    /// as they are.
+    pub fn abort_all_loaders(&self) {
+        lock(&self.loaders).abort_all();
+    }
+
+    /// Resolves once no load from the source is in flight and no task is
+    /// napping on the L1 map.
+    pub async fn quiesce(&self) {
+        self.loading.drained().await;
+        self.l1.quiesce().await;
+    }
+}
+
+async fn load<K, V, S>(l1: &NapMap<K, V>, source: &S, limiter: &Limiter, guard: LoadGuard<K>)
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: AsyncSource<K, V>,
+{
+    let _permit = match limiter.permits.get() {
+        Some(permits) => {
+            let _queued = limiter.queued.enter();
+            tracing::trace!("Waiting for a load permit...");
+            // The semaphore is never closed
+            permits.acquire().await.ok()
+        }
+        None => None,
+    };
+    tracing::trace!("Fetching from source");
+    if let Some(v) = source.fetch(&guard.k).await {
+        l1.insert(guard.k.clone(), v).await;
+    }
+}
+
+impl<K, V, S> Debug for TieredNapMap<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: AsyncSource<K, V>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TieredNapMap")
+            .field("l1", &self.l1)
+            .field("loads", &self.loads)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncSource;
+    use super::MaxAge;
+    use super::TieredNapMap;
+    use crate::NapMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct SlowSource {
+        fetches: AtomicUsize,
+    }
+
+    impl AsyncSource<u32, i32> for SlowSource {
+        async fn fetch(&self, k: &u32) -> Option<i32> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            (*k == 1).then_some(7)
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_fall_through_to_the_source() {
+        let tiered = TieredNapMap::new(NapMap::new(10), SlowSource::default());
+
+        assert_eq!(tiered.get(1).await, Some(7));
+        assert_eq!(tiered.l1().len().await, 1);
+        assert_eq!(tiered.get(1).await, Some(7));
+        assert_eq!(tiered.source().fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_coalesce_concurrent_misses() {
+        let tiered = Arc::new(TieredNapMap::new(NapMap::new(10), SlowSource::default()));
+
+        let handles: Vec<_> = (0..10)
+            .map(|_| {
+                let tiered = tiered.clone();
+                tokio::spawn(async move { tiered.get(1).await })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), Some(7));
+        }
+        assert_eq!(tiered.source().fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_nap_when_the_source_misses() {
+        let tiered = Arc::new(TieredNapMap::new(NapMap::new(10), SlowSource::default()));
+
+        tokio::spawn({
+            let tiered = tiered.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                tiered.insert(2, 3).await;
+            }
+        });
+
+        assert_eq!(tiered.get(2).await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn it_should_refresh_stale_hits_in_the_background() {
+        let tiered = TieredNapMap::new(NapMap::new(10), SlowSource::default())
+            .with_refresh(MaxAge(Duration::from_millis(50)));
+
+        assert_eq!(tiered.get(1).await, Some(7));
+        assert_eq!(tiered.get(1).await, Some(7));
+        assert_eq!(tiered.source().fetches.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(tiered.get(1).await, Some(7));
+        assert_eq!(tiered.get(1).await, Some(7));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(tiered.source().fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_quiesce_once_loads_land() {
+        let tiered = Arc::new(TieredNapMap::new(NapMap::new(10), SlowSource::default()));
+        let get = tokio::spawn({
+            let tiered = tiered.clone();
+            async move { tiered.get(1).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        tiered.quiesce().await;
+        assert_eq!(tiered.l1().len().await, 1);
+        assert_eq!(get.await.unwrap(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn it_should_abort_background_loads() {
+        let tiered = TieredNapMap::new(NapMap::new(10), SlowSource::default())
+            .with_refresh(MaxAge(Duration::ZERO));
+
+        assert_eq!(tiered.get(1).await, Some(7));
+        assert_eq!(tiered.get(1).await, Some(7));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(tiered.loader_count(), 1);
+
+        tiered.abort_all_loaders();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(tiered.loader_count(), 0);
+        assert_eq!(tiered.source().fetches.load(Ordering::SeqCst), 2);
+        tiered.quiesce().await;
+    }
+
+    #[tokio::test]
+    async fn it_should_queue_loads_past_the_limit() {
+        let tiered = Arc::new(
+            TieredNapMap::new(NapMap::new(10), SlowSource::default()).with_max_concurrent_loads(1),
+        );
+        let handles: Vec<_> = (1..=3)
+            .map(|k| {
+                let tiered = tiered.clone();
+                tokio::spawn(async move { tiered.get(k).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(tiered.queued_loads(), 2);
+        assert_eq!(tiered.source().fetches.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(tiered.queued_loads(), 1);
+        tiered.insert(2, 20).await;
+        tiered.insert(3, 30).await;
+        for (handle, expected) in handles.into_iter().zip([7, 20, 30]) {
+            assert_eq!(handle.await.unwrap(), Some(expected));
+        }
+        assert_eq!(tiered.source().fetches.load(Ordering::SeqCst), 3);
+    }
+}
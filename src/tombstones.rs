@@ -0,0 +1,87 @@
+use crate::error::lock;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What `get` does on a key that was removed and not inserted again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AfterRemove {
+    /// Nap until the key is inserted again, like for a key never seen.
+    #[default]
+    Nap,
+    /// Give up right away with `GetError::Removed`. Every removed key is
+    /// remembered until it is inserted again, see `with_tombstones` to forget
+    /// them after a while.
+    Removed,
+}
+
+/// Keys deliberately removed from a map, so `get` can tell them apart from
+/// keys that are yet to arrive. Each tombstone lasts `ttl`, or until the key
+/// is inserted again.
+#[derive(Debug)]
+pub(crate) struct Tombstones<K> {
+    ttl: Option<Duration>,
+    inner: Mutex<Inner<K>>,
+}
+
+#[derive(Debug)]
+struct Inner<K> {
+    buried_at: HashMap<K, Instant>,
+    // Oldest first, as every tombstone lives as long
+    order: VecDeque<(K, Instant)>,
+}
+
+impl<K> Tombstones<K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Tombstones lasting `ttl`, or forever when `None`.
+    pub(crate) fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            inner: Mutex::new(Inner {
+                buried_at: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn bury(&self, k: &K) {
+        let now = Instant::now();
+        let mut inner = lock(&self.inner);
+        self.prune(&mut inner, now);
+        inner.buried_at.insert(k.clone(), now);
+        if self.ttl.is_some() {
+            inner.order.push_back((k.clone(), now));
+        }
+    }
+
+    pub(crate) fn revive(&self, k: &K) {
+        lock(&self.inner).buried_at.remove(k);
+    }
+
+    pub(crate) fn is_buried(&self, k: &K) -> bool {
+        let mut inner = lock(&self.inner);
+        self.prune(&mut inner, Instant::now());
+        inner.buried_at.contains_key(k)
+    }
+
+    fn prune(&self, inner: &mut Inner<K>, now: Instant) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+        while let Some((_, at)) = inner.order.front() {
+            if now.duration_since(*at) < ttl {
+                break;
+            }
+            let (k, at) = inner.order.pop_front().unwrap();
+            // The key may have been buried again since
+            if inner.buried_at.get(&k) == Some(&at) {
+                inner.buried_at.remove(&k);
+            }
+        }
+    }
+}
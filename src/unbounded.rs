@@ -1,18 +1,167 @@
+use crate::backend::Attached;
+use crate::backend::Backend;
+use crate::backend::BackendError;
+use crate::backend::Mutation;
+use crate::backend::WriteBehind;
+use crate::backend::WriteOrder;
+use crate::backend::WriteThrough;
+use crate::changelog::Change;
+use crate::changelog::ChangeFilter;
+use crate::changelog::ChangeStream;
+use crate::changelog::Changelog;
+use crate::changelog::Recorder;
+use crate::dedup::Requests;
+use crate::error::lock;
+use crate::error::EntryTooLarge;
+use crate::error::GetError;
+use crate::error::InsertError;
+use crate::expiry::Expiry;
+use crate::gauge::Gauge;
+use crate::hooks::Authorizer;
+use crate::hooks::BlockingClone;
+use crate::hooks::Finalizer;
+use crate::hooks::HookPanic;
+use crate::hooks::Hooks;
+use crate::hooks::KeyFilter;
+use crate::hooks::OnEvict;
+use crate::hooks::OnRemove;
+use crate::hooks::Operation;
+use crate::hooks::RemovalCause;
+use crate::hooks::SlowWait;
+use crate::hooks::Weigher;
+use crate::loads::in_flight;
+use crate::loads::LoadGuard;
+use crate::loads::Loads;
+use crate::notifiers::NotifierStats;
+use crate::notifiers::Notifiers;
+use crate::notifiers::Registration;
+use crate::producers::Producers;
+use crate::snapshot::MapSnapshot;
+use crate::stats::Counters;
+use crate::stats::NapMapStats;
+use crate::stream::Iter;
+use crate::sync::AsyncRwLock;
+use crate::sync::RwLockWriteGuard;
+use crate::tombstones::AfterRemove;
+use crate::tombstones::Tombstones;
+use crate::version::EntryOrder;
+use crate::version::SwapError;
+use crate::version::Version;
+use crate::version::VersionError;
+use crate::watermark::HighWater;
+use crate::watermark::Watermarks;
+use futures_core::Stream;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::future::Future;
+use std::hash::BuildHasher;
 use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::Mutex as AsyncMutex;
-use tokio::sync::Notify;
-use tokio::sync::RwLock as AsyncRwLock;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-pub struct UnboundedNapMap<K, V>
+/// A handle to the map. Clones share the entries, the waiters and the
+/// configuration set so far, like the endpoints of a channel.
+#[derive(Clone)]
+pub struct UnboundedNapMap<K, V, S = RandomState>
 where
     K: Eq + Hash + Clone + Debug,
     V: Clone + Debug,
 {
-    map: Arc<AsyncRwLock<HashMap<K, V>>>,
-    notifiers: Arc<AsyncMutex<HashMap<K, Arc<Notify>>>>,
+    map: Arc<AsyncRwLock<HashMap<K, Slot<V>, S>>>,
+    notifiers: Arc<Notifiers<K, S>>,
+    versions: Arc<watch::Sender<Version>>,
+    hydrating: Arc<watch::Sender<usize>>,
+    requests: Arc<Requests<K, V>>,
+    napping: Arc<Gauge>,
+    loads: Loads<K>,
+    loading: Arc<Gauge>,
+    entries_peak: Arc<HighWater>,
+    counters: Arc<Counters>,
+    closed: Arc<AtomicBool>,
+    closed_keys: Arc<Mutex<Vec<KeyFilter<K>>>>,
+    producers: Arc<Producers<K>>,
+    parent: Option<Parent<K, V, S>>,
+    backend: Option<Attached<K, V>>,
+    write_behind: Option<mpsc::Sender<Mutation<K, V>>>,
+    finalizer: Option<Finalizer<K, V>>,
+    on_remove: Option<OnRemove<K, V>>,
+    on_evict: Option<OnEvict<K, V>>,
+    authorizer: Option<Authorizer<K>>,
+    hooks: Arc<Hooks>,
+    weigher: Option<Weigher<K, V>>,
+    slow_wait: Option<(Duration, SlowWait<K>)>,
+    max_entry_weight: Option<usize>,
+    blocking_clone: Option<(usize, BlockingClone<K, V>)>,
+    tombstones: Option<Arc<Tombstones<K>>>,
+    changelog: Option<Arc<Recorder<K, V>>>,
+    snapshot: Arc<Mutex<Snapshot<K, V>>>,
+    ttl: Option<Duration>,
+    grace: Duration,
+    expiry: Arc<Expiry<K>>,
+    span: Option<tracing::Span>,
+}
+
+/// One hydration in flight, over even if its task is aborted.
+struct Hydration(Arc<watch::Sender<usize>>);
+
+impl Drop for Hydration {
+    fn drop(&mut self) {
+        self.0.send_modify(|running| *running -= 1);
+    }
+}
+
+/// The last snapshot handed out, with the version it was taken at.
+type Snapshot<K, V> = Option<(Version, Arc<HashMap<K, V>>)>;
+
+#[derive(Debug)]
+struct Slot<V> {
+    value: V,
+    version: Version,
+    expires_at: Option<Instant>,
+}
+
+impl<V> Slot<V> {
+    /// Expired entries are misses until they are overwritten or reaped.
+    fn is_live(&self) -> bool {
+        self.expires_at.is_none_or(|at| at > Instant::now())
+    }
+
+    /// Live, or expired less than `grace` ago.
+    fn is_within(&self, grace: Duration) -> bool {
+        self.expires_at.is_none_or(|at| at + grace > Instant::now())
+    }
+}
+
+/// Where a `get` naps when a key is missing from both a map and its parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentNap {
+    /// Only an insert into the child map wakes the waiter.
+    Child,
+    /// An insert into either the child or the parent wakes the waiter.
+    Both,
+}
+
+#[derive(Clone)]
+struct Parent<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    map: Arc<UnboundedNapMap<K, V, S>>,
+    nap: ParentNap,
 }
 
 /// Creates an unbounded napmap for communicating between asynchronous tasks.
@@ -34,138 +183,4356 @@ where
     V: Clone + Debug,
 {
     pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, V, S> UnboundedNapMap<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: BuildHasher + Clone,
+{
+    /// Like [`new`](UnboundedNapMap::new), hashing both the entries and the
+    /// waiters' keys with `hasher`, e.g. a
+    /// [`BuildIdHasher`](crate::BuildIdHasher) for integer keys or a
+    /// fixed-seed one for reproducible tests.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    /// Like [`with_hasher`](Self::with_hasher), with room for `capacity`
+    /// entries before the map reallocates.
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        let counters = Arc::new(Counters::default());
         Self {
-            map: Arc::new(AsyncRwLock::new(HashMap::new())),
-            notifiers: Arc::new(AsyncMutex::new(HashMap::new())),
+            map: Arc::new(AsyncRwLock::new(HashMap::with_capacity_and_hasher(
+                capacity,
+                hasher.clone(),
+            ))),
+            notifiers: Arc::new(Notifiers::with_hasher(hasher)),
+            versions: Arc::new(watch::Sender::new(Version(0))),
+            hydrating: Arc::new(watch::Sender::new(0)),
+            requests: Arc::new(Requests::new()),
+            napping: Arc::new(counters.napping()),
+            loads: Arc::new(Mutex::new(HashMap::new())),
+            loading: Arc::new(Gauge::new()),
+            entries_peak: Arc::new(HighWater::new()),
+            counters,
+            closed: Arc::new(AtomicBool::new(false)),
+            closed_keys: Arc::new(Mutex::new(Vec::new())),
+            producers: Arc::new(Producers::new()),
+            parent: None,
+            backend: None,
+            write_behind: None,
+            finalizer: None,
+            on_remove: None,
+            on_evict: None,
+            authorizer: None,
+            hooks: Arc::new(Hooks::default()),
+            weigher: None,
+            slow_wait: None,
+            max_entry_weight: None,
+            blocking_clone: None,
+            tombstones: None,
+            changelog: None,
+            snapshot: Arc::new(Mutex::new(None)),
+            ttl: None,
+            grace: Duration::ZERO,
+            expiry: Arc::new(Expiry::new()),
+            span: None,
         }
     }
 
-    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
-    pub async fn insert(&self, k: K, v: V) {
-        tracing::trace!("Insert");
-        self.map.write().await.insert(k.clone(), v);
-        if let Some(notify) = self.notifiers.lock().await.remove(&k) {
-            notify.notify_waiters();
-            tracing::trace!("Notified all waiting tasks");
+    /// Names the map and records its inserts, wakes, evictions and sweeps as
+    /// events of one long-lived span, so a single span query tells the map's
+    /// whole story. Under the `metrics` feature the name also labels the
+    /// map's metrics as `map`, for every clone of the map.
+    pub fn with_span_name(mut self, name: &str) -> Self {
+        self.span = Some(tracing::info_span!("napmap", name));
+        #[cfg(feature = "metrics")]
+        self.counters.name(name);
+        self
+    }
+
+    /// Layers this map on top of `parent`, so that a `get` missing in this map
+    /// is answered by the parent (and its own parents) before napping.
+    ///
+    /// Inserts and removals only ever touch this map, the parent is read-only
+    /// from the child's point of view.
+    pub fn with_parent(mut self, parent: Arc<UnboundedNapMap<K, V, S>>, nap: ParentNap) -> Self {
+        self.parent = Some(Parent { map: parent, nap });
+        self
+    }
+
+    /// Writes every insert and removal through to `backend`, see
+    /// [`WriteThrough`] for ordering and error handling. A `get` missing the
+    /// map loads the key from the backend before napping, the loaded value is
+    /// kept in the map.
+    pub fn with_backend(
+        mut self,
+        backend: impl Backend<K, V> + 'static,
+        policy: WriteThrough,
+    ) -> Self {
+        self.backend = Some(Attached {
+            backend: Arc::new(backend),
+            policy,
+        });
+        self
+    }
+
+    /// Queues every insert and removal for `backend` instead of writing it
+    /// through, a background task flushes the queue in batches.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn with_write_behind(
+        mut self,
+        backend: impl Backend<K, V> + 'static,
+        config: WriteBehind,
+    ) -> Self
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        self.write_behind = Some(crate::backend::spawn_write_behind(backend, config));
+        self
+    }
+
+    /// Runs `finalizer` on a background task for every value leaving the map,
+    /// whether it was removed or overwritten. Meant for values owning
+    /// resources that need an async cleanup, like connections or child tasks.
+    ///
+    /// Must be used from within a tokio runtime.
+    pub fn with_finalizer<F, Fut>(mut self, finalizer: F) -> Self
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+        F: Fn(K, V) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.finalizer = Some(crate::hooks::finalizer(finalizer));
+        self
+    }
+
+    /// Calls `callback` inline for every entry removed through `remove` or
+    /// `clear`, while the write lock is still held, before any other task can
+    /// observe the removal. Keep it cheap, it is meant for logging and metrics.
+    pub fn with_on_remove(mut self, callback: impl Fn(&K, &V) + Send + Sync + 'static) -> Self {
+        self.on_remove = Some(Arc::new(callback));
+        self
+    }
+
+    /// Calls `listener` inline for every entry leaving the map, whether it
+    /// was removed, replaced, expired or evicted, see [`RemovalCause`].
+    /// Expired entries are reported once purged or overwritten. Keep it cheap,
+    /// it runs on the task whose operation made the entry leave.
+    pub fn with_on_evict(
+        mut self,
+        listener: impl Fn(&K, &V, RemovalCause) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_evict = Some(Arc::new(listener));
+        self
+    }
+
+    /// Consults `authorize` before every lookup, insert and removal of a key,
+    /// e.g. to keep each tenant of a shared map to its own namespace. Denied
+    /// lookups find nothing and `get_checked` fails with
+    /// [`GetError::Denied`], denied inserts fail with [`InsertError::Denied`]
+    /// and denied removals remove nothing. It runs inline, often under a
+    /// lock, so keep it cheap.
+    pub fn with_authorizer(
+        mut self,
+        authorize: impl Fn(&K, Operation) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.authorizer = Some(Arc::new(authorize));
+        self
+    }
+
+    /// Picks what a panicking hook does to the map operation running it, see
+    /// [`HookPanic`]. Panics unwind into the caller by default. Only the
+    /// synchronous part of a finalizer is covered, its future runs on a task
+    /// of its own.
+    pub fn with_hook_panic(mut self, on_panic: HookPanic) -> Self {
+        self.hooks = Arc::new(Hooks::new(on_panic));
+        self
+    }
+
+    /// Weighs entries for [`shrink_to_weight`](Self::shrink_to_weight), e.g. by
+    /// their size in bytes. Without a weigher every entry weighs 1.
+    pub fn with_weigher(
+        mut self,
+        weigher: impl Fn(&K, &V) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.weigher = Some(Arc::new(weigher));
+        self
+    }
+
+    /// Rejects entries weighing more than `max`, see
+    /// [`with_weigher`](Self::with_weigher), so one oversized value can't be
+    /// silently accepted into a shared map.
+    pub fn with_max_entry_weight(mut self, max: usize) -> Self {
+        self.max_entry_weight = Some(max);
+        self
+    }
+
+    /// Calls `callback` inline with the key and the elapsed time as soon as a
+    /// single `get` has napped for `threshold`, while it keeps napping. Meant
+    /// for alerting on coordination points that stall.
+    pub fn with_slow_wait(
+        mut self,
+        threshold: Duration,
+        callback: impl Fn(&K, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.slow_wait = Some((threshold, Arc::new(callback)));
+        self
+    }
+
+    /// Clones values weighing at least `min_weight` on the blocking pool when
+    /// [`get`](Self::get) returns them, so cloning a huge value doesn't stall
+    /// the async worker thread. Values are told apart by the
+    /// [`with_weigher`](Self::with_weigher) weight, e.g. a size in bytes.
+    ///
+    /// The clones go through `tokio::task::spawn_blocking`, so getting a heavy
+    /// value panics outside a tokio runtime.
+    pub fn with_blocking_clone(mut self, min_weight: usize) -> Self
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let clone = crate::hooks::blocking_clone(self.map.clone(), |map, k| {
+            map.get(k).filter(|s| s.is_live()).map(|s| s.value.clone())
+        });
+        self.blocking_clone = Some((min_weight, clone));
+        self
+    }
+
+    /// Caps how many distinct keys may have napping tasks at once. Past the
+    /// cap, [`get_checked`](Self::get_checked) on a new key fails right away
+    /// and [`get`](Self::get) returns `None`, so attacker-chosen keys can't
+    /// grow the notifier table without bound. Other napping methods are not
+    /// refused. The cap applies to every clone of the map.
+    pub fn with_max_pending_keys(self, limit: usize) -> Self {
+        self.notifiers.set_limit(limit);
+        self
+    }
+
+    /// Remembers keys removed through `remove` or `clear` for `ttl`. Until
+    /// the key is inserted again, [`get_checked`](Self::get_checked) fails
+    /// with [`GetError::Removed`] and [`get`](Self::get) returns `None` right
+    /// away, instead of napping for a value that may never come back.
+    pub fn with_tombstones(mut self, ttl: Duration) -> Self {
+        self.tombstones = Some(Arc::new(Tombstones::new(Some(ttl))));
+        self
+    }
+
+    /// Picks whether `get` naps on a removed key or gives up right away, see
+    /// [`AfterRemove`]. Napping is the default.
+    pub fn with_after_remove(mut self, after_remove: AfterRemove) -> Self {
+        self.tombstones = match after_remove {
+            AfterRemove::Nap => None,
+            AfterRemove::Removed => Some(Arc::new(Tombstones::new(None))),
+        };
+        self
+    }
+
+    /// Records the last `capacity` mutations, readable through
+    /// [`changes_since`](Self::changes_since).
+    pub fn with_changelog(mut self, capacity: usize) -> Self {
+        self.changelog = Some(Arc::new(Recorder(Arc::new(Changelog::new(capacity)))));
+        self
+    }
+
+    /// Streams every change after `version`, retained ones first and then
+    /// live ones, `Version(0)` starting from the oldest retained change.
+    ///
+    /// If changes past `version` were already dropped from the changelog, the
+    /// stream first yields [`Lagged`](crate::changelog::Lagged). Returns `None`
+    /// when the map was built without [`with_changelog`](Self::with_changelog).
+    /// Changes to keys the authorizer keeps from reads are skipped.
+    pub fn changes_since(&self, version: Version) -> Option<ChangeStream<K, V>> {
+        self.stream_changes(version, None)
+    }
+
+    /// Streams the changes made from now on. Subscribers share the changelog
+    /// instead of buffering on their own, so a stalled one costs no memory and
+    /// gets [`Lagged`](crate::changelog::Lagged) once it falls more than the
+    /// changelog capacity behind.
+    pub fn subscribe(&self) -> Option<ChangeStream<K, V>> {
+        self.changes_since(self.version())
+    }
+
+    /// Like [`subscribe`](Self::subscribe), but only yields the changes
+    /// `filter` accepts. Rejected changes are skipped inside the changelog,
+    /// never cloned for the subscriber.
+    pub fn subscribe_filtered(
+        &self,
+        filter: impl Fn(&Change<K, V>) -> bool + Send + Sync + 'static,
+    ) -> Option<ChangeStream<K, V>> {
+        self.stream_changes(self.version(), Some(Box::new(filter)))
+    }
+
+    /// Streams the changes after `version` that both the authorizer and
+    /// `filter` accept.
+    fn stream_changes(
+        &self,
+        version: Version,
+        filter: Option<ChangeFilter<K, V>>,
+    ) -> Option<ChangeStream<K, V>> {
+        let log = self.changelog.as_ref()?;
+        let mut changes = ChangeStream::new(log.0.clone(), version);
+        if let Some(authorize) = self.authorizer.clone() {
+            changes = changes.with_authorizer(authorize, self.hooks.clone());
         }
+        Some(match filter {
+            Some(filter) => changes.with_filter(filter),
+            None => changes,
+        })
     }
 
-    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
-    pub async fn get(&self, k: K) -> Option<V> {
-        tracing::trace!("Get");
-        if self.map.read().await.contains_key(&k) {
-            tracing::debug!("Contains key");
-            return self.map.read().await.get(&k).cloned();
+    /// Sends the current entries matching `filter`, then the matching ones
+    /// inserted later, into `sender` from a background task. The task ends
+    /// once `sender` is closed or the map is dropped. Returns `None` when the
+    /// map was built without [`with_changelog`](Self::with_changelog), which
+    /// is what future entries are read from.
+    pub async fn forward_matching(
+        &self,
+        filter: impl Fn(&K, &V) -> bool + Send + 'static,
+        sender: mpsc::Sender<(K, V)>,
+    ) -> Option<JoinHandle<()>>
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        self.changelog.as_ref()?;
+        let (current, mut changes) = {
+            let map = self.map.read().await;
+            let current: Vec<_> = map
+                .iter()
+                .filter(|(k, s)| self.visible(k, s) && filter(k, &s.value))
+                .map(|(k, s)| (k.clone(), s.value.clone()))
+                .collect();
+            (current, self.subscribe()?)
+        };
+
+        Some(tokio::spawn(async move {
+            for entry in current {
+                if sender.send(entry).await.is_err() {
+                    return;
+                }
+            }
+            while let Some(change) = changes.next().await {
+                match change {
+                    Ok(Change::Insert { key, value, .. }) if filter(&key, &value) => {
+                        if sender.send((key, value)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(lagged) => tracing::warn!("Forwarding {lagged}"),
+                }
+            }
+        }))
+    }
+
+    /// Streams the keys present now, to feed stream pipelines.
+    pub async fn keys_stream(&self) -> Iter<std::vec::IntoIter<K>> {
+        let map = self.map.read().await;
+        let keys: Vec<K> = map
+            .iter()
+            .filter(|(k, s)| self.visible(k, s))
+            .map(|(k, _)| k.clone())
+            .collect();
+        Iter(keys.into_iter())
+    }
+
+    /// A consistent point-in-time copy of the map, to be scanned without
+    /// holding up writers. The copy is shared until the map next changes, so
+    /// repeated calls on an idle map are cheap.
+    pub async fn snapshot_arc(&self) -> Arc<HashMap<K, V>> {
+        let map = self.map.read().await;
+        let version = self.version();
+        let mut snapshot = lock(&self.snapshot);
+        if let Some((at, snapshot)) = snapshot.as_ref() {
+            if *at == version {
+                return snapshot.clone();
+            }
         }
+        let fresh: Arc<HashMap<K, V>> = Arc::new(
+            map.iter()
+                .filter(|(k, slot)| self.visible(k, slot))
+                .map(|(k, slot)| (k.clone(), slot.value.clone()))
+                .collect(),
+        );
+        *snapshot = Some((version, fresh.clone()));
+        fresh
+    }
 
-        let mut notifiers = self.notifiers.lock().await;
-        let notify = notifiers
-            .entry(k.clone())
-            .or_insert(Arc::new(Notify::new()))
-            .clone();
-        drop(notifiers);
+    /// A read-only view answering from this map first and from `other`
+    /// otherwise, e.g. the current and the previous epoch during a rollover.
+    /// Gets through the view nap on both maps.
+    pub fn overlay<'a>(&'a self, other: &'a Self) -> Overlay<'a, K, V, S> {
+        Overlay {
+            top: self,
+            bottom: other,
+        }
+    }
 
-        tracing::trace!("Waiting...");
-        notify.notified().await;
-        tracing::trace!("Notified, data is available");
-        self.map.read().await.get(&k).cloned()
+    /// A read-only view whose lookups return `f` applied to the value, e.g.
+    /// a trimmed-down summary of it. Nothing is stored, `f` runs on every
+    /// hit, and gets through the view nap on this map's keys.
+    pub fn map_values<U, F>(&self, f: F) -> MapValues<'_, K, V, F, S>
+    where
+        F: Fn(&V) -> U,
+    {
+        MapValues { napmap: self, f }
     }
 
-    pub async fn remove(&self, k: K) -> Option<V> {
-        self.map.write().await.remove(&k)
+    /// Version of the latest mutation.
+    pub fn version(&self) -> Version {
+        *self.versions.as_ref().borrow()
     }
 
-    pub async fn len(&self) -> usize {
-        self.map.read().await.len()
+    /// Resolves once every mutation up to `version` is visible. Versions are
+    /// handed out map-wide in order, so this doubles as a sequence number
+    /// barrier for consumers mirroring the map, e.g. from a [`Change`].
+    pub async fn await_version(&self, version: Version) {
+        let mut versions = self.versions.subscribe();
+        let _ = versions.wait_for(|v| *v >= version).await;
+        // The write lock is still held while the version is bumped
+        drop(self.map.read().await);
     }
 
-    pub async fn is_empty(&self) -> bool {
-        self.map.read().await.is_empty()
+    /// Expires every entry `ttl` after it was inserted, unless inserted
+    /// through [`insert_with_ttl`](Self::insert_with_ttl). Expired entries are
+    /// misses, `get` naps on them, see [`purge_expired`](Self::purge_expired)
+    /// to reclaim them.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
     }
-}
 
-impl<K, V> Default for UnboundedNapMap<K, V>
-where
-    K: Eq + Hash + Clone + Debug,
-    V: Clone + Debug,
-{
-    fn default() -> Self {
-        Self::new()
+    /// Keeps expired entries readable through [`get_stale`](Self::get_stale)
+    /// for `grace` past their TTL, [`purge_expired`](Self::purge_expired)
+    /// leaves them in until then. Every other lookup still misses them.
+    pub fn with_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
     }
-}
 
-impl<K, V> Debug for UnboundedNapMap<K, V>
-where
-    K: Eq + Hash + Clone + Debug,
-    V: Clone + Debug,
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("UnboundedNapMap")
-            .field("map", &self.map)
-            .field("notifiers", &self.notifiers)
-            .finish()
+    /// Same as [`insert_checked`](Self::insert_checked), failures are logged.
+    pub async fn insert(&self, k: K, v: V) {
+        if let Err(e) = self.insert_checked(k, v).await {
+            tracing::error!("{e}");
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::UnboundedNapMap;
-    use std::sync::Arc;
-    use std::time::Duration;
-    use tracing_subscriber::EnvFilter;
+    /// Inserts like [`insert`](Self::insert), then waits, at most `timeout`,
+    /// for the tasks napping on `k` to observe the value. Returns how many
+    /// did, e.g. to release upstream resources once consumers got the value.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub async fn insert_acked(&self, k: K, v: V, timeout: Duration) -> usize {
+        let nap = self.notifiers.lock().await.get(&k);
+        let napping = nap.as_ref().map_or(0, |nap| nap.napping());
+        self.insert(k, v).await;
+        match nap {
+            Some(nap) => nap.acked(napping, timeout).await,
+            None => 0,
+        }
+    }
 
-    // Add this to a test to see the logs
-    fn _tracing_sub() {
-        let env_filter =
-            EnvFilter::from_default_env().add_directive("napmap=trace".parse().unwrap());
-        tracing_subscriber::fmt().with_env_filter(env_filter).init();
+    /// Inserts the output of `fut` under `k` once it completes, driving it on
+    /// a task holding a handle to the map. The future is dropped unfinished
+    /// if `k` is removed or if another one is registered for `k`. Meanwhile
+    /// `k` counts as [pending](Self::is_pending).
+    pub fn insert_from_future<F>(&self, k: K, fut: F)
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+        F: Future<Output = V> + Send + 'static,
+    {
+        if !self.allows(&k, Operation::Insert) {
+            tracing::error!("{}", InsertError::<V>::Denied);
+            return;
+        }
+        let map = self.clone();
+        self.producers.spawn(k.clone(), move |id| async move {
+            let running = map.producers.running(&k, id);
+            let v = crate::reentrant::producing(map.id(), &k, fut).await;
+            drop(running);
+            map.insert(k, v).await;
+        });
     }
 
-    #[tokio::test]
-    async fn it_should_wait_until_data_is_inserted() {
-        let napmap = Arc::new(UnboundedNapMap::new());
+    /// Inserts `k` and wakes its waiters, failing on a refusal or a backend
+    /// failure.
+    pub async fn insert_checked(&self, k: K, v: V) -> Result<(), InsertError<V>> {
+        self.insert_expiring(k, v, self.ttl).await
+    }
 
-        tokio::spawn({
-            let map = napmap.clone();
-            async move {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                map.insert("key", 7).await;
+    /// Like [`insert`](Self::insert), but expires the entry after `ttl`
+    /// instead of the map's [`with_ttl`](Self::with_ttl).
+    pub async fn insert_with_ttl(&self, k: K, v: V, ttl: Duration) {
+        if let Err(e) = self.insert_expiring(k, v, Some(ttl)).await {
+            tracing::error!("{e}");
+        }
+    }
+
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    async fn insert_expiring(
+        &self,
+        k: K,
+        v: V,
+        ttl: Option<Duration>,
+    ) -> Result<(), InsertError<V>> {
+        tracing::trace!("Insert");
+        if !self.allows(&k, Operation::Insert) {
+            return Err(InsertError::Denied);
+        }
+        self.check_weight(&k, &v)?;
+        self.persist_store(WriteOrder::BeforeVisible, &k, &v)
+            .await?;
+        if !self.tracks_writes() {
+            self.publish(k, v, ttl).await;
+            return Ok(());
+        }
+
+        self.publish(k.clone(), v.clone(), ttl).await;
+        self.persist_store(WriteOrder::AfterVisible, &k, &v).await?;
+        Ok(self.enqueue(Mutation::Store(k, v)).await?)
+    }
+
+    /// Inserts all `pairs` under a single write lock, so readers observe either
+    /// none or all of them, and wakes their waiters only once every pair is in.
+    ///
+    /// Oversized pairs reject the whole batch. With a
+    /// [`WriteOrder::BeforeVisible`] backend, every pair is persisted first and
+    /// a propagated failure leaves the map untouched, the pairs persisted so
+    /// far are restored to their previous value or deleted from the backend.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn insert_batch_atomic(
+        &self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<(), InsertError<V>> {
+        tracing::trace!("Insert batch");
+        let pairs: Vec<(K, V)> = pairs.into_iter().collect();
+        for (k, v) in &pairs {
+            if !self.allows(k, Operation::Insert) {
+                return Err(InsertError::Denied);
             }
-        });
+            self.check_weight(k, v)?;
+        }
+        self.persist_batch(&pairs).await?;
+        if !self.tracks_writes() {
+            self.publish_many(pairs).await;
+            return Ok(());
+        }
 
-        let res = napmap.get("key").await.unwrap();
-        assert_eq!(res, 7);
+        self.publish_many(pairs.clone()).await;
+        for (k, v) in pairs {
+            self.persist_store(WriteOrder::AfterVisible, &k, &v).await?;
+            self.enqueue(Mutation::Store(k, v)).await?;
+        }
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn it_should_notify_all_waiters() {
-        let napmap = Arc::new(UnboundedNapMap::new());
+    /// Same as [`insert_batch_atomic`](Self::insert_batch_atomic), failures
+    /// are logged.
+    pub async fn insert_many(&self, pairs: impl IntoIterator<Item = (K, V)>) {
+        if let Err(e) = self.insert_batch_atomic(pairs).await {
+            tracing::error!("{e}");
+        }
+    }
 
-        tokio::spawn({
-            let map = napmap.clone();
-            async move {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                map.insert("key", 7).await;
+    /// Inserts only if the entry is still at `expected`, `None` meaning that
+    /// the key must be absent. Returns the version of the new entry.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub async fn insert_if_version(
+        &self,
+        k: K,
+        v: V,
+        expected: Option<Version>,
+    ) -> Result<Version, VersionError> {
+        tracing::trace!("Insert if version");
+        if !self.allows(&k, Operation::Insert) {
+            return Err(VersionError::Denied);
+        }
+        let mut map = self.map.write().await;
+        let current = map.get(&k).filter(|s| s.is_live()).map(|s| s.version);
+        if current != expected {
+            return Err(VersionError::Mismatch { current });
+        }
+        self.persist_store(WriteOrder::BeforeVisible, &k, &v)
+            .await?;
+        if !self.tracks_writes() {
+            let version = self.store(&mut map, k.clone(), v, self.ttl);
+            drop(map);
+            self.wake(&k).await;
+            return Ok(version);
+        }
+
+        let version = self.store(&mut map, k.clone(), v.clone(), self.ttl);
+        drop(map);
+        self.wake(&k).await;
+        self.persist_store(WriteOrder::AfterVisible, &k, &v).await?;
+        self.enqueue(Mutation::Store(k, v)).await?;
+        Ok(version)
+    }
+
+    /// Applies `f` to the value of `k` under the write lock, waking the tasks
+    /// napping on it like an insert. Returns the updated value and its
+    /// version, `None` if `k` is absent.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, f))]
+    pub async fn update(
+        &self,
+        k: K,
+        f: impl FnOnce(&mut V),
+    ) -> Result<Option<(V, Version)>, InsertError<V>> {
+        tracing::trace!("Update");
+        if !self.allows(&k, Operation::Insert) {
+            return Err(InsertError::Denied);
+        }
+        let mut map = self.map.write().await;
+        let Some(slot) = map.get(&k).filter(|s| s.is_live()) else {
+            return Ok(None);
+        };
+        let mut v = slot.value.clone();
+        f(&mut v);
+        self.store_locked(&mut map, k.clone(), v.clone()).await?;
+        let version = map[&k].version;
+        Ok(Some((v, version)))
+    }
+
+    /// Swaps in `new` only if the entry still holds `expected`, `None`
+    /// meaning that the key must be absent. Returns the version of the new
+    /// entry, or the value found instead.
+    ///
+    /// The comparison is against the value as of one write: another writer
+    /// storing an equal value meanwhile still fails the swap.
+    pub async fn compare_and_swap(
+        &self,
+        k: K,
+        expected: Option<&V>,
+        new: V,
+    ) -> Result<Version, SwapError<V>>
+    where
+        V: PartialEq,
+    {
+        if !self.allows(&k, Operation::Insert) {
+            return Err(SwapError::Denied);
+        }
+        let current = self.peek_versioned(&k).await;
+        if current.as_ref().map(|(v, _)| v) != expected {
+            return Err(SwapError::Mismatch {
+                current: current.map(|(v, _)| v),
+            });
+        }
+        let version = current.map(|(_, version)| version);
+        match self.insert_if_version(k.clone(), new, version).await {
+            Ok(version) => Ok(version),
+            Err(VersionError::Backend(e)) => Err(SwapError::Backend(e)),
+            Err(VersionError::Denied) => Err(SwapError::Denied),
+            Err(VersionError::Mismatch { .. }) => Err(SwapError::Mismatch {
+                current: self.peek_versioned(&k).await.map(|(v, _)| v),
+            }),
+        }
+    }
+
+    /// Read-copy-update: computes a new value from the current one (`None` when
+    /// the key is absent) and swaps it in only if no other writer touched the
+    /// entry meanwhile, retrying against the fresher value otherwise.
+    ///
+    /// `f` may run several times and should be free of side effects. Never
+    /// fails with [`VersionError::Mismatch`], which is retried.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, f))]
+    pub async fn rcu<F>(&self, k: K, mut f: F) -> Result<V, VersionError>
+    where
+        F: FnMut(Option<&V>) -> V,
+    {
+        if !self.allows(&k, Operation::Insert) {
+            return Err(VersionError::Denied);
+        }
+        loop {
+            let current = self.peek_versioned(&k).await;
+            let new = f(current.as_ref().map(|(v, _)| v));
+            let expected = current.map(|(_, version)| version);
+            match self
+                .insert_if_version(k.clone(), new.clone(), expected)
+                .await
+            {
+                Ok(_) => return Ok(new),
+                Err(VersionError::Mismatch { .. }) => {
+                    tracing::trace!("Entry changed concurrently, retrying");
+                }
+                Err(e) => return Err(e),
             }
+        }
+    }
+
+    /// The entry of `k`, holding the write lock until it is consumed, so that
+    /// checking for the key and writing it happen as a single step.
+    pub async fn entry(&self, k: K) -> Entry<'_, K, V, S> {
+        Entry {
+            napmap: self,
+            map: self.map.write().await,
+            k,
+        }
+    }
+
+    fn next_version(&self) -> Version {
+        let mut next = Version(0);
+        self.versions.send_modify(|v| {
+            v.0 += 1;
+            next = *v;
         });
+        next
+    }
 
-        let first_handle = tokio::spawn({
-            let map = napmap.clone();
-            async move {
-                let res = map.get("key").await.unwrap();
-                assert_eq!(res, 7);
+    /// Every insertion goes through here, under the write lock, so versions
+    /// are handed out in the order changes become visible.
+    fn store(
+        &self,
+        map: &mut HashMap<K, Slot<V>, S>,
+        k: K,
+        v: V,
+        ttl: Option<Duration>,
+    ) -> Version {
+        let version = self.next_version();
+        self.counters.insert();
+        self.lifecycle("insert", &k);
+        if let Some(tombstones) = &self.tombstones {
+            tombstones.revive(&k);
+        }
+        self.record(|| Change::Insert {
+            key: k.clone(),
+            value: v.clone(),
+            version,
+        });
+        if let Some(at) = map.get(&k).and_then(|s| s.expires_at) {
+            self.expiry.unschedule(&k, at);
+        }
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        if let Some(at) = expires_at {
+            self.expiry.schedule(k.clone(), at);
+        }
+        let slot = Slot {
+            value: v,
+            version,
+            expires_at,
+        };
+        match self.retires() {
+            false => {
+                map.insert(k, slot);
             }
+            true => {
+                if let Some(old) = map.insert(k.clone(), slot) {
+                    let cause = match old.is_live() {
+                        true => RemovalCause::Replaced,
+                        false => RemovalCause::Expired,
+                    };
+                    self.retire(k, old.value, cause);
+                }
+            }
+        }
+        self.entries_peak.observe(map.len());
+        version
+    }
+
+    /// Writes through an [`Entry`] go through here, under its write lock.
+    async fn store_locked(
+        &self,
+        map: &mut HashMap<K, Slot<V>, S>,
+        k: K,
+        v: V,
+    ) -> Result<(), InsertError<V>> {
+        if !self.allows(&k, Operation::Insert) {
+            return Err(InsertError::Denied);
+        }
+        self.check_weight(&k, &v)?;
+        self.persist_store(WriteOrder::BeforeVisible, &k, &v)
+            .await?;
+        self.store(map, k.clone(), v.clone(), self.ttl);
+        self.wake(&k).await;
+        self.persist_store(WriteOrder::AfterVisible, &k, &v).await?;
+        Ok(self.enqueue(Mutation::Store(k, v)).await?)
+    }
+
+    /// Every removal goes through here, under the write lock.
+    fn unstore(&self, map: &mut HashMap<K, Slot<V>, S>, k: &K) -> Option<(K, V)> {
+        let (k, slot) = map.remove_entry(k)?;
+        if let Some(at) = slot.expires_at {
+            self.expiry.unschedule(&k, at);
+        }
+        let version = self.next_version();
+        self.record(|| Change::Remove {
+            key: k.clone(),
+            version,
         });
+        Some((k, slot.value))
+    }
 
-        let second_handle = tokio::spawn({
-            let map = napmap.clone();
-            async move {
-                let res = map.get("key").await.unwrap();
-                assert_eq!(res, 7);
+    fn record(&self, change: impl FnOnce() -> Change<K, V>) {
+        if let Some(log) = &self.changelog {
+            log.0.record(change());
+        }
+    }
+
+    /// Whether values leaving the map are needed past the removal.
+    fn retires(&self) -> bool {
+        self.finalizer.is_some() || self.on_evict.is_some()
+    }
+
+    fn retire(&self, k: K, v: V, cause: RemovalCause) {
+        if let Some(on_evict) = &self.on_evict {
+            self.hooks.call("on_evict", || on_evict(&k, &v, cause));
+        }
+        if let Some(finalizer) = &self.finalizer {
+            tracing::trace!("Finalizing");
+            self.hooks.call("finalizer", || finalizer(k, v));
+        }
+    }
+
+    /// Records an insert, a wake or an eviction of `k` under the span of
+    /// [`with_span_name`](Self::with_span_name).
+    fn lifecycle(&self, event: &'static str, k: &K) {
+        if let Some(span) = &self.span {
+            tracing::debug!(parent: span, key = ?k, "{event}");
+        }
+    }
+
+    /// Records a sweep over the whole map, see [`lifecycle`](Self::lifecycle).
+    fn swept(&self, event: &'static str, count: usize) {
+        if let Some(span) = &self.span {
+            tracing::debug!(parent: span, count, "{event}");
+        }
+    }
+
+    /// Whether a change still has to be persisted once it became visible.
+    fn tracks_writes(&self) -> bool {
+        self.write_behind.is_some()
+            || self
+                .backend
+                .as_ref()
+                .is_some_and(|a| a.policy.order == WriteOrder::AfterVisible)
+    }
+
+    async fn persist_store(&self, order: WriteOrder, k: &K, v: &V) -> Result<(), BackendError> {
+        match &self.backend {
+            Some(attached) if attached.policy.order == order => {
+                attached.persist(attached.backend.store(k, v)).await
             }
-        });
+            _ => Ok(()),
+        }
+    }
 
-        first_handle.await.unwrap();
-        second_handle.await.unwrap();
+    /// Persists a batch ahead of publishing it, see
+    /// [`insert_batch_atomic`](Self::insert_batch_atomic).
+    async fn persist_batch(&self, pairs: &[(K, V)]) -> Result<(), BackendError> {
+        let Some(attached) = self
+            .backend
+            .as_ref()
+            .filter(|a| a.policy.order == WriteOrder::BeforeVisible)
+        else {
+            return Ok(());
+        };
+        let previous: Vec<Option<V>> = {
+            let map = self.map.read().await;
+            pairs
+                .iter()
+                .map(|(k, _)| map.get(k).filter(|s| s.is_live()).map(|s| s.value.clone()))
+                .collect()
+        };
+        attached.store_batch(pairs, &previous).await
+    }
+
+    async fn persist_delete(&self, order: WriteOrder, k: &K) -> Result<(), BackendError> {
+        match &self.backend {
+            Some(attached) if attached.policy.order == order => {
+                attached.persist(attached.backend.delete(k)).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads a missing `k` from the backend and publishes it, unless an
+    /// insert got there first. A failed load is logged and counts as a miss,
+    /// the caller naps on.
+    async fn load_from_backend(&self, k: &K) -> Option<V> {
+        let attached = self.backend.as_ref()?;
+        if !self.allows(k, Operation::Get) {
+            return None;
+        }
+        let v = match attached.backend.load(k).await {
+            Ok(v) => v?,
+            Err(e) => {
+                tracing::error!("Backend load failed: {e}");
+                return None;
+            }
+        };
+        let mut map = self.map.write().await;
+        if let Some(slot) = map.get(k).filter(|s| s.is_live()) {
+            return Some(slot.value.clone());
+        }
+        self.store(&mut map, k.clone(), v.clone(), self.ttl);
+        drop(map);
+        self.wake(k).await;
+        Some(v)
+    }
+
+    async fn enqueue(&self, mutation: Mutation<K, V>) -> Result<(), BackendError> {
+        let Some(queue) = &self.write_behind else {
+            return Ok(());
+        };
+        queue
+            .send(mutation)
+            .await
+            .map_err(|_| BackendError("write-behind task has stopped".into()))
+    }
+
+    async fn publish(&self, k: K, v: V, ttl: Option<Duration>) {
+        self.store(&mut *self.map.write().await, k.clone(), v, ttl);
+        self.wake(&k).await;
+    }
+
+    async fn wake(&self, k: &K) {
+        if let Some(notify) = self.notifiers.lock().await.remove(k) {
+            notify.notify_waiters();
+            self.lifecycle("wake", k);
+            tracing::trace!("Notified all waiting tasks");
+        }
+    }
+
+    async fn publish_many(&self, pairs: Vec<(K, V)>) {
+        let mut map = self.map.write().await;
+        let keys: Vec<K> = pairs
+            .into_iter()
+            .map(|(k, v)| {
+                self.store(&mut map, k.clone(), v, self.ttl);
+                k
+            })
+            .collect();
+        drop(map);
+
+        let mut notifiers = self.notifiers.lock().await;
+        for k in &keys {
+            if let Some(notify) = notifiers.remove(k) {
+                notify.notify_waiters();
+                self.lifecycle("wake", k);
+            }
+        }
+        tracing::trace!("Notified all waiting tasks");
+    }
+
+    /// Like [`get`](Self::get), but repeated gets of `k` tagged with the same
+    /// `request`, e.g. retries of one handler, share a single nap and wakeup
+    /// instead of napping side by side.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn get_for_request(&self, k: K, request: u64) -> Option<V> {
+        self.requests.dedup(k.clone(), request, self.get(&k)).await
+    }
+
+    /// Same as [`get_checked`](Self::get_checked), a refused key is `None`.
+    ///
+    /// Takes any borrowed form of the key, like `&str` for `String` keys, and
+    /// only allocates an owned key when the fast path below misses.
+    ///
+    /// A hit first tries the lock without awaiting, a best-effort fast path:
+    /// while a writer holds or waits for the lock, the hit awaits like a miss.
+    /// Awaiting lookups spend from tokio's cooperative budget, so a task
+    /// looping over contended or missing keys yields to its peers once it
+    /// runs out.
+    pub async fn get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(v) = self.clone_now(k) {
+            self.counters.lookup(true);
+            return Some(v);
+        }
+        self.get_or_nap(k.to_owned(), false).await.ok()
+    }
+
+    /// Like [`get`](Self::get), but opts out of tokio's cooperative budget,
+    /// for callers that yield on their own terms.
+    pub async fn get_uncooperative<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        tokio::task::unconstrained(self.get(k)).await
+    }
+
+    /// Whether a task naps on `k` or computes it through
+    /// [`get_or_compute`](Self::get_or_compute) or
+    /// [`insert_from_future`](Self::insert_from_future), without registering
+    /// interest in it. Producers can skip results nobody waits for anymore.
+    pub async fn is_pending(&self, k: &K) -> bool {
+        if !self.allows(k, Operation::Get) {
+            return false;
+        }
+        let loading = lock(&self.loads).contains_key(k) || self.producers.contains(k);
+        loading || self.notifiers.lock().await.is_held(k)
+    }
+
+    /// Returns the value of `k`, or runs `compute` and inserts its output when
+    /// there is none. Concurrent calls for a missing key run `compute` once:
+    /// the first one computes while the others nap until the value lands.
+    /// A key the authorizer keeps from reads is computed without touching the
+    /// map.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn get_or_compute<F, Fut>(&self, k: K, compute: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        if !self.allows(&k, Operation::Get) {
+            return compute().await;
+        }
+        let guard = loop {
+            if let Some(v) = self.try_get(&k).await {
+                return v;
+            }
+            if self.is_hydrating() {
+                if let Some(v) = self.get_while_hydrating(&k).await {
+                    return v;
+                }
+            }
+            if let Some(guard) = LoadGuard::acquire(&self.loads, &self.loading, &k) {
+                break guard;
+            }
+            if let Some(notify) = in_flight(&self.loads, &k) {
+                let notified = notify.notified();
+                // The computation might have finished between the lookup and the registration
+                if lock(&self.loads).contains_key(&k) {
+                    crate::reentrant::check_nap(self.id(), &k);
+                    tracing::trace!("Waiting for in-flight computation...");
+                    notified.await;
+                }
+            }
+        };
+
+        tracing::trace!("Computing");
+        let v = crate::reentrant::producing(self.id(), &k, compute()).await;
+        self.insert(guard.k.clone(), v.clone()).await;
+        v
+    }
+
+    /// Like [`get_checked`](Self::get_checked), but gives up after `timeout`.
+    pub async fn get_timeout(&self, k: K, timeout: Duration) -> Result<V, GetError> {
+        self.get_deadline(k, Instant::now() + timeout).await
+    }
+
+    /// Like [`get_checked`](Self::get_checked), but gives up at `deadline`
+    /// with [`GetError::TimedOut`]. The last waiter giving up on a key drops
+    /// its notifier, so abandoned keys don't pile up.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn get_deadline(&self, k: K, deadline: Instant) -> Result<V, GetError> {
+        match tokio::time::timeout_at(deadline, self.get_checked(k)).await {
+            Ok(got) => got,
+            Err(_) => {
+                tracing::debug!("Gave up waiting");
+                Err(GetError::TimedOut)
+            }
+        }
+    }
+
+    /// Returns the value right away, from this map or its parents, `None` if
+    /// the key is absent rather than napping on it.
+    pub async fn try_get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let v = self.find(k).await;
+        self.counters.lookup(v.is_some());
+        v
+    }
+
+    /// Like [`try_get`](Self::try_get), also returning a value expired less
+    /// than the [`with_grace`](Self::with_grace) window ago, e.g. to serve a
+    /// slightly old value rather than an error. Only this map is looked up,
+    /// its parents aside.
+    pub async fn get_stale<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let map = self.map.read().await;
+        let v = map
+            .get_key_value(k)
+            .filter(|(k, s)| s.is_within(self.grace) && self.allows(k, Operation::Get))
+            .map(|(_, s)| s.value.clone());
+        self.counters.lookup(v.is_some());
+        v
+    }
+
+    /// Whether this map holds the key, its parents aside.
+    pub async fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let map = self.map.read().await;
+        map.get_key_value(k)
+            .is_some_and(|(k, s)| self.visible(k, s))
+    }
+
+    /// Naps until `k` is available. Fails right away when `k` would exceed
+    /// [`with_max_pending_keys`](Self::with_max_pending_keys), or when it is
+    /// still held past its TTL, with the instant it expired at. See
+    /// [`GetError`] for the other reasons to give up.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn get_checked(&self, k: K) -> Result<V, GetError> {
+        tracing::trace!("Get");
+        self.get_or_nap(k, true).await
+    }
+
+    /// Where `get` naps on expired keys like on missing ones, `get_checked`
+    /// reports them.
+    async fn get_or_nap(&self, k: K, report_expired: bool) -> Result<V, GetError> {
+        if !self.allows(&k, Operation::Get) {
+            return Err(GetError::Denied);
+        }
+        if let Some(v) = self.clone_out(&k).await {
+            tracing::debug!("Contains key");
+            self.counters.lookup(true);
+            return Ok(v);
+        }
+        self.counters.lookup(false);
+        loop {
+            if self.is_closed_for(&k) {
+                return Err(GetError::Closed);
+            }
+            if self.buried(&k) {
+                tracing::debug!("Removed");
+                return Err(GetError::Removed);
+            }
+            if let Some(v) = self.lookup_parents(&k).await {
+                tracing::debug!("Found in parent");
+                return Ok(v);
+            }
+            if let Some(at) = self.expired_at(&k).await.filter(|_| report_expired) {
+                tracing::debug!("Expired");
+                return Err(GetError::Expired { at });
+            }
+            if let Some(v) = self.load_from_backend(&k).await {
+                tracing::debug!("Loaded from the backend");
+                return Ok(v);
+            }
+
+            let mut notifies = vec![self.notifiers.lock().await.try_register(&k)?];
+            let mut current = self;
+            while let Some(parent) = &current.parent {
+                if parent.nap == ParentNap::Child {
+                    break;
+                }
+                notifies.push(parent.map.notifier(&k).await);
+                current = &parent.map;
+            }
+
+            let napping = notifies[0].enter();
+            let mut notified: Vec<_> = notifies.iter().map(|n| Box::pin(n.notified())).collect();
+            // Checked once registered, so a concurrent close or insert can't go unnoticed
+            if self.is_closed_for(&k) {
+                return Err(GetError::Closed);
+            }
+            if let Some(v) = self.find(&k).await {
+                return Ok(v);
+            }
+            let any = std::future::poll_fn(|cx| {
+                match notified.iter_mut().any(|n| n.as_mut().poll(cx).is_ready()) {
+                    true => Poll::Ready(()),
+                    false => Poll::Pending,
+                }
+            });
+            self.nap(&k, any).await;
+            if notifies[0].is_evicted() {
+                return Err(match self.is_closed_for(&k) {
+                    true => GetError::Closed,
+                    false => GetError::Evicted,
+                });
+            }
+            if let Some(v) = self.find(&k).await {
+                tracing::trace!("Notified, data is available");
+                napping.delivered();
+                return Ok(v);
+            }
+            tracing::debug!("Gone before it was read, napping again");
+        }
+    }
+
+    /// When `k` expired, if it is still held past its deadline.
+    async fn expired_at(&self, k: &K) -> Option<Instant> {
+        let map = self.map.read().await;
+        map.get(k).filter(|s| !s.is_live())?.expires_at
+    }
+
+    /// Tries to serve a hit without awaiting, `None` when a writer holds or
+    /// waits for the lock, the key is missing or its value is cloned on the
+    /// blocking pool.
+    fn clone_now<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let map = self.map.try_read().ok()?;
+        let (k, slot) = map
+            .get_key_value(k)
+            .filter(|(k, s)| s.is_live() && self.allows(k, Operation::Get))?;
+        if let Some((min_weight, _)) = &self.blocking_clone {
+            if self.weigh(k, &slot.value) >= *min_weight {
+                return None;
+            }
+        }
+        Some(slot.value.clone())
+    }
+
+    async fn clone_out<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let map = self.map.read().await;
+        let (k, slot) = map
+            .get_key_value(k)
+            .filter(|(k, s)| s.is_live() && self.allows(k, Operation::Get))?;
+        match &self.blocking_clone {
+            Some((min_weight, clone)) if self.weigh(k, &slot.value) >= *min_weight => {
+                let k = k.clone();
+                drop(map);
+                tracing::trace!("Cloning on the blocking pool");
+                clone(k).await
+            }
+            _ => Some(slot.value.clone()),
+        }
+    }
+
+    /// Like [`get`](Self::get), but also returns the version of the entry, to
+    /// be handed back to [`insert_if_version`](Self::insert_if_version).
+    ///
+    /// Only this map's own entries are considered, not its parent's.
+    pub async fn get_versioned(&self, k: K) -> Result<(V, Version), GetError> {
+        self.changed_since(k, Version(0)).await
+    }
+
+    /// Returns the entry right away if its version is past `version`, naps
+    /// until it is otherwise. Checking and napping in one call leaves no gap
+    /// for a write to slip through unnoticed. Fails with [`GetError::Denied`]
+    /// for a key the authorizer keeps from reads, with [`GetError::Closed`]
+    /// instead of napping once the key is closed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn changed_since(&self, k: K, version: Version) -> Result<(V, Version), GetError> {
+        tracing::trace!("Changed since");
+        if !self.allows(&k, Operation::Get) {
+            return Err(GetError::Denied);
+        }
+        loop {
+            let map = self.map.read().await;
+            if let Some(slot) = map.get(&k).filter(|s| s.is_live() && s.version > version) {
+                return Ok((slot.value.clone(), slot.version));
+            }
+            let notify = self.notifier(&k).await;
+            let notified = notify.notified();
+            drop(map);
+            // Checked once registered, so a concurrent close can't go unnoticed
+            if self.is_closed_for(&k) {
+                return Err(GetError::Closed);
+            }
+
+            self.nap(&k, notified).await;
+        }
+    }
+
+    /// Naps until `k` holds a value satisfying `predicate`, e.g. a job whose
+    /// status reached `Done`, checking it again on every write to `k`. Fails
+    /// with [`GetError::Closed`] instead of napping once the key is closed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, predicate))]
+    pub async fn wait_for(&self, k: K, predicate: impl Fn(&V) -> bool) -> Result<V, GetError> {
+        tracing::trace!("Wait for");
+        if !self.allows(&k, Operation::Get) {
+            return Err(GetError::Denied);
+        }
+        loop {
+            let map = self.map.read().await;
+            if let Some(slot) = map.get(&k).filter(|s| s.is_live() && predicate(&s.value)) {
+                return Ok(slot.value.clone());
+            }
+            let notify = self.notifier(&k).await;
+            let notified = notify.notified();
+            drop(map);
+            // Checked once registered, so a concurrent close can't go unnoticed
+            if self.is_closed_for(&k) {
+                return Err(GetError::Closed);
+            }
+
+            self.nap(&k, notified).await;
+        }
+    }
+
+    /// Identifies the map across its handles.
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.counters) as usize
+    }
+
+    async fn nap(&self, k: &K, notified: impl Future<Output = ()>) {
+        tracing::trace!("Waiting...");
+        crate::reentrant::check_nap(self.id(), k);
+        let _napping = self.napping.enter();
+        let started = Instant::now();
+        let mut notified = std::pin::pin!(notified);
+        match &self.slow_wait {
+            Some((threshold, callback)) => {
+                if tokio::time::timeout(*threshold, &mut notified)
+                    .await
+                    .is_err()
+                {
+                    tracing::debug!("Slow wait");
+                    // The timer may fire late on a busy runtime
+                    let elapsed = started.elapsed();
+                    self.hooks.call("slow_wait", || callback(k, elapsed));
+                    notified.await;
+                }
+            }
+            None => notified.await,
+        }
+        self.counters.napped(started.elapsed());
+    }
+
+    /// Streams the current value of `k`, if any, then the value of every
+    /// later write. Writes landing between two polls are coalesced into the
+    /// latest one, like with a `watch` channel. Ends right away for a key the
+    /// authorizer keeps from reads, and once the key is closed.
+    pub fn subscribe_key(&self, k: K) -> KeyUpdates<'_, K, V, S>
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+    {
+        KeyUpdates {
+            napmap: self,
+            k,
+            cursor: Version(0),
+            next: None,
+        }
+    }
+
+    /// Naps until the next write of `k`, ignoring the current value, and
+    /// returns the written value.
+    pub async fn next_update(&self, k: K) -> Result<V, GetError> {
+        let since = self
+            .map
+            .read()
+            .await
+            .get(&k)
+            .map_or(Version(0), |s| s.version);
+        Ok(self.changed_since(k, since).await?.0)
+    }
+
+    async fn peek_versioned(&self, k: &K) -> Option<(V, Version)> {
+        let map = self.map.read().await;
+        map.get(k)
+            .filter(|s| s.is_live())
+            .map(|s| (s.value.clone(), s.version))
+    }
+
+    async fn notifier(&self, k: &K) -> Registration<K, S> {
+        self.notifiers.lock().await.register(k)
+    }
+
+    /// The value of `k` in this map, or else in the closest parent holding it.
+    async fn find<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.clone_out(k).await {
+            Some(v) => Some(v),
+            None => self.lookup_parents(k).await,
+        }
+    }
+
+    async fn lookup_parents<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut current = self;
+        while let Some(parent) = &current.parent {
+            let map = parent.map.map.read().await;
+            if let Some(v) = map.get(k).filter(|s| s.is_live()).map(|s| s.value.clone()) {
+                return Some(v);
+            }
+            current = &parent.map;
+        }
+        None
+    }
+
+    /// Same as [`remove_checked`](Self::remove_checked), backend failures are
+    /// logged.
+    pub async fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.remove_checked(k.to_owned()).await {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::error!("{e}");
+                None
+            }
+        }
+    }
+
+    /// Like [`get`](Self::get), for synchronous callers: blocks the thread
+    /// until `k` is available. Meant for threads outside the runtime, so
+    /// configurations that need one, like
+    /// [`with_slow_wait`](Self::with_slow_wait) or
+    /// [`with_blocking_clone`](Self::with_blocking_clone), panic here.
+    ///
+    /// # Panics
+    ///
+    /// When called within a tokio runtime, like tokio's own blocking calls.
+    pub fn blocking_get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        crate::blocking::block_on(self.get(k))
+    }
+
+    /// Like [`insert`](Self::insert), for synchronous callers.
+    ///
+    /// # Panics
+    ///
+    /// When called within a tokio runtime.
+    pub fn blocking_insert(&self, k: K, v: V) {
+        crate::blocking::block_on(self.insert(k, v))
+    }
+
+    /// Like [`remove`](Self::remove), for synchronous callers.
+    ///
+    /// # Panics
+    ///
+    /// When called within a tokio runtime.
+    pub fn blocking_remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        crate::blocking::block_on(self.remove(k))
+    }
+
+    /// Removes `k` and returns its live value, failing if the backend does.
+    pub async fn remove_checked(&self, k: K) -> Result<Option<V>, BackendError> {
+        if !self.allows(&k, Operation::Remove) {
+            tracing::debug!("Remove denied");
+            return Ok(None);
+        }
+        self.persist_delete(WriteOrder::BeforeVisible, &k).await?;
+        let mut map = self.map.write().await;
+        let live = map.get(&k).is_some_and(Slot::is_live);
+        let removed = self.unstore(&mut map, &k).map(|(_, v)| v);
+        if let Some(v) = removed.as_ref().filter(|_| live) {
+            self.removed(&k, v);
+        }
+        drop(map);
+
+        if let Some(v) = &removed {
+            let cause = match live {
+                true => RemovalCause::Removed,
+                false => RemovalCause::Expired,
+            };
+            self.retire(k.clone(), v.clone(), cause);
+        }
+        self.persist_delete(WriteOrder::AfterVisible, &k).await?;
+        self.enqueue(Mutation::Delete(k)).await?;
+        Ok(removed.filter(|_| live))
+    }
+
+    /// Removes every entry the authorizer lets go, backend failures are
+    /// logged.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn clear(&self) {
+        tracing::trace!("Clear");
+        let map = self.map.write().await;
+        let keys: Vec<K> = map
+            .keys()
+            .filter(|k| self.allows(k, Operation::Remove))
+            .cloned()
+            .collect();
+        self.remove_locked(map, keys).await;
+    }
+
+    /// Removes every entry like [`clear`](Self::clear), returning the ones
+    /// still live in the given order.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn drain(&self, order: EntryOrder) -> Vec<(K, V)> {
+        tracing::trace!("Drain");
+        let map = self.map.write().await;
+        let mut keys: Vec<(&K, Version)> = map
+            .iter()
+            .filter(|(k, s)| s.is_live() && self.allows(k, Operation::Remove))
+            .map(|(k, s)| (k, s.version))
+            .collect();
+        if order == EntryOrder::Written {
+            keys.sort_by_key(|(_, version)| *version);
+        }
+        let keys: Vec<K> = keys.into_iter().map(|(k, _)| k.clone()).collect();
+        self.remove_locked(map, keys).await
+    }
+
+    /// Like [`drain`](Self::drain), sorted by key.
+    pub async fn drain_sorted(&self) -> Vec<(K, V)>
+    where
+        K: Ord,
+    {
+        let mut drained = self.drain(EntryOrder::Any).await;
+        drained.sort_by(|(a, _), (b, _)| a.cmp(b));
+        drained
+    }
+
+    /// Removes all `keys` under a single write lock and returns the live
+    /// entries that were present, backend failures are logged.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn remove_many(&self, keys: impl IntoIterator<Item = K>) -> Vec<(K, V)> {
+        tracing::trace!("Remove many");
+        let map = self.map.write().await;
+        let keys: Vec<K> = keys
+            .into_iter()
+            .filter(|k| map.contains_key(k) && self.allows(k, Operation::Remove))
+            .collect();
+        self.remove_locked(map, keys).await
+    }
+
+    /// Removes the entry only if its current value satisfies `predicate`, as
+    /// a single atomic step. Returns whether the entry was removed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, predicate))]
+    pub async fn remove_if(&self, k: K, predicate: impl FnOnce(&V) -> bool) -> bool {
+        tracing::trace!("Remove if");
+        if !self.allows(&k, Operation::Remove) {
+            return false;
+        }
+        let map = self.map.write().await;
+        if !map
+            .get(&k)
+            .is_some_and(|slot| slot.is_live() && predicate(&slot.value))
+        {
+            return false;
+        }
+        !self.remove_locked(map, vec![k]).await.is_empty()
+    }
+
+    /// Naps until `k` is available, then removes it in the same step. Each
+    /// value goes to exactly one of the concurrent takers of its key, the
+    /// others keep napping, e.g. for one-shot replies correlated by request
+    /// id. `None` once `k` is closed or the taker evicted, or right away if
+    /// the authorizer denies removing it.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn take(&self, k: K) -> Option<V> {
+        if !self.allows(&k, Operation::Remove) {
+            return None;
+        }
+        loop {
+            if let Some(v) = self.remove(&k).await {
+                return Some(v);
+            }
+            if self.is_closed_for(&k) {
+                return None;
+            }
+
+            let notify = self.notifiers.lock().await.register(&k);
+            let napping = notify.enter();
+            let notified = notify.notified();
+            // Checked once registered, so a concurrent close or insert can't go unnoticed
+            if self.is_closed_for(&k) {
+                return None;
+            }
+            if let Some(v) = self.remove(&k).await {
+                return Some(v);
+            }
+            self.nap(&k, notified).await;
+            if notify.is_evicted() {
+                return None;
+            }
+            if let Some(v) = self.remove(&k).await {
+                napping.delivered();
+                return Some(v);
+            }
+            tracing::debug!("Taken by another task, napping again");
+        }
+    }
+
+    async fn remove_locked(
+        &self,
+        mut map: RwLockWriteGuard<'_, HashMap<K, Slot<V>, S>>,
+        keys: Vec<K>,
+    ) -> Vec<(K, V)> {
+        for k in &keys {
+            if let Err(e) = self.persist_delete(WriteOrder::BeforeVisible, k).await {
+                tracing::error!("{e}");
+                return Vec::new();
+            }
+        }
+        let removed: Vec<(K, V, bool)> = keys
+            .into_iter()
+            .filter_map(|k| {
+                let live = map.get(&k).is_some_and(Slot::is_live);
+                let (k, v) = self.unstore(&mut map, &k)?;
+                Some((k, v, live))
+            })
+            .collect();
+        for (k, v, _) in removed.iter().filter(|(_, _, live)| *live) {
+            self.removed(k, v);
+        }
+        if map.is_empty() {
+            // Nothing left to expire, e.g. once cleared
+            self.expiry.clear();
+        }
+        drop(map);
+
+        for (k, v, live) in &removed {
+            if let Err(e) = self.persist_delete(WriteOrder::AfterVisible, k).await {
+                tracing::error!("{e}");
+            }
+            if let Err(e) = self.enqueue(Mutation::Delete(k.clone())).await {
+                tracing::error!("{e}");
+            }
+            if self.retires() {
+                let cause = match live {
+                    true => RemovalCause::Removed,
+                    false => RemovalCause::Expired,
+                };
+                self.retire(k.clone(), v.clone(), cause);
+            }
+        }
+        // Expired entries were already gone for readers
+        removed
+            .into_iter()
+            .filter_map(|(k, v, live)| live.then_some((k, v)))
+            .collect()
+    }
+
+    fn buried(&self, k: &K) -> bool {
+        self.tombstones.as_ref().is_some_and(|t| t.is_buried(k))
+    }
+
+    fn allows(&self, k: &K, operation: Operation) -> bool {
+        match &self.authorizer {
+            Some(authorize) => self
+                .hooks
+                .call("authorizer", || authorize(k, operation))
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+
+    /// Whether enumerations and bulk reads hand out the entry.
+    fn visible(&self, k: &K, slot: &Slot<V>) -> bool {
+        slot.is_live() && self.allows(k, Operation::Get)
+    }
+
+    fn removed(&self, k: &K, v: &V) {
+        self.counters.removal();
+        self.producers.cancel(k);
+        if let Some(tombstones) = &self.tombstones {
+            tombstones.bury(k);
+        }
+        if let Some(on_remove) = &self.on_remove {
+            self.hooks.call("on_remove", || on_remove(k, v));
+        }
+    }
+
+    /// Naps until every key is present, then reads all of them under a single
+    /// read lock, so the values come from one consistent point in time.
+    /// Fails with [`GetError::Denied`] if the authorizer keeps any of them
+    /// from reads, with [`GetError::Closed`] once one of the missing keys is
+    /// closed.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn get_all_atomic(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<Vec<V>, GetError> {
+        tracing::trace!("Get all");
+        let keys: Vec<K> = keys.into_iter().collect();
+        if !keys.iter().all(|k| self.allows(k, Operation::Get)) {
+            return Err(GetError::Denied);
+        }
+        loop {
+            let map = self.map.read().await;
+            let missing: Vec<&K> = keys
+                .iter()
+                .filter(|k| !map.get(*k).is_some_and(Slot::is_live))
+                .collect();
+            if missing.is_empty() {
+                return Ok(keys.iter().map(|k| map[k].value.clone()).collect());
+            }
+
+            // Registering while holding the read lock guarantees that no insert
+            // of a missing key can slip in unnoticed
+            let mut notifiers = self.notifiers.lock().await;
+            let notifies: Vec<Registration<K, S>> =
+                missing.iter().map(|k| notifiers.register(k)).collect();
+            drop(notifiers);
+            let mut notified: Vec<_> = notifies.iter().map(|n| Box::pin(n.notified())).collect();
+            drop(map);
+            // Checked once registered, so a concurrent close can't go unnoticed
+            if missing.iter().any(|k| self.is_closed_for(k)) {
+                return Err(GetError::Closed);
+            }
+
+            tracing::trace!("Waiting...");
+            let _napping = self.napping.enter();
+            // Any write or close ends the nap, the loop checks them all again
+            std::future::poll_fn(|cx| {
+                match notified.iter_mut().any(|n| n.as_mut().poll(cx).is_ready()) {
+                    true => Poll::Ready(()),
+                    false => Poll::Pending,
+                }
+            })
+            .await;
+        }
+    }
+
+    /// Same as [`get_all_atomic`](Self::get_all_atomic).
+    pub async fn get_many(&self, keys: impl IntoIterator<Item = K>) -> Result<Vec<V>, GetError> {
+        self.get_all_atomic(keys).await
+    }
+
+    /// Naps until any of `keys` is present and returns it with its value,
+    /// the earliest in `keys` if several are. Never resolves without keys.
+    /// Fails with [`GetError::Denied`] if the authorizer keeps any of them
+    /// from reads, with [`GetError::Closed`] once all of them are closed.
+    ///
+    /// Only this map's own entries are considered, not its parent's.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn wait_any(&self, keys: impl IntoIterator<Item = K>) -> Result<(K, V), GetError> {
+        tracing::trace!("Wait any");
+        let keys: Vec<K> = keys.into_iter().collect();
+        if !keys.iter().all(|k| self.allows(k, Operation::Get)) {
+            return Err(GetError::Denied);
+        }
+        loop {
+            let map = self.map.read().await;
+            let found = keys
+                .iter()
+                .find_map(|k| map.get_key_value(k).filter(|(_, s)| s.is_live()));
+            if let Some((k, slot)) = found {
+                return Ok((k.clone(), slot.value.clone()));
+            }
+
+            // Registering while holding the read lock guarantees that no insert
+            // of any key can slip in unnoticed
+            let mut notifiers = self.notifiers.lock().await;
+            let notifies: Vec<Registration<K, S>> =
+                keys.iter().map(|k| notifiers.register(k)).collect();
+            drop(notifiers);
+            let mut notified: Vec<_> = notifies.iter().map(|n| Box::pin(n.notified())).collect();
+            drop(map);
+            // Checked once registered, so a concurrent close can't go unnoticed
+            if !keys.is_empty() && keys.iter().all(|k| self.is_closed_for(k)) {
+                return Err(GetError::Closed);
+            }
+
+            tracing::trace!("Waiting...");
+            let _napping = self.napping.enter();
+            std::future::poll_fn(|cx| {
+                match notified.iter_mut().any(|n| n.as_mut().poll(cx).is_ready()) {
+                    true => Poll::Ready(()),
+                    false => Poll::Pending,
+                }
+            })
+            .await;
+        }
+    }
+
+    /// Returns the entries of `keys` currently present, read in a single pass
+    /// under the read lock. Never naps, missing and denied keys are left out.
+    ///
+    /// Only this map's own entries are considered, not its parent's.
+    pub async fn try_get_many(&self, keys: impl IntoIterator<Item = K>) -> HashMap<K, V> {
+        let map = self.map.read().await;
+        keys.into_iter()
+            .filter_map(|k| {
+                let slot = map.get(&k).filter(|s| self.visible(&k, s))?;
+                Some((k, slot.value.clone()))
+            })
+            .collect()
+    }
+
+    /// Total weight of the entries, see [`with_weigher`](Self::with_weigher).
+    pub async fn weight(&self) -> usize {
+        let map = self.map.read().await;
+        map.iter().map(|(k, s)| self.weigh(k, &s.value)).sum()
+    }
+
+    /// Drops the expired entries, handing them to the finalizer if any.
+    /// Returns how many there were. Only the entries due are visited, not the
+    /// whole map.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn purge_expired(&self) -> usize {
+        // Entries within their grace window stay until it is over
+        let Some(horizon) = Instant::now().checked_sub(self.grace) else {
+            return 0;
+        };
+        let mut map = self.map.write().await;
+        let expired: Vec<(K, V)> = self
+            .expiry
+            .due(horizon)
+            .into_iter()
+            .filter_map(|(k, at)| {
+                // Only purge the deadline the slot still has
+                map.get(&k).filter(|s| s.expires_at == Some(at))?;
+                self.unstore(&mut map, &k)
+            })
+            .collect();
+        drop(map);
+
+        let purged = expired.len();
+        self.swept("purge_expired", purged);
+        for (k, v) in expired {
+            self.retire(k, v, RemovalCause::Expired);
+        }
+        purged
+    }
+
+    /// The key due to expire first and when, e.g. to align an external timer
+    /// with the map. Entries already expired but not purged yet come first,
+    /// with an instant in the past.
+    pub async fn next_expiration(&self) -> Option<(K, Instant)> {
+        let map = self.map.read().await;
+        self.expiry
+            .earliest(|k, at| map.get(k).is_some_and(|s| s.expires_at == Some(at)))
+    }
+
+    /// Purges expired entries every `period` on a background task, which ends
+    /// at the first tick after every other handle to the map is dropped.
+    pub fn reap_expired_every(&self, period: Duration) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let map = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                // Every handle shares the counters, the task's own is the last
+                if Arc::strong_count(&map.counters) == 1 {
+                    break;
+                }
+                let reaped = map.purge_expired().await;
+                if reaped > 0 {
+                    tracing::debug!("Reaped {reaped} expired entries");
+                }
+            }
+        })
+    }
+
+    /// Evicts `fraction` (between 0 and 1) of the entries, picked arbitrarily.
+    /// Returns how many entries were evicted.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn shed(&self, fraction: f64) -> usize {
+        tracing::trace!("Shed");
+        let mut map = self.map.write().await;
+        let count = (map.len() as f64 * fraction.clamp(0.0, 1.0)).ceil() as usize;
+        let keys: Vec<K> = map.keys().take(count).cloned().collect();
+        let evicted: Vec<_> = keys
+            .iter()
+            .filter_map(|k| self.unstore(&mut map, k))
+            .collect();
+        drop(map);
+
+        let shed = evicted.len();
+        self.swept("shed", shed);
+        for (k, v) in evicted {
+            self.retire(k, v, RemovalCause::Evicted);
+        }
+        shed
+    }
+
+    /// Evicts entries, picked arbitrarily, until the total weight is at most
+    /// `target`. Returns how many entries were evicted.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn shrink_to_weight(&self, target: usize) -> usize {
+        tracing::trace!("Shrink to weight");
+        let mut map = self.map.write().await;
+        let mut total: usize = map.iter().map(|(k, s)| self.weigh(k, &s.value)).sum();
+        let mut evicted = Vec::new();
+        while total > target {
+            let Some((k, v)) = map
+                .keys()
+                .next()
+                .cloned()
+                .and_then(|k| self.unstore(&mut map, &k))
+            else {
+                break;
+            };
+            total -= self.weigh(&k, &v);
+            evicted.push((k, v));
+        }
+        drop(map);
+
+        let shed = evicted.len();
+        self.swept("shrink_to_weight", shed);
+        for (k, v) in evicted {
+            self.retire(k, v, RemovalCause::Evicted);
+        }
+        shed
+    }
+
+    /// Sheds entries on a background task each time `signal` changes to a
+    /// fraction above 0, which lets a memory-pressure probe evict entries
+    /// proactively. The task ends when the sender of `signal` is dropped.
+    pub fn shed_on(&self, mut signal: watch::Receiver<f64>) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let map = self.clone();
+        tokio::spawn(async move {
+            while signal.changed().await.is_ok() {
+                let fraction = *signal.borrow_and_update();
+                if fraction > 0.0 {
+                    let shed = map.shed(fraction).await;
+                    tracing::debug!("Shed {shed} entries under memory pressure");
+                }
+            }
+        })
+    }
+
+    /// Bulk-loads the pairs of `source` on a background task, e.g. a cache
+    /// dump at startup, skipping keys written meanwhile: a live write is
+    /// fresher than the dump. Gets are served and nap as usual in the
+    /// meantime, while [`get_or_compute`](Self::get_or_compute) holds off
+    /// computing a miss until hydration is over, so a cold start doesn't
+    /// stampede whatever computes the values. See
+    /// [`await_hydrated`](Self::await_hydrated).
+    pub fn hydrate<St>(&self, source: St) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+        St: Stream<Item = (K, V)> + Send + 'static,
+    {
+        self.hydrating.send_modify(|running| *running += 1);
+        let hydration = Hydration(self.hydrating.clone());
+        let map = self.clone();
+        tokio::spawn(async move {
+            let _hydration = hydration;
+            let mut source = std::pin::pin!(source);
+            while let Some((k, v)) = std::future::poll_fn(|cx| source.as_mut().poll_next(cx)).await
+            {
+                map.entry(k).await.or_insert(v).await;
+            }
+            tracing::debug!("Hydrated");
+        })
+    }
+
+    /// Whether a [`hydrate`](Self::hydrate) is still loading.
+    pub fn is_hydrating(&self) -> bool {
+        *self.hydrating.as_ref().borrow() > 0
+    }
+
+    /// Waits until no [`hydrate`](Self::hydrate) is loading anymore, e.g. to
+    /// report the service ready.
+    pub async fn await_hydrated(&self) {
+        let mut hydrating = self.hydrating.subscribe();
+        let _ = hydrating.wait_for(|running| *running == 0).await;
+    }
+
+    /// Naps on `k` until it lands or hydration is over, whichever first.
+    async fn get_while_hydrating(&self, k: &K) -> Option<V> {
+        let mut got = std::pin::pin!(self.get(k));
+        let mut hydrated = std::pin::pin!(self.await_hydrated());
+        std::future::poll_fn(|cx| match got.as_mut().poll(cx) {
+            Poll::Ready(v) => Poll::Ready(v),
+            Poll::Pending => hydrated.as_mut().poll(cx).map(|()| None),
+        })
+        .await
+    }
+
+    /// Inserts every pair received on `rx` on a background task, until all
+    /// senders are dropped.
+    pub fn feed_from_mpsc(&self, mut rx: mpsc::Receiver<(K, V)>) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let map = self.clone();
+        tokio::spawn(async move {
+            while let Some((k, v)) = rx.recv().await {
+                map.insert(k, v).await;
+            }
+            tracing::debug!("Feeding channel closed");
+        })
+    }
+
+    /// Inserts every pair received on `rx` on a background task, until the
+    /// channel is closed. Lagging behind the channel skips the missed pairs
+    /// with a warning, the next ones are still applied.
+    pub fn feed_from_broadcast(&self, mut rx: broadcast::Receiver<(K, V)>) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let map = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok((k, v)) => map.insert(k, v).await,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Feeding channel lagged, missed {missed} entries");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            tracing::debug!("Feeding channel closed");
+        })
+    }
+
+    fn weigh(&self, k: &K, v: &V) -> usize {
+        match &self.weigher {
+            Some(weigher) => self.hooks.call("weigher", || weigher(k, v)).unwrap_or(1),
+            None => 1,
+        }
+    }
+
+    fn check_weight(&self, k: &K, v: &V) -> Result<(), EntryTooLarge> {
+        let Some(max) = self.max_entry_weight else {
+            return Ok(());
+        };
+        let weight = self.weigh(k, v);
+        match weight > max {
+            true => Err(EntryTooLarge { weight, max }),
+            false => Ok(()),
+        }
+    }
+
+    /// Number of entries held, expired ones not reaped yet included.
+    pub async fn len(&self) -> usize {
+        self.map.read().await.len()
+    }
+
+    /// Whether the map holds no entry, see [`len`](Self::len).
+    pub async fn is_empty(&self) -> bool {
+        self.map.read().await.is_empty()
+    }
+
+    /// The keys present now. Like the other enumerations, copies out under
+    /// the read lock, so callers can take their time over the result.
+    pub async fn keys(&self) -> Vec<K> {
+        let map = self.map.read().await;
+        map.iter()
+            .filter(|(k, s)| self.visible(k, s))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// The values present now, in no particular order.
+    pub async fn values(&self) -> Vec<V> {
+        let map = self.map.read().await;
+        map.iter()
+            .filter(|(k, s)| self.visible(k, s))
+            .map(|(_, s)| s.value.clone())
+            .collect()
+    }
+
+    /// Up to `n` entries in no particular order, `None` for the expired ones,
+    /// e.g. to move them to another shard.
+    pub(crate) async fn some_entries(&self, n: usize) -> Vec<(K, Option<V>)> {
+        let map = self.map.read().await;
+        map.iter()
+            .take(n)
+            .map(|(k, s)| (k.clone(), s.is_live().then(|| s.value.clone())))
+            .collect()
+    }
+
+    /// The entries present now.
+    pub async fn iter(&self) -> std::vec::IntoIter<(K, V)> {
+        let map = self.map.read().await;
+        let entries: Vec<(K, V)> = map
+            .iter()
+            .filter(|(k, s)| self.visible(k, s))
+            .map(|(k, s)| (k.clone(), s.value.clone()))
+            .collect();
+        entries.into_iter()
+    }
+
+    /// Like [`iter`](Self::iter), in the given order.
+    pub async fn iter_in(&self, order: EntryOrder) -> std::vec::IntoIter<(K, V)> {
+        let map = self.map.read().await;
+        let mut entries: Vec<(&K, &Slot<V>)> =
+            map.iter().filter(|(k, s)| self.visible(k, s)).collect();
+        if order == EntryOrder::Written {
+            entries.sort_by_key(|(_, s)| s.version);
+        }
+        let entries: Vec<(K, V)> = entries
+            .into_iter()
+            .map(|(k, s)| (k.clone(), s.value.clone()))
+            .collect();
+        entries.into_iter()
+    }
+
+    /// Like [`iter`](Self::iter), sorted by key.
+    pub async fn iter_sorted(&self) -> std::vec::IntoIter<(K, V)>
+    where
+        K: Ord,
+    {
+        let mut entries: Vec<(K, V)> = self.iter().await.collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries.into_iter()
+    }
+
+    /// A copy of the map, for debugging and bulk export.
+    pub async fn snapshot(&self) -> HashMap<K, V> {
+        self.iter().await.collect()
+    }
+
+    /// The live entries, oldest write first, to be persisted and handed back
+    /// to [`restore_snapshot`](Self::restore_snapshot), e.g. across restarts
+    /// with the `save_to_writer` of the `serde` feature.
+    pub async fn to_snapshot(&self) -> MapSnapshot<K, V> {
+        MapSnapshot {
+            entries: self.iter_in(EntryOrder::Written).await.collect(),
+        }
+    }
+
+    /// Inserts the entries of `snapshot` like
+    /// [`insert_many`](Self::insert_many), waking the tasks napping on them.
+    pub async fn restore_snapshot(&self, snapshot: MapSnapshot<K, V>) {
+        self.insert_many(snapshot.entries).await;
+    }
+
+    /// Resolves once no task is napping on or computing into the map, e.g.
+    /// before shutting down or between the phases of a test.
+    pub async fn quiesce(&self) {
+        self.napping.drained().await;
+        self.loading.drained().await;
+    }
+
+    /// Keeps the waiters of the keys for which `f` returns `true`. Tasks
+    /// napping on any other key are woken with [`GetError::Evicted`], stored
+    /// values are left alone. Returns how many keys had their waiters evicted.
+    pub async fn retain_waiters(&self, f: impl FnMut(&K) -> bool) -> usize {
+        self.notifiers.lock().await.retain(f)
+    }
+
+    /// Closes the map for waiters: the tasks napping in
+    /// [`get_checked`](Self::get_checked), the lookups built on it and the
+    /// waits like [`changed_since`](Self::changed_since),
+    /// [`wait_for`](Self::wait_for), [`get_all_atomic`](Self::get_all_atomic)
+    /// or [`wait_any`](Self::wait_any) are woken with [`GetError::Closed`],
+    /// and later lookups of missing keys fail with it right away. Entries stay
+    /// readable and writable.
+    pub async fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let woken = self.notifiers.lock().await.retain(|_| false);
+        tracing::debug!("Closed, woke the waiters of {woken} keys");
+    }
+
+    /// Whether [`close`](Self::close) was called.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Like [`close`](Self::close), for the keys `f` returns `true` for only,
+    /// e.g. the keys of a decommissioned tenant. The rest of the map keeps
+    /// napping as usual.
+    pub async fn close_where(&self, f: impl Fn(&K) -> bool + Send + Sync + 'static) {
+        let f: KeyFilter<K> = Arc::new(f);
+        lock(&self.closed_keys).push(f.clone());
+        let woken = self.notifiers.lock().await.retain(|k| !f(k));
+        tracing::debug!("Closed some keys, woke the waiters of {woken}");
+    }
+
+    /// Closes the keys starting with `prefix`, see
+    /// [`close_where`](Self::close_where).
+    pub async fn close_prefix(&self, prefix: &str)
+    where
+        K: AsRef<str> + 'static,
+    {
+        let prefix = prefix.to_owned();
+        self.close_where(move |k| k.as_ref().starts_with(&prefix))
+            .await;
+    }
+
+    /// Whether lookups of `k` fail with [`GetError::Closed`] rather than nap,
+    /// through [`close`](Self::close) or [`close_where`](Self::close_where).
+    pub fn is_closed_for(&self, k: &K) -> bool {
+        self.is_closed() || lock(&self.closed_keys).iter().any(|f| f(k))
+    }
+
+    /// Size of the notifier table, see [`NotifierStats`].
+    pub async fn notifier_stats(&self) -> NotifierStats {
+        self.notifiers.stats().await
+    }
+
+    /// Most entries and waiters the map ever held at once, see [`Watermarks`].
+    pub fn watermarks(&self) -> Watermarks {
+        Watermarks {
+            entries: self.entries_peak.get(),
+            waiters: self.napping.peak(),
+        }
+    }
+
+    /// Starts a new observation window, from the current counts.
+    pub async fn reset_watermarks(&self) {
+        self.entries_peak.reset(self.len().await);
+        self.napping.reset_peak();
+    }
+
+    /// Lookup, write and nap counts since the map was built, see
+    /// [`NapMapStats`].
+    pub fn stats(&self) -> NapMapStats {
+        self.counters.stats(self.napping.count(), 0)
+    }
+}
+
+impl<K, V, S> Default for UnboundedNapMap<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: BuildHasher + Clone + Default,
+{
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> Debug for UnboundedNapMap<K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UnboundedNapMap")
+            .field("map", &self.map)
+            .field("notifiers", &self.notifiers)
+            .field("parent", &self.parent.as_ref().map(|p| &p.map))
+            .finish()
+    }
+}
+
+/// See [`UnboundedNapMap::overlay`].
+pub struct Overlay<'a, K, V, S = RandomState>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    top: &'a UnboundedNapMap<K, V, S>,
+    bottom: &'a UnboundedNapMap<K, V, S>,
+}
+
+impl<K, V, S> Overlay<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: BuildHasher + Clone,
+{
+    pub async fn try_get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.top.try_get(k).await {
+            Some(v) => Some(v),
+            None => self.bottom.try_get(k).await,
+        }
+    }
+
+    pub async fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.top.contains_key(k).await || self.bottom.contains_key(k).await
+    }
+
+    /// Naps until `k` is in either map.
+    #[tracing::instrument(level = tracing::Level::TRACE, skip_all)]
+    pub async fn get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(v) = self.try_get(k).await {
+            return Some(v);
+        }
+        let owned = k.to_owned();
+        loop {
+            // Registering before checking again leaves no gap for an insert
+            let top = self.top.notifier(&owned).await;
+            let bottom = self.bottom.notifier(&owned).await;
+            let mut notified = [Box::pin(top.notified()), Box::pin(bottom.notified())];
+            if let Some(v) = self.try_get(k).await {
+                return Some(v);
+            }
+            let any = std::future::poll_fn(|cx| {
+                match notified.iter_mut().any(|n| n.as_mut().poll(cx).is_ready()) {
+                    true => Poll::Ready(()),
+                    false => Poll::Pending,
+                }
+            });
+            self.top.nap(&owned, any).await;
+        }
+    }
+}
+
+impl<K, V, S> Debug for Overlay<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Overlay")
+            .field("top", &self.top)
+            .field("bottom", &self.bottom)
+            .finish()
+    }
+}
+
+/// See [`UnboundedNapMap::map_values`].
+pub struct MapValues<'a, K, V, F, S = RandomState>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    napmap: &'a UnboundedNapMap<K, V, S>,
+    f: F,
+}
+
+impl<K, V, U, F, S> MapValues<'_, K, V, F, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    F: Fn(&V) -> U,
+    S: BuildHasher + Clone,
+{
+    pub async fn try_get<Q>(&self, k: &Q) -> Option<U>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.napmap.try_get(k).await.map(|v| (self.f)(&v))
+    }
+
+    pub async fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.napmap.contains_key(k).await
+    }
+
+    /// Naps until `k` is available in the underlying map.
+    pub async fn get<Q>(&self, k: &Q) -> Option<U>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.napmap.get(k).await.map(|v| (self.f)(&v))
+    }
+
+    /// Like [`UnboundedNapMap::get_checked`], transformed.
+    pub async fn get_checked(&self, k: K) -> Result<U, GetError> {
+        self.napmap.get_checked(k).await.map(|v| (self.f)(&v))
+    }
+}
+
+impl<K, V, F, S> Debug for MapValues<'_, K, V, F, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MapValues")
+            .field("napmap", &self.napmap)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A key of an [`UnboundedNapMap`] under the map's write lock, see
+/// [`entry`](UnboundedNapMap::entry). Writes through the entry wake the tasks
+/// napping on the key, like an [`insert`](UnboundedNapMap::insert) does.
+pub struct Entry<'a, K, V, S = RandomState>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    napmap: &'a UnboundedNapMap<K, V, S>,
+    map: RwLockWriteGuard<'a, HashMap<K, Slot<V>, S>>,
+    k: K,
+}
+
+impl<K, V, S> Entry<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: BuildHasher + Clone,
+{
+    pub fn key(&self) -> &K {
+        &self.k
+    }
+
+    /// The live value, `None` for a key the authorizer keeps from reads.
+    fn current(&self) -> Option<&V> {
+        self.map
+            .get(&self.k)
+            .filter(|s| s.is_live() && self.napmap.allows(&self.k, Operation::Get))
+            .map(|s| &s.value)
+    }
+
+    /// Updates the value with `f` if the key is present.
+    pub async fn and_modify(mut self, f: impl FnOnce(&mut V)) -> Self {
+        if let Some(mut v) = self.current().cloned() {
+            f(&mut v);
+            self.write(v).await;
+        }
+        self
+    }
+
+    /// Returns the value, inserting `v` first if the key is absent.
+    pub async fn or_insert(self, v: V) -> V {
+        self.or_insert_with_async(|| async { v }).await
+    }
+
+    /// Returns the value, inserting the output of `f` first if the key is
+    /// absent. The write lock is held while `f` runs.
+    pub async fn or_insert_with_async<F, Fut>(mut self, f: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        if let Some(v) = self.current() {
+            return v.clone();
+        }
+        let v = f().await;
+        self.write(v.clone()).await;
+        v
+    }
+
+    async fn write(&mut self, v: V) {
+        let result = self
+            .napmap
+            .store_locked(&mut self.map, self.k.clone(), v)
+            .await;
+        if let Err(e) = result {
+            tracing::error!("{e}");
+        }
+    }
+}
+
+impl<K, V, S> Debug for Entry<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Entry")
+            .field("key", &self.k)
+            .field("value", &self.current())
+            .finish()
+    }
+}
+
+type Update<'a, V> = Pin<Box<dyn Future<Output = Result<(V, Version), GetError>> + Send + 'a>>;
+
+/// Stream of the values written to one key, see
+/// [`subscribe_key`](UnboundedNapMap::subscribe_key).
+pub struct KeyUpdates<'a, K, V, S = RandomState>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    napmap: &'a UnboundedNapMap<K, V, S>,
+    k: K,
+    cursor: Version,
+    next: Option<Update<'a, V>>,
+}
+
+impl<K, V, S> KeyUpdates<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug + Send + Sync,
+    V: Clone + Debug + Send + Sync,
+    S: BuildHasher + Clone + Send + Sync,
+{
+    /// Version of the last value yielded.
+    pub fn cursor(&self) -> Version {
+        self.cursor
+    }
+
+    /// Waits for the next value, for callers not using a stream combinator
+    /// crate.
+    pub async fn next(&mut self) -> Option<V> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+// Nothing is pinned in place, the pending write is boxed
+impl<K, V, S> Unpin for KeyUpdates<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+}
+
+impl<K, V, S> Stream for KeyUpdates<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug + Send + Sync,
+    V: Clone + Debug + Send + Sync,
+    S: BuildHasher + Clone + Send + Sync,
+{
+    type Item = V;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<V>> {
+        let this = self.get_mut();
+        let napmap = this.napmap;
+        let next = this
+            .next
+            .get_or_insert_with(|| Box::pin(napmap.changed_since(this.k.clone(), this.cursor)));
+        let update = std::task::ready!(next.as_mut().poll(cx));
+        this.next = None;
+        let Ok((v, version)) = update else {
+            return Poll::Ready(None);
+        };
+        this.cursor = version;
+        Poll::Ready(Some(v))
+    }
+}
+
+impl<K, V, S> Debug for KeyUpdates<'_, K, V, S>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyUpdates")
+            .field("key", &self.k)
+            .field("cursor", &self.cursor)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParentNap;
+    use super::UnboundedNapMap;
+    use crate::backend::Backend;
+    use crate::backend::BoxError;
+    use crate::backend::BoxFuture;
+    use crate::backend::InMemoryBackend;
+    use crate::backend::WriteBehind;
+    use crate::backend::WriteThrough;
+    use crate::changelog::Change;
+    use crate::changelog::Lagged;
+    use crate::error::EntryTooLarge;
+    use crate::error::GetError;
+    use crate::error::InsertError;
+    use crate::hash::BuildIdHasher;
+    use crate::hooks::Operation;
+    use crate::hooks::RemovalCause;
+    use crate::tombstones::AfterRemove;
+    use crate::version::EntryOrder;
+    use crate::version::SwapError;
+    use crate::version::Version;
+    use crate::version::VersionError;
+    use futures_core::Stream;
+    use std::collections::HashMap;
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::task::Context;
+    use std::task::Poll;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+    use tokio::sync::mpsc;
+    use tokio::sync::watch;
+    use tracing_subscriber::EnvFilter;
+
+    // Add this to a test to see the logs
+    fn _tracing_sub() {
+        let env_filter =
+            EnvFilter::from_default_env().add_directive("napmap=trace".parse().unwrap());
+        tracing_subscriber::fmt().with_env_filter(env_filter).init();
+    }
+
+    #[tokio::test]
+    async fn it_should_wait_until_data_is_inserted() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+
+        tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                map.insert("key", 7).await;
+            }
+        });
+
+        let res = napmap.get(&"key").await.unwrap();
+        assert_eq!(res, 7);
+    }
+
+    #[tokio::test]
+    async fn it_should_notify_all_waiters() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+
+        tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                map.insert("key", 7).await;
+            }
+        });
+
+        let first_handle = tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                let res = map.get(&"key").await.unwrap();
+                assert_eq!(res, 7);
+            }
+        });
+
+        let second_handle = tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                let res = map.get(&"key").await.unwrap();
+                assert_eq!(res, 7);
+            }
+        });
+
+        first_handle.await.unwrap();
+        second_handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_fall_back_to_the_parent() {
+        let parent = Arc::new(UnboundedNapMap::new());
+        parent.insert("default", 1).await;
+        let child = UnboundedNapMap::new().with_parent(parent.clone(), ParentNap::Child);
+        child.insert("override", 2).await;
+
+        assert_eq!(child.get(&"default").await, Some(1));
+        assert_eq!(child.get(&"override").await, Some(2));
+        assert_eq!(parent.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_nap_on_the_parent_too() {
+        let parent = Arc::new(UnboundedNapMap::new());
+        let child = UnboundedNapMap::new().with_parent(parent.clone(), ParentNap::Both);
+
+        tokio::spawn({
+            let parent = parent.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                parent.insert("key", 7).await;
+            }
+        });
+
+        assert_eq!(child.get(&"key").await, Some(7));
+    }
+
+    #[derive(Default)]
+    struct MemoryBackend {
+        store: Arc<Mutex<HashMap<&'static str, i32>>>,
+        fail: bool,
+        reject: Option<&'static str>,
+        /// Stores failing before the backend recovers.
+        outage: Arc<AtomicUsize>,
+    }
+
+    impl Backend<&'static str, i32> for MemoryBackend {
+        fn store<'a>(
+            &'a self,
+            k: &'a &'static str,
+            v: &'a i32,
+        ) -> BoxFuture<'a, Result<(), BoxError>> {
+            Box::pin(async move {
+                if self.fail || self.reject == Some(*k) {
+                    return Err("unavailable".into());
+                }
+                let down = self
+                    .outage
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+                if down.is_ok() {
+                    return Err("unavailable".into());
+                }
+                self.store.lock().unwrap().insert(k, *v);
+                Ok(())
+            })
+        }
+
+        fn delete<'a>(&'a self, k: &'a &'static str) -> BoxFuture<'a, Result<(), BoxError>> {
+            Box::pin(async move {
+                self.store.lock().unwrap().remove(k);
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_write_through_to_the_backend() {
+        let backend = MemoryBackend::default();
+        let store = backend.store.clone();
+        let napmap = UnboundedNapMap::new().with_backend(backend, WriteThrough::default());
+
+        napmap.insert("key", 7).await;
+        assert_eq!(store.lock().unwrap().get(&"key"), Some(&7));
+
+        napmap.remove(&"key").await;
+        assert!(store.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_should_load_missing_keys_from_the_backend() {
+        let backend: InMemoryBackend<_, _> = [("stored", 7)].into_iter().collect();
+        let napmap = UnboundedNapMap::new().with_backend(backend.clone(), WriteThrough::default());
+
+        assert_eq!(napmap.get(&"stored").await, Some(7));
+        assert!(napmap.contains_key(&"stored").await);
+
+        napmap.insert("fresh", 1).await;
+        assert_eq!(backend.len(), 2);
+        assert_eq!(napmap.try_get(&"missing").await, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_not_publish_when_the_backend_fails() {
+        let backend = MemoryBackend {
+            fail: true,
+            ..Default::default()
+        };
+        let napmap = UnboundedNapMap::new().with_backend(backend, WriteThrough::default());
+
+        assert!(napmap.insert_checked("key", 7).await.is_err());
+        assert!(napmap.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn it_should_roll_back_a_failed_batch_in_the_backend() {
+        let backend = MemoryBackend {
+            reject: Some("rogue"),
+            ..Default::default()
+        };
+        let store = backend.store.clone();
+        let napmap = UnboundedNapMap::new().with_backend(backend, WriteThrough::default());
+        napmap.insert("kept", 1).await;
+
+        let batch = napmap
+            .insert_batch_atomic([("kept", 2), ("new", 3), ("rogue", 4)])
+            .await;
+        assert!(matches!(batch, Err(InsertError::Backend(_))));
+        assert_eq!(*store.lock().unwrap(), HashMap::from([("kept", 1)]));
+        assert_eq!(napmap.try_get(&"kept").await, Some(1));
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_flush_queued_writes_in_the_background() {
+        let backend = MemoryBackend::default();
+        let store = backend.store.clone();
+        let config = WriteBehind {
+            flush_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let napmap = UnboundedNapMap::new().with_write_behind(backend, config);
+
+        napmap.insert("first", 1).await;
+        napmap.insert("second", 2).await;
+        assert_eq!(napmap.get(&"first").await, Some(1));
+        assert!(store.lock().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(store.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_retry_failed_flushes_with_backoff() {
+        let backend = MemoryBackend {
+            outage: Arc::new(AtomicUsize::new(2)),
+            ..Default::default()
+        };
+        let store = backend.store.clone();
+        let outage = backend.outage.clone();
+        let config = WriteBehind {
+            flush_interval: Duration::from_millis(10),
+            retry_backoff: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let napmap = UnboundedNapMap::new().with_write_behind(backend, config);
+        napmap.insert("first", 1).await;
+
+        // Failed at 10ms, then at 110ms, stored at 310ms
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(outage.load(Ordering::Relaxed), 0);
+        assert!(store.lock().unwrap().is_empty());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(*store.lock().unwrap(), HashMap::from([("first", 1)]));
+    }
+
+    #[tokio::test]
+    async fn it_should_publish_a_batch_at_once() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+
+        let waiter = tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                let second = map.get(&"second").await.unwrap();
+                assert_eq!(map.len().await, 2);
+                second
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        napmap
+            .insert_batch_atomic([("first", 1), ("second", 2)])
+            .await
+            .unwrap();
+        assert_eq!(waiter.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_read_many_keys_at_once() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        napmap.insert("first", 1).await;
+
+        tokio::spawn({
+            let map = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                map.insert("second", 2).await;
+            }
+        });
+
+        let res = napmap.get_all_atomic(["first", "second"]).await.unwrap();
+        assert_eq!(res, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn it_should_apply_concurrent_rcu_updates() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+
+        let handles: Vec<_> = (0..10)
+            .map(|_| {
+                let map = napmap.clone();
+                tokio::spawn(async move {
+                    map.rcu("counter", |v| v.copied().unwrap_or(0) + 1)
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(napmap.get(&"counter").await, Some(10));
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_stale_versions() {
+        let napmap = UnboundedNapMap::new();
+        let first = napmap.insert_if_version("key", 1, None).await.unwrap();
+        assert!(napmap.insert_if_version("key", 1, None).await.is_err());
+
+        let (v, version) = napmap.get_versioned("key").await.unwrap();
+        assert_eq!((v, version), (1, first));
+        napmap.insert("key", 2).await;
+
+        let res = napmap.insert_if_version("key", 3, Some(version)).await;
+        assert!(matches!(res, Err(VersionError::Mismatch { current: Some(c) }) if c > first));
+        assert_eq!(napmap.get(&"key").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn it_should_finalize_removed_and_replaced_values() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let napmap = UnboundedNapMap::new().with_finalizer(move |k, v| {
+            let tx = tx.clone();
+            async move {
+                tx.send((k, v)).unwrap();
+            }
+        });
+
+        napmap.insert("key", 1).await;
+        napmap.insert("key", 2).await;
+        assert_eq!(rx.recv().await, Some(("key", 1)));
+
+        napmap.remove(&"key").await;
+        assert_eq!(rx.recv().await, Some(("key", 2)));
+    }
+
+    #[tokio::test]
+    async fn it_should_report_removed_entries_inline() {
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let napmap = UnboundedNapMap::new().with_on_remove({
+            let removed = removed.clone();
+            move |k: &&str, v: &i32| removed.lock().unwrap().push((*k, *v))
+        });
+
+        napmap.insert("first", 1).await;
+        napmap.insert("second", 2).await;
+        napmap.remove(&"first").await;
+        assert_eq!(*removed.lock().unwrap(), vec![("first", 1)]);
+
+        napmap.clear().await;
+        assert!(napmap.is_empty().await);
+        assert_eq!(removed.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_shed_entries() {
+        let napmap = Arc::new(UnboundedNapMap::new().with_weigher(|_, v: &usize| *v));
+        for i in 1..=4 {
+            napmap.insert(i, i * 10).await;
+        }
+        assert_eq!(napmap.weight().await, 100);
+
+        assert_eq!(napmap.shed(0.5).await, 2);
+        assert_eq!(napmap.len().await, 2);
+
+        napmap.shrink_to_weight(0).await;
+        assert!(napmap.is_empty().await);
+
+        let (tx, rx) = watch::channel(0.0);
+        napmap.insert(1, 1).await;
+        let task = napmap.shed_on(rx);
+        tx.send(1.0).unwrap();
+        drop(tx);
+        task.await.unwrap();
+        assert!(napmap.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn it_should_remove_many_keys_at_once() {
+        let napmap = UnboundedNapMap::new();
+        napmap
+            .insert_batch_atomic([("first", 1), ("second", 2), ("third", 3)])
+            .await
+            .unwrap();
+
+        let mut removed = napmap.remove_many(["first", "third", "missing"]).await;
+        removed.sort();
+        assert_eq!(removed, vec![("first", 1), ("third", 3)]);
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_remove_only_matching_values() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("key", 1).await;
+
+        assert!(!napmap.remove_if("key", |v| *v == 2).await);
+        assert!(napmap.remove_if("key", |v| *v == 1).await);
+        assert!(!napmap.remove_if("key", |_| true).await);
+        assert!(napmap.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn it_should_stream_changes_since_a_version() {
+        let napmap = UnboundedNapMap::new().with_changelog(8);
+        napmap.insert("first", 1).await;
+        let since = napmap.version();
+        napmap.insert("second", 2).await;
+        napmap.remove(&"first").await;
+
+        let mut changes = napmap.changes_since(since).unwrap();
+        assert_eq!(
+            changes.next().await,
+            Some(Ok(Change::Insert {
+                key: "second",
+                value: 2,
+                version: Version(2)
+            }))
+        );
+        assert_eq!(
+            changes.next().await,
+            Some(Ok(Change::Remove {
+                key: "first",
+                version: Version(3)
+            }))
+        );
+
+        let napmap = Arc::new(napmap);
+        let writer = napmap.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.insert("third", 3).await;
+        });
+        assert_eq!(changes.next().await.unwrap().unwrap().version(), Version(4));
+        assert_eq!(changes.cursor(), Version(4));
+    }
+
+    #[tokio::test]
+    async fn it_should_signal_lag_when_changes_were_dropped() {
+        let napmap = UnboundedNapMap::new().with_changelog(2);
+        for i in 0..5 {
+            napmap.insert(i, i).await;
+        }
+
+        let mut changes = napmap.changes_since(Version(1)).unwrap();
+        assert_eq!(changes.next().await, Some(Err(Lagged(2))));
+        assert_eq!(changes.next().await.unwrap().unwrap().version(), Version(4));
+        assert_eq!(changes.next().await.unwrap().unwrap().version(), Version(5));
+
+        drop(napmap);
+        assert_eq!(changes.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_not_stream_without_a_changelog() {
+        let napmap = UnboundedNapMap::<&str, i32>::new();
+        assert!(napmap.changes_since(Version(0)).is_none());
+    }
+
+    #[tokio::test]
+    async fn it_should_lag_a_stalled_subscriber() {
+        let napmap = UnboundedNapMap::new().with_changelog(4);
+        napmap.insert("before", 0).await;
+
+        let mut events = napmap.subscribe().unwrap();
+        for i in 1..=10 {
+            napmap.insert("key", i).await;
+        }
+        assert_eq!(events.next().await, Some(Err(Lagged(6))));
+        assert_eq!(
+            events.next().await,
+            Some(Ok(Change::Insert {
+                key: "key",
+                value: 7,
+                version: Version(8)
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_feed_from_channels() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+
+        let (tx, rx) = mpsc::channel(4);
+        let task = napmap.feed_from_mpsc(rx);
+        tx.send(("first", 1)).await.unwrap();
+        drop(tx);
+        task.await.unwrap();
+        assert_eq!(napmap.get(&"first").await, Some(1));
+
+        let (tx, rx) = broadcast::channel(1);
+        tx.send(("lost", 0)).unwrap();
+        tx.send(("second", 2)).unwrap();
+        let task = napmap.feed_from_broadcast(rx);
+        drop(tx);
+        task.await.unwrap();
+        assert_eq!(napmap.get(&"second").await, Some(2));
+        assert_eq!(napmap.len().await, 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_forward_matching_entries() {
+        let napmap = UnboundedNapMap::new().with_changelog(8);
+        napmap.insert("even", 2).await;
+        napmap.insert("odd", 1).await;
+
+        let (tx, mut rx) = mpsc::channel(8);
+        napmap
+            .forward_matching(|_, v| v % 2 == 0, tx)
+            .await
+            .unwrap();
+        napmap.insert("odd", 3).await;
+        napmap.insert("even", 4).await;
+
+        assert_eq!(rx.recv().await, Some(("even", 2)));
+        assert_eq!(rx.recv().await, Some(("even", 4)));
+        drop(napmap);
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_quiesce_once_nobody_naps() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        napmap.quiesce().await;
+
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let quiesce = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.quiesce().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!quiesce.is_finished());
+
+        napmap.insert("key", 1).await;
+        quiesce.await.unwrap();
+        assert_eq!(get.await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_report_notifier_stats() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let gets: Vec<_> = ["first", "second"]
+            .into_iter()
+            .map(|k| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get(&k).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let stats = napmap.notifier_stats().await;
+        assert_eq!((stats.keys, stats.peak), (2, 2));
+
+        napmap.insert("first", 1).await;
+        napmap.insert("second", 1).await;
+        for get in gets {
+            get.await.unwrap();
+        }
+        let stats = napmap.notifier_stats().await;
+        assert_eq!((stats.keys, stats.peak), (0, 2));
+    }
+
+    #[tokio::test]
+    async fn it_should_refuse_keys_past_the_pending_limit() {
+        let napmap = Arc::new(UnboundedNapMap::new().with_max_pending_keys(1));
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"first").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            napmap.get_checked("second").await,
+            Err(GetError::TooManyPendingKeys { limit: 1 })
+        );
+        assert_eq!(napmap.get(&"second").await, None);
+
+        napmap.insert("first", 1).await;
+        assert_eq!(get.await.unwrap(), Some(1));
+        napmap.insert("second", 2).await;
+        assert_eq!(napmap.get_checked("second").await, Ok(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_not_nap_on_tombstoned_keys() {
+        let napmap = UnboundedNapMap::new().with_tombstones(Duration::from_secs(10));
+        napmap.insert("key", 1).await;
+        napmap.remove(&"key").await;
+        assert_eq!(napmap.get_checked("key").await, Err(GetError::Removed));
+        assert_eq!(napmap.get(&"key").await, None);
+
+        napmap.insert("key", 2).await;
+        assert_eq!(napmap.get_checked("key").await, Ok(2));
+
+        napmap.remove(&"key").await;
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let napped = tokio::time::timeout(Duration::from_secs(1), napmap.get(&"key")).await;
+        assert!(napped.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_pick_what_get_does_after_a_removal() {
+        let napmap = UnboundedNapMap::new().with_after_remove(AfterRemove::Removed);
+        napmap.insert("key", 1).await;
+        napmap.remove(&"key").await;
+        assert_eq!(napmap.get_checked("key").await, Err(GetError::Removed));
+
+        let napmap = UnboundedNapMap::new().with_after_remove(AfterRemove::Nap);
+        napmap.insert("key", 1).await;
+        napmap.remove(&"key").await;
+        let napped = tokio::time::timeout(Duration::from_millis(50), napmap.get(&"key")).await;
+        assert!(napped.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_wait_for_the_next_update() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        napmap.insert("key", 1).await;
+
+        let update = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.next_update("key").await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!update.is_finished());
+
+        napmap.insert("key", 1).await;
+        assert_eq!(update.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_return_changes_past_a_version() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        napmap.insert("key", 1).await;
+        let (_, seen) = napmap.get_versioned("key").await.unwrap();
+        assert_eq!(
+            napmap.changed_since("key", Version(0)).await.unwrap(),
+            (1, seen)
+        );
+
+        let changed = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.changed_since("key", seen).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!changed.is_finished());
+
+        napmap.insert("key", 2).await;
+        let (v, version) = changed.await.unwrap();
+        assert_eq!(v, 2);
+        assert!(version > seen);
+    }
+
+    #[tokio::test]
+    async fn it_should_clone_heavy_values_on_the_blocking_pool() {
+        let napmap = UnboundedNapMap::new()
+            .with_weigher(|_, v: &Vec<u8>| v.len())
+            .with_blocking_clone(1024);
+        napmap.insert("light", vec![0; 8]).await;
+        napmap.insert("heavy", vec![0; 4096]).await;
+
+        assert_eq!(napmap.get(&"light").await.unwrap().len(), 8);
+        assert_eq!(napmap.get(&"heavy").await.unwrap().len(), 4096);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_oversized_entries() {
+        let napmap = UnboundedNapMap::new()
+            .with_weigher(|_, v: &Vec<u8>| v.len())
+            .with_max_entry_weight(16);
+        napmap.insert_checked("small", vec![0; 16]).await.unwrap();
+
+        let err = napmap
+            .insert_checked("rogue", vec![0; 17])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InsertError::TooLarge(EntryTooLarge {
+                weight: 17,
+                max: 16
+            })
+        ));
+        assert!(napmap
+            .insert_batch_atomic([("other", vec![0; 1]), ("rogue", vec![0; 17])])
+            .await
+            .is_err());
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_report_slow_waits_before_they_end() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let napmap = Arc::new(UnboundedNapMap::new().with_slow_wait(
+            Duration::from_secs(5),
+            move |k, elapsed| {
+                tx.send((*k, elapsed)).unwrap();
+            },
+        ));
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+
+        assert_eq!(rx.recv().await, Some(("key", Duration::from_secs(5))));
+        assert!(!get.is_finished());
+        napmap.insert("key", 1).await;
+        assert_eq!(get.await.unwrap(), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_share_a_nap_per_request() {
+        let slow = Arc::new(AtomicUsize::new(0));
+        let napmap = Arc::new(
+            UnboundedNapMap::new().with_slow_wait(Duration::from_secs(1), {
+                let slow = slow.clone();
+                move |_, _| {
+                    slow.fetch_add(1, Ordering::SeqCst);
+                }
+            }),
+        );
+        let gets: Vec<_> = (0..3)
+            .map(|_| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get_for_request("key", 7).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(slow.load(Ordering::SeqCst), 1);
+
+        napmap.insert("key", 1).await;
+        for get in gets {
+            assert_eq!(get.await.unwrap(), Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_ack_once_waiters_observed_the_value() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let gets: Vec<_> = (0..3)
+            .map(|_| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get(&"key").await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let delivered = napmap.insert_acked("key", 1, Duration::from_secs(1)).await;
+        assert_eq!(delivered, 3);
+        for get in gets {
+            assert_eq!(get.await.unwrap(), Some(1));
+        }
+        assert_eq!(
+            napmap.insert_acked("key", 2, Duration::from_secs(1)).await,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_await_a_version() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        napmap.insert("a", 1).await;
+        napmap.await_version(Version(1)).await;
+
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.await_version(Version(3)).await }
+        });
+        napmap.insert("b", 2).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+
+        napmap.remove(&"a").await;
+        waiter.await.unwrap();
+        assert_eq!(napmap.version(), Version(3));
+    }
+
+    #[tokio::test]
+    async fn it_should_share_snapshots_until_the_map_changes() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("a", 1).await;
+
+        let first = napmap.snapshot_arc().await;
+        let second = napmap.snapshot_arc().await;
+        assert!(Arc::ptr_eq(&first, &second));
+
+        napmap.insert("b", 2).await;
+        let third = napmap.snapshot_arc().await;
+        assert_eq!(first.len(), 1);
+        assert_eq!(third.get(&"b"), Some(&2));
+    }
+
+    #[tokio::test]
+    async fn it_should_give_up_at_the_deadline() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let abandoned = napmap.get_timeout("key", Duration::from_millis(20)).await;
+        assert_eq!(abandoned, Err(GetError::TimedOut));
+        assert_eq!(napmap.notifier_stats().await.keys, 0);
+
+        tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                napmap.insert("key", 1).await;
+            }
+        });
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        assert_eq!(napmap.get_deadline("key", deadline).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_evict_waiters() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let gets: Vec<_> = ["tenant-a/1", "tenant-b/1"]
+            .into_iter()
+            .map(|k| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get_checked(k).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let evicted = napmap.retain_waiters(|k| !k.starts_with("tenant-a/")).await;
+        assert_eq!(evicted, 1);
+        napmap.insert("tenant-b/1", 1).await;
+
+        let [a, b] = <[_; 2]>::try_from(gets).unwrap();
+        assert!(matches!(a.await.unwrap(), Err(GetError::Evicted)));
+        assert_eq!(b.await.unwrap(), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_probe_without_napping() {
+        let napmap = UnboundedNapMap::new();
+        assert_eq!(napmap.try_get(&"key").await, None);
+        assert!(!napmap.contains_key(&"key").await);
+        assert_eq!(napmap.notifier_stats().await.keys, 0);
+
+        napmap.insert("key", 1).await;
+        assert_eq!(napmap.try_get(&"key").await, Some(1));
+        assert!(napmap.contains_key(&"key").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_expire_entries() {
+        let napmap = Arc::new(UnboundedNapMap::new().with_ttl(Duration::from_secs(60)));
+        napmap.insert("default", 1).await;
+        napmap
+            .insert_with_ttl("short", 2, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(napmap.try_get(&"short").await, None);
+        assert_eq!(napmap.try_get(&"default").await, Some(1));
+
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"short").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!get.is_finished());
+
+        assert_eq!(napmap.purge_expired().await, 1);
+        napmap.insert("short", 3).await;
+        assert_eq!(get.await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn it_should_look_up_borrowed_keys() {
+        let napmap: Arc<UnboundedNapMap<String, i32>> = Arc::new(UnboundedNapMap::new());
+        napmap.insert("key".to_string(), 1).await;
+        assert!(napmap.contains_key("key").await);
+        assert_eq!(napmap.get("key").await, Some(1));
+        assert_eq!(napmap.remove("key").await, Some(1));
+
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get("late").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        napmap.insert("late".to_string(), 2).await;
+        assert_eq!(get.await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn it_should_overlay_two_maps() {
+        let current = Arc::new(UnboundedNapMap::new());
+        let previous = Arc::new(UnboundedNapMap::new());
+        previous.insert("old", 1).await;
+        previous.insert("shared", 1).await;
+        current.insert("shared", 2).await;
+
+        let overlay = current.overlay(&previous);
+        assert_eq!(overlay.get(&"old").await, Some(1));
+        assert_eq!(overlay.get(&"shared").await, Some(2));
+        assert!(!overlay.contains_key(&"new").await);
+
+        tokio::spawn({
+            let previous = previous.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                previous.insert("new", 3).await;
+            }
+        });
+        assert_eq!(overlay.get(&"new").await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn it_should_compute_a_missing_value_once() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut handles = Vec::new();
+        for _ in 0..5 {
+            let napmap = napmap.clone();
+            let calls = calls.clone();
+            handles.push(tokio::spawn(async move {
+                napmap
+                    .get_or_compute("key", || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        1
+                    })
+                    .await
+            }));
+        }
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), 1);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(napmap.get_or_compute("key", || async { 2 }).await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_write_through_an_entry() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let v = napmap.entry("key").await.or_insert(1).await;
+        assert_eq!(v, 1);
+        assert_eq!(waiter.await.unwrap(), Some(1));
+
+        let v = napmap
+            .entry("key")
+            .await
+            .and_modify(|v| *v += 1)
+            .await
+            .or_insert_with_async(|| async { unreachable!() })
+            .await;
+        assert_eq!(v, 2);
+        let absent = napmap.entry("other").await.and_modify(|v| *v += 1).await;
+        assert_eq!(absent.or_insert(10).await, 10);
+    }
+
+    #[tokio::test]
+    async fn it_should_track_high_water_marks() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"d").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        napmap
+            .insert_batch_atomic([("a", 1), ("b", 2), ("c", 3)])
+            .await
+            .unwrap();
+        napmap.remove_many(["a", "b"]).await;
+        napmap.insert("d", 4).await;
+        waiter.await.unwrap();
+
+        let marks = napmap.watermarks();
+        assert_eq!(marks.entries.peak, 3);
+        assert_eq!(marks.waiters.peak, 1);
+        assert!(marks.entries.at.is_some());
+
+        napmap.reset_watermarks().await;
+        let marks = napmap.watermarks();
+        assert_eq!(marks.entries.peak, 2);
+        assert_eq!(marks.waiters.peak, 0);
+        assert_eq!(marks.waiters.at, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_tell_whether_a_key_is_pending() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        assert!(!napmap.is_pending(&"key").await);
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(napmap.is_pending(&"key").await);
+
+        napmap.insert("key", 1).await;
+        waiter.await.unwrap();
+        assert!(!napmap.is_pending(&"key").await);
+
+        let compute = tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                napmap
+                    .get_or_compute("other", || async {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        2
+                    })
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(napmap.is_pending(&"other").await);
+        compute.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_drop_the_notifiers_of_cancelled_waiters() {
+        let napmap = Arc::new(UnboundedNapMap::<&str, i32>::new());
+        let first = tokio::time::timeout(Duration::from_millis(10), napmap.get(&"key"));
+        let aborted = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(napmap.notifier_stats().await.keys, 1);
+
+        assert!(first.await.is_err());
+        assert_eq!(napmap.notifier_stats().await.keys, 1);
+        aborted.abort();
+        assert!(aborted.await.unwrap_err().is_cancelled());
+        assert_eq!(napmap.notifier_stats().await.keys, 0);
+    }
+
+    #[tokio::test]
+    async fn it_should_only_stream_changes_passing_the_filter() {
+        let napmap = UnboundedNapMap::new().with_changelog(8);
+        let mut changes = napmap
+            .subscribe_filtered(|c| matches!(c, Change::Insert { value, .. } if value % 2 == 0))
+            .unwrap();
+        for (k, v) in [("a", 1), ("b", 2), ("c", 3), ("d", 4)] {
+            napmap.insert(k, v).await;
+        }
+        napmap.remove(&"b").await;
+        drop(napmap);
+
+        let mut got = Vec::new();
+        while let Some(change) = changes.next().await {
+            got.push(change.unwrap());
+        }
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[1].version(), Version(4));
+        assert_eq!(changes.cursor(), Version(5));
+    }
+
+    #[tokio::test]
+    async fn it_should_stream_the_updates_of_a_key() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        napmap.insert("key", 1).await;
+        let mut updates = napmap.subscribe_key("key");
+        assert_eq!(updates.next().await, Some(1));
+
+        tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                napmap.insert("other", 0).await;
+                napmap.insert("key", 2).await;
+            }
+        });
+        assert_eq!(updates.next().await, Some(2));
+        assert_eq!(updates.cursor(), napmap.version());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_tell_the_next_expiration() {
+        let napmap = UnboundedNapMap::new();
+        assert_eq!(napmap.next_expiration().await, None);
+        let start = tokio::time::Instant::now();
+        napmap.insert("forever", 0).await;
+        napmap
+            .insert_with_ttl("late", 1, Duration::from_secs(60))
+            .await;
+        napmap
+            .insert_with_ttl("soon", 2, Duration::from_secs(5))
+            .await;
+
+        assert_eq!(
+            napmap.next_expiration().await,
+            Some(("soon", start + Duration::from_secs(5)))
+        );
+        napmap.remove(&"soon").await;
+        assert_eq!(napmap.next_expiration().await.unwrap().0, "late");
+    }
+
+    #[tokio::test]
+    async fn it_should_wait_for_any_of_many_keys() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let any = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.wait_any(["a", "b", "c"]).await.unwrap() }
+        });
+        let all = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get_many(["b", "c"]).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        napmap.insert("b", 2).await;
+        assert_eq!(any.await.unwrap(), ("b", 2));
+        napmap.insert_many([("a", 1), ("c", 3)]).await;
+        assert_eq!(all.await.unwrap(), [2, 3]);
+        assert_eq!(napmap.wait_any(["c", "a"]).await.unwrap(), ("c", 3));
+        assert_eq!(napmap.notifier_stats().await.keys, 0);
+    }
+
+    #[tokio::test]
+    async fn it_should_drain_in_write_order() {
+        let napmap = UnboundedNapMap::new();
+        for k in [3, 1, 2, 1] {
+            napmap.insert(k, k * 10).await;
+        }
+
+        let drained = napmap.drain(EntryOrder::Written).await;
+        assert_eq!(drained, [(3, 30), (2, 20), (1, 10)]);
+        assert!(napmap.is_empty().await);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn it_should_warm_start_from_a_saved_snapshot() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("a".to_string(), 1).await;
+        napmap.insert("b".to_string(), 2).await;
+        let mut saved = Vec::new();
+        napmap
+            .to_snapshot()
+            .await
+            .save_to_writer(&mut saved)
+            .unwrap();
+
+        let restored = Arc::new(UnboundedNapMap::new());
+        let waiter = tokio::spawn({
+            let restored = restored.clone();
+            async move { restored.get("b").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let snapshot = crate::MapSnapshot::load_from_reader(saved.as_slice()).unwrap();
+        restored.restore_snapshot(snapshot).await;
+
+        assert_eq!(waiter.await.unwrap(), Some(2));
+        assert_eq!(restored.try_get("a").await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_share_entries_between_cloned_handles() {
+        let napmap = UnboundedNapMap::new();
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        napmap.clone().insert("key", 1).await;
+        assert_eq!(waiter.await.unwrap(), Some(1));
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_insert_the_output_of_a_registered_future() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        napmap.insert_from_future("produced", async move { rx.await.unwrap() });
+        assert!(napmap.is_pending(&"produced").await);
+
+        tx.send(1).unwrap();
+        assert_eq!(napmap.get(&"produced").await, Some(1));
+        assert!(!napmap.is_pending(&"produced").await);
+
+        let (mut tx, rx) = tokio::sync::oneshot::channel::<i32>();
+        napmap.insert("cancelled", 0).await;
+        napmap.insert_from_future("cancelled", async move { rx.await.unwrap() });
+        napmap.remove(&"cancelled").await;
+        tokio::time::timeout(Duration::from_secs(1), tx.closed())
+            .await
+            .expect("producer dropped on remove");
+    }
+
+    #[tokio::test]
+    async fn it_should_produce_for_the_clones_left() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let clone = (*napmap).clone();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        napmap.insert_from_future("produced", async move { rx.await.unwrap() });
+        drop(napmap);
+
+        tx.send(1).unwrap();
+        assert_eq!(clone.get(&"produced").await, Some(1));
+
+        clone.insert_from_future("panicked", async { panic!("producer failed") });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!clone.is_pending(&"panicked").await);
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_waiters_on_close() {
+        let napmap = UnboundedNapMap::new();
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get_checked("missing").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        napmap.insert("present", 1).await;
+
+        napmap.close().await;
+        assert!(napmap.is_closed());
+        assert_eq!(waiter.await.unwrap(), Err(GetError::Closed));
+        assert_eq!(napmap.get(&"missing").await, None);
+        assert_eq!(napmap.get(&"present").await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_close_a_prefix_only() {
+        let napmap = UnboundedNapMap::new();
+        let closed = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get_checked("tenant-a/1").await }
+        });
+        let open = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get_checked("tenant-b/1").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        napmap.close_prefix("tenant-a/").await;
+        assert_eq!(closed.await.unwrap(), Err(GetError::Closed));
+        assert!(napmap.is_closed_for(&"tenant-a/2"));
+        assert!(!napmap.is_closed());
+
+        napmap.insert("tenant-b/1", 1).await;
+        assert_eq!(open.await.unwrap(), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_changed_since_on_close() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("job", 1).await;
+        let (_, version) = napmap.get_versioned("job").await.unwrap();
+
+        let (changed, _) = tokio::join!(napmap.changed_since("job", version), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close().await
+        });
+        assert_eq!(changed, Err(GetError::Closed));
+        assert_eq!(
+            napmap.changed_since("job", version).await,
+            Err(GetError::Closed)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_wait_for_on_close() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("job", 1).await;
+
+        let (done, _) = tokio::join!(napmap.wait_for("job", |v| *v == 2), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close_where(|k| *k == "job").await
+        });
+        assert_eq!(done, Err(GetError::Closed));
+        assert_eq!(napmap.wait_for("job", |v| *v == 1).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_get_all_atomic_on_close() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("a/1", 1).await;
+
+        // Closing the second missing key wakes the wait napping on the first
+        let (all, _) = tokio::join!(napmap.get_all_atomic(["b/1", "a/2"]), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close_prefix("a/").await
+        });
+        assert_eq!(all, Err(GetError::Closed));
+        assert_eq!(napmap.get_all_atomic(["a/1"]).await, Ok(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn it_should_wake_wait_any_once_every_key_is_closed() {
+        let napmap = UnboundedNapMap::new();
+        let (any, _) = tokio::join!(napmap.wait_any(["a/1", "b/1"]), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close_prefix("a/").await;
+            napmap.insert("b/1", 1).await;
+        });
+        assert_eq!(any, Ok(("b/1", 1)));
+
+        let (any, _) = tokio::join!(napmap.wait_any(["a/2", "c/1"]), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close().await
+        });
+        assert_eq!(any, Err(GetError::Closed));
+    }
+
+    #[tokio::test]
+    async fn it_should_end_key_updates_on_close() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("job", 1).await;
+        let mut updates = napmap.subscribe_key("job");
+        assert_eq!(updates.next().await, Some(1));
+
+        let (next, _) = tokio::join!(updates.next(), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.close().await
+        });
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn it_should_nap_on_a_plain_thread() {
+        let napmap = UnboundedNapMap::new();
+        let waiter = std::thread::spawn({
+            let napmap = napmap.clone();
+            move || napmap.blocking_get(&"sync")
+        });
+        std::thread::sleep(Duration::from_millis(10));
+
+        napmap.blocking_insert("sync", 1);
+        assert_eq!(waiter.join().unwrap(), Some(1));
+        assert_eq!(napmap.blocking_remove(&"sync"), Some(1));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Cannot block the current thread from within a runtime")]
+    async fn it_should_refuse_to_block_within_a_runtime() {
+        let napmap = UnboundedNapMap::new();
+        napmap.blocking_insert("sync", 1);
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn it_should_panic_on_napping_on_a_key_being_computed_by_the_same_task() {
+        let napmap: UnboundedNapMap<&str, u32> = UnboundedNapMap::new();
+        let handle = napmap.clone();
+        let computing = tokio::spawn(async move {
+            napmap
+                .get_or_compute("loop", || async { handle.get(&"loop").await.unwrap() })
+                .await
+        });
+        assert!(computing.await.unwrap_err().is_panic());
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_tenants_to_their_namespace() {
+        let napmap = UnboundedNapMap::new()
+            .with_authorizer(|k: &&str, op| k.starts_with("a/") || op == Operation::Get);
+        napmap.insert("a/1", 1).await;
+        assert!(matches!(
+            napmap.insert_checked("b/1", 2).await,
+            Err(InsertError::Denied)
+        ));
+        assert_eq!(napmap.get(&"a/1").await, Some(1));
+
+        let napmap = napmap.with_authorizer(|k: &&str, _| k.starts_with("b/"));
+        assert_eq!(napmap.try_get(&"a/1").await, None);
+        assert_eq!(napmap.get_checked("a/1").await, Err(GetError::Denied));
+        assert_eq!(napmap.remove(&"a/1").await, None);
+        assert_eq!(napmap.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_deny_conditional_writes() {
+        let napmap = UnboundedNapMap::new().with_authorizer(|_: &&str, op| op != Operation::Insert);
+        assert!(matches!(
+            napmap.insert_if_version("key", 1, None).await,
+            Err(VersionError::Denied)
+        ));
+        assert!(matches!(
+            napmap.compare_and_swap("key", None, 1).await,
+            Err(SwapError::Denied)
+        ));
+        assert!(matches!(
+            napmap.rcu("key", |_| 1).await,
+            Err(VersionError::Denied)
+        ));
+        assert_eq!(napmap.try_get(&"key").await, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_refuse_waits_on_denied_keys() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("a/1", 1).await;
+        let napmap = napmap.with_authorizer(|k: &&str, _| k.starts_with("b/"));
+        assert_eq!(napmap.get_versioned("a/1").await, Err(GetError::Denied));
+        assert_eq!(
+            napmap.changed_since("a/1", Version(0)).await,
+            Err(GetError::Denied)
+        );
+        assert_eq!(
+            napmap.wait_for("a/1", |_| true).await,
+            Err(GetError::Denied)
+        );
+        assert_eq!(napmap.next_update("a/1").await, Err(GetError::Denied));
+        assert_eq!(
+            napmap.get_all_atomic(["b/1", "a/1"]).await,
+            Err(GetError::Denied)
+        );
+        assert_eq!(napmap.wait_any(["b/1", "a/1"]).await, Err(GetError::Denied));
+    }
+
+    #[tokio::test]
+    async fn it_should_hide_denied_keys_from_bulk_reads() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("a/1", 1).await;
+        napmap.insert("b/1", 2).await;
+        let napmap = napmap.with_authorizer(|k: &&str, _| k.starts_with("b/"));
+        assert_eq!(
+            napmap.try_get_many(["a/1", "b/1"]).await,
+            HashMap::from([("b/1", 2)])
+        );
+        assert_eq!(napmap.keys().await, vec!["b/1"]);
+        assert_eq!(napmap.values().await, vec![2]);
+        assert_eq!(napmap.iter().await.collect::<Vec<_>>(), vec![("b/1", 2)]);
+        assert_eq!(napmap.snapshot().await, HashMap::from([("b/1", 2)]));
+        assert!(!napmap.contains_key(&"a/1").await);
+        assert!(!napmap.is_pending(&"a/1").await);
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_denied_keys_on_clear() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("a/1", 1).await;
+        napmap.insert("b/1", 2).await;
+        napmap.insert("b/2", 3).await;
+        let napmap =
+            napmap.with_authorizer(|k: &&str, op| k.starts_with("b/") || op != Operation::Remove);
+        assert_eq!(
+            napmap.drain(EntryOrder::Written).await,
+            vec![("b/1", 2), ("b/2", 3)]
+        );
+        assert_eq!(napmap.get(&"a/1").await, Some(1));
+
+        napmap.insert("b/3", 4).await;
+        napmap.clear().await;
+        assert_eq!(napmap.keys().await, vec!["a/1"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_skip_denied_keys_in_the_changelog() {
+        let napmap = UnboundedNapMap::new()
+            .with_changelog(8)
+            .with_authorizer(|k: &&str, op| k.starts_with("b/") || op != Operation::Get);
+        let mut changes = napmap.subscribe().unwrap();
+        napmap.insert("a/1", 1).await;
+        napmap.insert("b/1", 2).await;
+
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!(change.key(), &"b/1");
+    }
+
+    #[tokio::test]
+    async fn it_should_nap_with_a_custom_hasher() {
+        let napmap = UnboundedNapMap::with_hasher(BuildIdHasher);
+        let waiter = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&42u64).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        napmap.insert(42, "answer").await;
+        assert_eq!(waiter.await.unwrap(), Some("answer"));
+    }
+
+    #[tokio::test]
+    async fn it_should_nap_through_a_derived_view() {
+        let napmap = UnboundedNapMap::new();
+        let view = napmap.map_values(|v: &Vec<u32>| v.len());
+        let (got, _) = tokio::join!(view.get(&"batch"), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            napmap.insert("batch", vec![1, 2, 3]).await;
+        });
+
+        assert_eq!(got, Some(3));
+        assert_eq!(view.try_get(&"missing").await, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_hold_off_computing_while_hydrating() {
+        struct Dump(mpsc::Receiver<(&'static str, u32)>);
+
+        impl Stream for Dump {
+            type Item = (&'static str, u32);
+
+            fn poll_next(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<Option<Self::Item>> {
+                self.0.poll_recv(cx)
+            }
+        }
+
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let (tx, rx) = mpsc::channel(4);
+        napmap.hydrate(Dump(rx));
+        assert!(napmap.is_hydrating());
+
+        let warm = tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                napmap
+                    .get_or_compute("warm", || async { unreachable!("loaded by hydration") })
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        tx.send(("warm", 1)).await.unwrap();
+        assert_eq!(warm.await.unwrap(), 1);
+
+        drop(tx);
+        napmap.await_hydrated().await;
+        assert_eq!(napmap.get_or_compute("cold", || async { 2 }).await, 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_update_under_the_write_lock() {
+        let napmap = UnboundedNapMap::new();
+        napmap.insert("counter", 0).await;
+        let bumps: Vec<_> = (0..8)
+            .map(|_| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.update("counter", |n| *n += 1).await })
+            })
+            .collect();
+        for bump in bumps {
+            assert!(bump.await.unwrap().unwrap().is_some());
+        }
+
+        assert_eq!(napmap.get(&"counter").await, Some(8));
+        let err = napmap.compare_and_swap("counter", Some(&7), 0).await;
+        assert!(matches!(err, Err(SwapError::Mismatch { current: Some(8) })));
+    }
+
+    #[tokio::test]
+    async fn it_should_wait_for_a_matching_value() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let done = tokio::spawn({
+            let napmap = napmap.clone();
+            async move {
+                napmap
+                    .wait_for("job", |status| *status == "done")
+                    .await
+                    .unwrap()
+            }
+        });
+        for status in ["queued", "running", "done"] {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            assert!(!done.is_finished());
+            napmap.insert("job", status).await;
+        }
+
+        assert_eq!(done.await.unwrap(), "done");
+    }
+
+    #[tokio::test]
+    async fn it_should_not_take_expired_values() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        napmap
+            .insert_with_ttl("reply", 1, Duration::from_millis(1))
+            .await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let taken = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.take("reply").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!taken.is_finished());
+
+        napmap.insert("reply", 2).await;
+        assert_eq!(taken.await.unwrap(), Some(2));
+        assert_eq!(napmap.remove(&"reply").await, None);
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_waking_clones_made_before_capping_pending_keys() {
+        let napmap = UnboundedNapMap::new();
+        let early = napmap.clone();
+        let napmap = napmap.with_max_pending_keys(1);
+
+        let got = tokio::spawn(async move { early.get(&"key").await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            napmap.get_checked("other").await,
+            Err(GetError::TooManyPendingKeys { limit: 1 })
+        );
+
+        napmap.insert("key", 7).await;
+        assert_eq!(got.await.unwrap(), Some(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_only_purge_the_deadline_a_key_still_has() {
+        let napmap = UnboundedNapMap::new();
+        napmap
+            .insert_with_ttl("renewed", 1, Duration::from_secs(1))
+            .await;
+        napmap
+            .insert_with_ttl("renewed", 2, Duration::from_secs(60))
+            .await;
+        napmap
+            .insert_with_ttl("dropped", 3, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_millis(1500)).await;
+
+        assert_eq!(napmap.purge_expired().await, 1);
+        assert_eq!(napmap.try_get(&"renewed").await, Some(2));
+        assert_eq!(napmap.next_expiration().await.unwrap().0, "renewed");
+        assert_eq!(napmap.purge_expired().await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_tell_expired_keys_from_missing_ones() {
+        let napmap = Arc::new(UnboundedNapMap::new());
+        let start = tokio::time::Instant::now();
+        napmap
+            .insert_with_ttl("key", 1, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(
+            napmap.get_checked("key").await,
+            Err(GetError::Expired {
+                at: start + Duration::from_secs(1)
+            })
+        );
+
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!get.is_finished());
+        napmap.insert("key", 2).await;
+        assert_eq!(get.await.unwrap(), Some(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_read_stale_values_within_the_grace_window() {
+        let napmap = UnboundedNapMap::new().with_grace(Duration::from_secs(10));
+        napmap
+            .insert_with_ttl("key", 1, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(napmap.try_get(&"key").await, None);
+        assert_eq!(napmap.get_stale(&"key").await, Some(1));
+        assert_eq!(napmap.purge_expired().await, 0);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(napmap.get_stale(&"key").await, None);
+        assert_eq!(napmap.purge_expired().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_report_the_time_actually_napped() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let napmap = Arc::new(UnboundedNapMap::new().with_slow_wait(
+            Duration::from_millis(10),
+            move |_: &&str, elapsed| {
+                tx.send(elapsed).unwrap();
+            },
+        ));
+        let get = tokio::spawn({
+            let napmap = napmap.clone();
+            async move { napmap.get(&"key").await }
+        });
+        tokio::task::yield_now().await;
+        // Hold the only worker past the threshold, so the timer fires late
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert!(rx.recv().await.unwrap() >= Duration::from_millis(50));
+        napmap.insert("key", 1).await;
+        assert_eq!(get.await.unwrap(), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_stop_reaping_once_the_map_is_dropped() {
+        let napmap = UnboundedNapMap::new().with_ttl(Duration::from_secs(1));
+        let reaper = napmap.reap_expired_every(Duration::from_secs(1));
+        napmap.insert("key", 1).await;
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(napmap.len().await, 0);
+        assert!(!reaper.is_finished());
+
+        drop(napmap);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(reaper.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_unschedule_overwritten_and_removed_deadlines() {
+        let napmap = UnboundedNapMap::new().with_ttl(Duration::from_secs(60));
+        for _ in 0..3 {
+            napmap.insert("key", 1).await;
+            tokio::time::advance(Duration::from_secs(2)).await;
+        }
+        napmap.insert("other", 2).await;
+        assert_eq!(napmap.expiry.len(), 2);
+
+        napmap.remove(&"key").await;
+        assert_eq!(napmap.expiry.len(), 1);
+        napmap.clear().await;
+        assert_eq!(napmap.expiry.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_should_retire_expired_entries_of_bulk_removals_as_expired() {
+        let left = Arc::new(Mutex::new(Vec::new()));
+        let napmap = UnboundedNapMap::new().with_on_evict({
+            let left = left.clone();
+            move |k: &&str, v: &i32, cause| left.lock().unwrap().push((*k, *v, cause))
+        });
+        napmap.insert("live", 1).await;
+        napmap
+            .insert_with_ttl("stale", 2, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(napmap.remove_many(["live", "stale"]).await, [("live", 1)]);
+
+        napmap
+            .insert_with_ttl("stale", 3, Duration::from_secs(1))
+            .await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        napmap.clear().await;
+        assert_eq!(
+            *left.lock().unwrap(),
+            [
+                ("live", 1, RemovalCause::Removed),
+                ("stale", 2, RemovalCause::Expired),
+                ("stale", 3, RemovalCause::Expired),
+            ]
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn it_should_export_the_stats_as_metrics() {
+        use metrics_util::debugging::DebugValue;
+        use metrics_util::debugging::DebuggingRecorder;
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .unwrap();
+            rt.block_on(async {
+                let map = UnboundedNapMap::<u64, u64>::new().with_span_name("sessions");
+                let napper = tokio::spawn({
+                    let map = map.clone();
+                    async move { map.get(&1).await }
+                });
+                tokio::task::yield_now().await;
+                map.insert(1, 10).await;
+                assert_eq!(napper.await.unwrap(), Some(10));
+                assert_eq!(map.try_get(&2).await, None);
+                map.remove(&1).await;
+            });
+        });
+
+        let metrics: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                assert!(key
+                    .labels()
+                    .any(|l| l.key() == "map" && l.value() == "sessions"));
+                (key.name().to_owned(), value)
+            })
+            .collect();
+        let value = |name: &str| {
+            metrics
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v)
+                .unwrap()
+        };
+        assert_eq!(value("napmap_inserts_total"), &DebugValue::Counter(1));
+        assert_eq!(value("napmap_removals_total"), &DebugValue::Counter(1));
+        assert_eq!(value("napmap_misses_total"), &DebugValue::Counter(2));
+        assert_eq!(value("napmap_napping"), &DebugValue::Gauge(0.0.into()));
+        assert!(
+            matches!(value("napmap_nap_seconds"), DebugValue::Histogram(naps) if naps.len() == 1)
+        );
     }
 }
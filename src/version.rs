@@ -0,0 +1,93 @@
+use crate::BackendError;
+use std::error::Error;
+use std::fmt::Display;
+
+/// Identifies one write of an entry. Versions are unique and increasing per
+/// map, so a re-inserted key never gets back a version it used to have.
+///
+/// The raw number is public so tokens can travel through external systems,
+/// e.g. as an HTTP `ETag` or a database row version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version(pub u64);
+
+/// Order of the entries handed out by `drain` and `iter_in`. See also
+/// `drain_sorted` and `iter_sorted` for key order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EntryOrder {
+    /// Whatever order the map holds them in, which may change from one call
+    /// to the next.
+    #[default]
+    Any,
+    /// Oldest write first, by [`Version`], stable across replays.
+    Written,
+}
+
+/// Returned by `insert_if_version` when the entry was not applied.
+#[derive(Debug)]
+pub enum VersionError {
+    /// The entry changed since the expected version was read.
+    Mismatch { current: Option<Version> },
+    /// The backend refused the change, see [`BackendError`].
+    Backend(BackendError),
+    /// The authorizer refused the write, see `with_authorizer`.
+    Denied,
+}
+
+impl Display for VersionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionError::Mismatch { current: Some(v) } => {
+                write!(f, "entry is at version {}", v.0)
+            }
+            VersionError::Mismatch { current: None } => write!(f, "entry is absent"),
+            VersionError::Backend(e) => e.fmt(f),
+            VersionError::Denied => write!(f, "write was denied"),
+        }
+    }
+}
+
+impl Error for VersionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VersionError::Mismatch { .. } | VersionError::Denied => None,
+            VersionError::Backend(e) => Some(e),
+        }
+    }
+}
+
+impl From<BackendError> for VersionError {
+    fn from(e: BackendError) -> Self {
+        VersionError::Backend(e)
+    }
+}
+
+/// Returned by `compare_and_swap` when the entry was not swapped.
+#[derive(Debug)]
+pub enum SwapError<V> {
+    /// The entry doesn't hold the expected value, it holds `current`.
+    Mismatch { current: Option<V> },
+    /// The backend refused the change, see [`BackendError`].
+    Backend(BackendError),
+    /// The authorizer refused the write, see `with_authorizer`.
+    Denied,
+}
+
+impl<V> Display for SwapError<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SwapError::Mismatch { current: Some(_) } => write!(f, "entry holds another value"),
+            SwapError::Mismatch { current: None } => write!(f, "entry is absent"),
+            SwapError::Backend(e) => e.fmt(f),
+            SwapError::Denied => write!(f, "write was denied"),
+        }
+    }
+}
+
+impl<V: std::fmt::Debug> Error for SwapError<V> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SwapError::Mismatch { .. } | SwapError::Denied => None,
+            SwapError::Backend(e) => Some(e),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::error::lock;
+use std::sync::Mutex;
+use tokio::time::Instant;
+
+/// The highest a count ever got, and when it first got there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermark {
+    pub peak: usize,
+    /// `None` until the count first rises above 0.
+    pub at: Option<Instant>,
+}
+
+/// High-water marks of a map, kept for capacity planning. See
+/// `reset_watermarks` to start a new observation window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Most entries ever held at once.
+    pub entries: Watermark,
+    /// Most tasks ever napping at once.
+    pub waiters: Watermark,
+}
+
+#[derive(Debug)]
+pub(crate) struct HighWater(Mutex<Watermark>);
+
+impl HighWater {
+    pub(crate) fn new() -> Self {
+        Self(Mutex::new(Watermark { peak: 0, at: None }))
+    }
+
+    pub(crate) fn observe(&self, n: usize) {
+        let mut mark = lock(&self.0);
+        if n > mark.peak {
+            *mark = Watermark {
+                peak: n,
+                at: Some(Instant::now()),
+            };
+        }
+    }
+
+    pub(crate) fn get(&self) -> Watermark {
+        *lock(&self.0)
+    }
+
+    /// Starts over from the current count `n`.
+    pub(crate) fn reset(&self, n: usize) {
+        *lock(&self.0) = Watermark {
+            peak: n,
+            at: (n > 0).then(Instant::now),
+        };
+    }
+}
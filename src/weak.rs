@@ -0,0 +1,186 @@
+use crate::gauge::Gauge;
+use crate::notifiers::NotifierStats;
+use crate::notifiers::Notifiers;
+use crate::sync::AsyncRwLock;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Weak;
+
+/// A napmap holding its values weakly, entries vanish once the last strong
+/// reference elsewhere is dropped and the map never extends their lifetime.
+///
+/// A dead entry is a miss, `get` naps on it until a live value is inserted.
+/// Dead entries are dropped lazily, on overwrite or through
+/// [`purge`](Self::purge).
+pub struct WeakNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: ?Sized,
+{
+    map: Arc<AsyncRwLock<HashMap<K, Weak<V>>>>,
+    notifiers: Arc<Notifiers<K>>,
+    napping: Arc<Gauge>,
+}
+
+impl<K, V> WeakNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: ?Sized,
+{
+    pub fn new() -> Self {
+        Self {
+            map: Arc::new(AsyncRwLock::new(HashMap::new())),
+            notifiers: Arc::new(Notifiers::new()),
+            napping: Arc::new(Gauge::new()),
+        }
+    }
+
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self, v))]
+    pub async fn insert(&self, k: K, v: &Arc<V>) {
+        tracing::trace!("Insert");
+        self.map.write().await.insert(k.clone(), Arc::downgrade(v));
+        if let Some(notify) = self.notifiers.lock().await.remove(&k) {
+            notify.notify_waiters();
+            tracing::trace!("Notified all waiting tasks");
+        }
+    }
+
+    #[tracing::instrument(level = tracing::Level::TRACE, skip(self))]
+    pub async fn get(&self, k: K) -> Arc<V> {
+        tracing::trace!("Get");
+        loop {
+            let map = self.map.read().await;
+            if let Some(v) = map.get(&k).and_then(Weak::upgrade) {
+                tracing::debug!("Contains live key");
+                return v;
+            }
+
+            let notify = self.notifiers.lock().await.register(&k);
+            let notified = notify.notified();
+            drop(map);
+
+            tracing::trace!("Waiting...");
+            let _napping = self.napping.enter();
+            notified.await;
+        }
+    }
+
+    pub async fn remove(&self, k: K) -> Option<Arc<V>> {
+        self.map.write().await.remove(&k).and_then(|w| w.upgrade())
+    }
+
+    /// Drops the dead entries, returns how many there were.
+    pub async fn purge(&self) -> usize {
+        let mut map = self.map.write().await;
+        let before = map.len();
+        map.retain(|_, w| w.strong_count() > 0);
+        before - map.len()
+    }
+
+    /// Number of live entries.
+    pub async fn len(&self) -> usize {
+        let map = self.map.read().await;
+        map.values().filter(|w| w.strong_count() > 0).count()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Resolves once no task is napping on the map, e.g. before shutting down
+    /// or between the phases of a test.
+    pub async fn quiesce(&self) {
+        self.napping.drained().await;
+    }
+
+    /// Size of the notifier table, see [`NotifierStats`].
+    pub async fn notifier_stats(&self) -> NotifierStats {
+        self.notifiers.stats().await
+    }
+}
+
+impl<K, V> Default for WeakNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: ?Sized,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Debug for WeakNapMap<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: ?Sized,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeakNapMap")
+            .field("map", &self.map)
+            .field("notifiers", &self.notifiers)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WeakNapMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn it_should_not_extend_lifetimes() {
+        let napmap = WeakNapMap::new();
+        let session = Arc::new(String::from("session"));
+        napmap.insert("key", &session).await;
+        assert_eq!(*napmap.get("key").await, "session");
+
+        drop(session);
+        assert_eq!(napmap.len().await, 0);
+        assert_eq!(napmap.purge().await, 1);
+    }
+
+    #[tokio::test]
+    async fn it_should_nap_on_dead_entries() {
+        let napmap = Arc::new(WeakNapMap::new());
+        napmap.insert("key", &Arc::new(1)).await;
+
+        let replacement = Arc::new(2);
+        tokio::spawn({
+            let map = napmap.clone();
+            let replacement = replacement.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                map.insert("key", &replacement).await;
+            }
+        });
+
+        assert_eq!(*napmap.get("key").await, 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_report_notifier_stats() {
+        let napmap = Arc::new(WeakNapMap::new());
+        let gets: Vec<_> = ["first", "second"]
+            .into_iter()
+            .map(|k| {
+                let napmap = napmap.clone();
+                tokio::spawn(async move { napmap.get(k).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let stats = napmap.notifier_stats().await;
+        assert_eq!((stats.keys, stats.peak), (2, 2));
+
+        let value = Arc::new(1);
+        napmap.insert("first", &value).await;
+        napmap.insert("second", &value).await;
+        for get in gets {
+            get.await.unwrap();
+        }
+        let stats = napmap.notifier_stats().await;
+        assert_eq!((stats.keys, stats.peak), (0, 2));
+    }
+}